@@ -1,4 +1,4 @@
-use ezrtc::{client::EzRTCClient, socket::DataChannelHandler, RTCDataChannel, RTCDataChannelState, RTCIceServer};
+use ezrtc::{client::EzRTCClient, socket::DataChannelHandler, DataChannel, RTCDataChannelState, RTCIceServer};
 use log::{info, LevelFilter};
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 use std::sync::Arc;
@@ -17,7 +17,7 @@ pub async fn main() {
     struct MyDataChannelHandler {}
 
     impl DataChannelHandler for MyDataChannelHandler {
-        fn handle_data_channel_open(&self, dc: Arc<RTCDataChannel>) {
+        fn handle_data_channel_open(&self, dc: DataChannel) {
             info!("Data channel opened!");
 
             tokio::spawn(async move {
@@ -1,4 +1,4 @@
-use ezrtc::{host::EzRTCHost, protocol::{SignalMessage, Status, UserId}, socket::{DataChannelHandler, WSHost}, RTCDataChannel, RTCDataChannelState, RTCIceServer};
+use ezrtc::{host::EzRTCHost, protocol::{SignalMessage, Status, UserId}, socket::{DataChannelHandler, WSHost}, DataChannel, RTCDataChannelState, RTCIceServer};
 use log::{info, LevelFilter};
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 use std::sync::Arc;
@@ -17,7 +17,7 @@ pub async fn main() {
     struct MyDataChannelHandler {}
 
     impl DataChannelHandler for MyDataChannelHandler {
-        fn handle_data_channel_open(&self, dc: Arc<RTCDataChannel>) {
+        fn handle_data_channel_open(&self, dc: DataChannel) {
             info!("Data channel opened!");
 
             tokio::spawn(async move {
@@ -40,16 +40,21 @@ pub async fn main() {
             let ping_message = SignalMessage::KeepAlive(
                 user_id,
                 Status {
-                    session_id: Some(handle.session_id.clone()),
+                    session_id: handle.session_id.lock().unwrap().clone(),
                     is_host: Some(true),
                     version: Some(env!("CARGO_PKG_VERSION").to_string()),
                     metadata: Some(serde_json::json!({"test": "test",})),
+                    public: Some(false),
                 },
             );
             handle.handle.text(serde_json::to_string(&ping_message).unwrap()).unwrap();
 
             info!("Sending pong to server");
         }
+
+        fn handle_notification(&self, payload: serde_json::Value) {
+            info!("Notification received: {:?}", payload);
+        }
     }
 
     // Start the connection
@@ -0,0 +1,5 @@
+// Regenerate the schema with `cargo r --example schema --features schema > ../../protocol/schema.json`
+pub fn main() {
+    let schema = ezrtc::schema::json_schema();
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+}
@@ -0,0 +1,102 @@
+use crate::protocol::{SessionId, SignalMessage, UserId};
+use crate::sas;
+use crate::transport::SignalingHandle;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+/// Data channel payloads relayed through the signaling server after the peer connection failed.
+#[derive(Clone)]
+pub struct RelayChannel {
+    session_id: SessionId,
+    user_id: UserId,
+    signaling: SignalingHandle,
+    open: Arc<AtomicBool>,
+}
+
+impl RelayChannel {
+    pub(crate) fn new(session_id: SessionId, user_id: UserId, signaling: SignalingHandle) -> Self {
+        Self {
+            session_id,
+            user_id,
+            signaling,
+            open: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// The user on the other end of the relay
+    pub fn user_id(&self) -> UserId {
+        self.user_id
+    }
+
+    pub fn ready_state(&self) -> RTCDataChannelState {
+        if self.open.load(Ordering::Relaxed) {
+            RTCDataChannelState::Open
+        } else {
+            RTCDataChannelState::Closed
+        }
+    }
+
+    pub async fn send_text(&self, text: impl Into<String>) -> Result<usize, webrtc::Error> {
+        if self.ready_state() != RTCDataChannelState::Open {
+            return Err(webrtc::Error::ErrConnectionClosed);
+        }
+
+        let text = text.into();
+        let len = text.len();
+        let message = SignalMessage::RelayData(self.session_id.clone(), self.user_id, text);
+
+        if self.signaling.send(message).is_ok() {
+            Ok(len)
+        } else {
+            Err(webrtc::Error::ErrConnectionClosed)
+        }
+    }
+
+    /// Stop relaying and let the other peer know
+    pub fn close(&self) {
+        if self.open.swap(false, Ordering::Relaxed) {
+            let _ = self.signaling.send(SignalMessage::RelayClose(self.session_id.clone(), self.user_id));
+        }
+    }
+
+    /// The other peer closed the relay
+    pub(crate) fn closed_by_peer(&self) {
+        self.open.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Either a WebRTC data channel or the relay that replaced it, both are used the same way.
+#[derive(Clone)]
+pub enum DataChannel {
+    WebRTC(Arc<RTCDataChannel>),
+    Relay(RelayChannel),
+}
+
+impl DataChannel {
+    pub fn ready_state(&self) -> RTCDataChannelState {
+        match self {
+            DataChannel::WebRTC(dc) => dc.ready_state(),
+            DataChannel::Relay(relay) => relay.ready_state(),
+        }
+    }
+
+    pub async fn send_text(&self, text: impl Into<String>) -> Result<usize, webrtc::Error> {
+        match self {
+            DataChannel::WebRTC(dc) => dc.send_text(text).await,
+            DataChannel::Relay(relay) => relay.send_text(text).await,
+        }
+    }
+
+    /// `true` if messages go through the signaling server instead of a peer connection
+    pub fn is_relayed(&self) -> bool {
+        matches!(self, DataChannel::Relay(_))
+    }
+
+    /// Send the short authentication string of this side, the other peer checks it with [`sas::check_confirmation`].
+    /// Whoever intercepts the connection can change this message too, compare the strings out of band when it matters.
+    pub async fn confirm_short_auth_string(&self, sas: &str) -> Result<usize, webrtc::Error> {
+        self.send_text(sas::confirmation(sas)).await
+    }
+}
@@ -1,9 +1,13 @@
-use crate::protocol::SessionId;
+use crate::channel::{DataChannel, RelayChannel};
+use crate::e2e::SessionCipher;
+use crate::ice;
+use crate::options::ConnectionOptions;
+use crate::protocol::{IceConfig, SessionId, SignalMessage};
 use crate::socket::{DataChannelHandler, WSClient};
-use ezsockets::{ClientConfig, SocketConfig};
-use log::info;
+use crate::transport::{self, DeliveryError, SignalingHandle};
+use crate::turn::TurnCache;
+use log::{info, warn};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::APIBuilder;
@@ -17,99 +21,213 @@ use webrtc::peer_connection::RTCPeerConnection;
 pub struct EzRTCClient {
     pub peer_connection: Arc<Mutex<Arc<RTCPeerConnection>>>,
     pub ice_servers: Vec<RTCIceServer>,
-    pub handle: ezsockets::Client<WSClient>,
+    pub relay_channel: Arc<Mutex<Option<RelayChannel>>>,
+    /// Short authentication string of the connection with the host, see [`sas`](crate::sas)
+    pub short_auth_string: Arc<Mutex<Option<String>>>,
+    /// Metadata the host joined with, see [`ConnectionOptions::metadata`]
+    pub host_metadata: Arc<Mutex<Option<serde_json::Value>>>,
+    /// Session the client is in, `None` after leaving it
+    pub session_id: Arc<Mutex<Option<SessionId>>>,
+    pub handle: SignalingHandle,
+    options: ConnectionOptions,
+    data_channel_handler: Arc<Box<dyn DataChannelHandler>>,
+    signaling_url: url::Url,
+    /// ICE servers passed to the constructor, [`EzRTCClient::ice_servers`] also contains the TURN credentials of the first session
+    configured_ice_servers: Vec<RTCIceServer>,
+    ice_config: Arc<Mutex<Option<IceConfig>>>,
 }
 
 impl EzRTCClient {
     pub async fn new(host_url: String, session_id: String, ice_servers: Vec<RTCIceServer>, data_channel_handler: Arc<Box<dyn DataChannelHandler>>) -> Self {
-        // Setup WebRTC
-        let mut m = MediaEngine::default();
-        m.register_default_codecs().unwrap();
+        Self::with_options(host_url, session_id, ice_servers, data_channel_handler, ConnectionOptions::default()).await
+    }
 
-        let mut registry = Registry::new();
-        registry = register_default_interceptors(registry, &mut m).unwrap();
+    pub async fn with_options(host_url: String, session_id: String, ice_servers: Vec<RTCIceServer>, data_channel_handler: Arc<Box<dyn DataChannelHandler>>, options: ConnectionOptions) -> Self {
+        let signaling_url = url::Url::parse(&host_url).expect("Invalid host URL");
 
-        let api = APIBuilder::new().with_media_engine(m).with_interceptor_registry(registry).build();
+        // Add TURN credentials issued by the signaling server
+        let mut turn_credentials = TurnCache::default();
+        let config = ice::rtc_configuration(&signaling_url, &SessionId::new(session_id.clone()), &ice_servers, None, &mut turn_credentials).await;
+        let effective_ice_servers = config.ice_servers.clone();
 
-        let config = RTCConfiguration {
-            ice_servers: ice_servers.clone(),
-            ..Default::default()
-        };
-
-        let peer_connection = Arc::new(api.new_peer_connection(config).await.unwrap());
-
-        let pc = peer_connection.clone();
-        let dh = data_channel_handler.clone();
-        pc.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
-            let dc = Arc::clone(&d);
-            let dh2 = dh.clone();
-
-            // Register channel opening handling
-            Box::pin(async move {
-                // Handle the channel opening
-                let dh3 = dh2.clone();
-                d.on_open(Box::new(move || {
-                    let dc2 = Arc::clone(&dc);
-                    dh3.handle_data_channel_open(dc2);
-
-                    Box::pin(async move {})
-                }));
-
-                // Handle the received messages
-                let dh3 = dh2.clone();
-                d.on_message(Box::new(move |msg: DataChannelMessage| {
-                    //Convert message to string
-                    let message = String::from_utf8(msg.data.to_vec()).unwrap();
-                    dh3.handle_data_channel_message(message);
-
-                    Box::pin(async move {})
-                }));
-
-                // Handle the channel closing
-                d.on_close(Box::new(move || {
-                    info!("Data channel closed");
-                    Box::pin(async {})
-                }));
-            })
-        }));
+        let peer_connection = create_peer_connection(config, data_channel_handler.clone()).await;
 
         let global_peer_connection = Arc::new(Mutex::new(peer_connection));
-        let signaling_url = url::Url::parse(&host_url).expect("Invalid host URL");
+        let global_relay_channel = Arc::new(Mutex::new(None));
+        let global_short_auth_string = Arc::new(Mutex::new(None));
+        let global_host_metadata = Arc::new(Mutex::new(None));
+        let global_session_id = Arc::new(Mutex::new(Some(SessionId::new(session_id.clone()))));
+        let global_ice_config = Arc::new(Mutex::new(None));
+        let cipher = options.session_secret.as_deref().map(|secret| SessionCipher::new(secret, &SessionId::new(session_id.clone()), false));
+        let (handle, rx) = SignalingHandle::with_cipher(cipher);
 
         let pc = Arc::clone(&global_peer_connection);
+        let rc = Arc::clone(&global_relay_channel);
+        let sas = Arc::clone(&global_short_auth_string);
+        let host_metadata = Arc::clone(&global_host_metadata);
+        let sid = Arc::clone(&global_session_id);
+        let ice_config = Arc::clone(&global_ice_config);
+        let client_options = options.clone();
+        let client_data_channel_handler = data_channel_handler.clone();
         let ice = ice_servers.clone();
-
-        // Crypto provider
-        rustls::crypto::aws_lc_rs::default_provider().install_default().expect("failed to install default crypto provider");
-
-        let config = ClientConfig::new(signaling_url);
-        let config = config.socket_config(SocketConfig {
-            heartbeat: Duration::from_secs(60),
-            timeout: Duration::from_secs(90),
-            ..SocketConfig::default()
-        });
-
-        let (handle, future) = ezsockets::connect(
-            |handle| WSClient {
-                handle,
-                session_id: SessionId::new(session_id),
-                peer_connection: pc,
-                ice_servers: ice,
-                data_channel_handler,
+        let hndl = handle.clone();
+        let url = signaling_url.clone();
+        let client_signaling_url = signaling_url.clone();
+        let long_poll_fallback = options.long_poll_fallback;
+        let encoding = options.encoding;
+
+        // Crypto provider, already installed if a host or client was created before in this process
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        transport::connect(
+            signaling_url,
+            rx,
+            move |fallback| WSClient {
+                handle: hndl.clone(),
+                fallback,
+                session_id: sid.clone(),
+                peer_connection: pc.clone(),
+                ice_servers: ice.clone(),
+                signaling_url: url.clone(),
+                turn_credentials: turn_credentials.clone(),
+                ice_config: ice_config.clone(),
+                relay_channel: rc.clone(),
+                short_auth_string: sas.clone(),
+                host_metadata: host_metadata.clone(),
+                options: options.clone(),
+                server: None,
+                data_channel_handler: data_channel_handler.clone(),
             },
-            config,
+            long_poll_fallback,
+            encoding,
         )
         .await;
 
-        tokio::spawn(async move {
-            info!("Connected to signaling server");
-            future.await.unwrap();
-        });
-
-        return Self {
+        Self {
             peer_connection: global_peer_connection.clone(),
-            ice_servers: ice_servers.clone(),
+            ice_servers: effective_ice_servers,
+            relay_channel: global_relay_channel,
+            short_auth_string: global_short_auth_string,
+            host_metadata: global_host_metadata,
+            session_id: global_session_id,
             handle,
+            options: client_options,
+            data_channel_handler: client_data_channel_handler,
+            signaling_url: client_signaling_url,
+            configured_ice_servers: ice_servers,
+            ice_config: global_ice_config,
+        }
+    }
+
+    /// Short authentication string of the connection with the host, `None` until the peer connection is connected
+    pub fn short_auth_string(&self) -> Option<String> {
+        self.short_auth_string.lock().unwrap().clone()
+    }
+
+    /// Metadata the host joined with, `None` until the host joins or if it didn't send any
+    pub fn host_metadata(&self) -> Option<serde_json::Value> {
+        self.host_metadata.lock().unwrap().clone()
+    }
+
+    /// Leave the session without closing the signaling connection, the connection with the host is closed
+    pub async fn leave_session(&self) -> Result<(), DeliveryError> {
+        let Some(session_id) = self.session_id.lock().unwrap().take() else {
+            return Ok(());
         };
+
+        // close the relay first, the server only passes its close on to users of the session
+        if let Some(relay_channel) = self.relay_channel.lock().unwrap().take() {
+            relay_channel.close();
+        }
+        let peer_connection = self.peer_connection.lock().unwrap().clone();
+        if let Err(e) = peer_connection.close().await {
+            warn!("Failed to close peer connection: {:?}", e);
+        }
+        *self.short_auth_string.lock().unwrap() = None;
+        *self.host_metadata.lock().unwrap() = None;
+
+        self.handle.send_acknowledged(SignalMessage::SessionLeave(session_id)).await
+    }
+
+    /// Leave the current session and join `session_id` over the same signaling connection
+    pub async fn join_session(&self, session_id: String) -> Result<(), DeliveryError> {
+        if let Err(e) = self.leave_session().await {
+            warn!("Failed to leave session: {}", e);
+        }
+
+        // the closed peer connection can't be used again, the new one gets TURN credentials for the new session
+        let session_id = SessionId::new(session_id);
+        let ice_config = self.ice_config.lock().unwrap().clone();
+        let config = ice::rtc_configuration(&self.signaling_url, &session_id, &self.configured_ice_servers, ice_config.as_ref(), &mut TurnCache::default()).await;
+        let peer_connection = create_peer_connection(config, self.data_channel_handler.clone()).await;
+        *self.peer_connection.lock().unwrap() = peer_connection;
+
+        let cipher = self.options.session_secret.as_deref().map(|secret| SessionCipher::new(secret, &session_id, false));
+        self.handle.set_cipher(cipher);
+        *self.session_id.lock().unwrap() = Some(session_id.clone());
+
+        let join = SignalMessage::SessionJoin(session_id, false, self.options.metadata.clone());
+        self.handle.send_acknowledged(join).await
     }
 }
+
+/// Create the client side peer connection that waits for the host's data channel
+pub async fn create_peer_connection(config: RTCConfiguration, data_channel_handler: Arc<Box<dyn DataChannelHandler>>) -> Arc<RTCPeerConnection> {
+    create_peer_connection_with(config, data_channel_handler, |_| {}).await
+}
+
+/// [`create_peer_connection`] that also passes every data channel created by the other peer to `on_data_channel`
+pub(crate) async fn create_peer_connection_with(
+    config: RTCConfiguration,
+    data_channel_handler: Arc<Box<dyn DataChannelHandler>>,
+    on_data_channel: impl Fn(Arc<RTCDataChannel>) + Send + Sync + 'static,
+) -> Arc<RTCPeerConnection> {
+    // Setup WebRTC
+    let mut m = MediaEngine::default();
+    m.register_default_codecs().unwrap();
+
+    let mut registry = Registry::new();
+    registry = register_default_interceptors(registry, &mut m).unwrap();
+
+    let api = APIBuilder::new().with_media_engine(m).with_interceptor_registry(registry).build();
+
+    let peer_connection = Arc::new(api.new_peer_connection(config).await.unwrap());
+
+    let pc = peer_connection.clone();
+    let dh = data_channel_handler.clone();
+    pc.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
+        on_data_channel(Arc::clone(&d));
+        let dc = Arc::clone(&d);
+        let dh2 = dh.clone();
+
+        // Register channel opening handling
+        Box::pin(async move {
+            // Handle the channel opening
+            let dh3 = dh2.clone();
+            d.on_open(Box::new(move || {
+                let dc2 = Arc::clone(&dc);
+                dh3.handle_data_channel_open(DataChannel::WebRTC(dc2));
+
+                Box::pin(async move {})
+            }));
+
+            // Handle the received messages
+            let dh3 = dh2.clone();
+            d.on_message(Box::new(move |msg: DataChannelMessage| {
+                //Convert message to string
+                let message = String::from_utf8(msg.data.to_vec()).unwrap();
+                dh3.handle_data_channel_message(message);
+
+                Box::pin(async move {})
+            }));
+
+            // Handle the channel closing
+            d.on_close(Box::new(move || {
+                info!("Data channel closed");
+                Box::pin(async {})
+            }));
+        })
+    }));
+
+    peer_connection
+}
@@ -0,0 +1,205 @@
+use crate::protocol::{IceCandidateJSON, IcePayload, SdpPayload, SdpType, SealedPayload, SessionDescription, SessionId, SignalMessage, UserId};
+use base64::prelude::*;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt::{Debug, Display, Formatter};
+
+const NONCE_LENGTH: usize = 12;
+
+/// Reason a message from the other peer was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SealError {
+    /// A session secret is configured, but the payload isn't sealed
+    Unsealed,
+    /// The payload is sealed, but no session secret is configured
+    MissingSecret,
+    /// The payload was changed or sealed with a different secret
+    Invalid,
+    /// The server didn't assign a user id yet, payloads are bound to the ids of both peers
+    UnknownUser,
+}
+
+impl Display for SealError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SealError::Unsealed => write!(f, "payload isn't sealed with the session secret"),
+            SealError::MissingSecret => write!(f, "payload is sealed, but no session secret is configured"),
+            SealError::Invalid => write!(f, "payload was tampered with or sealed with a different secret"),
+            SealError::UnknownUser => write!(f, "the signaling server didn't assign a user id yet"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// Encrypts and authenticates the SDP and ICE payloads of a session with a secret shared by the host and its clients,
+/// so the signaling server can neither read nor change the DTLS fingerprints in them.
+///
+/// The key is derived with HKDF, which doesn't slow down guessing: the server can try secrets offline against any sealed
+/// payload, so the secret has to be random like the ones from [`SessionCipher::generate_secret`], not a password.
+#[derive(Clone)]
+pub struct SessionCipher {
+    cipher: ChaCha20Poly1305,
+    session_id: SessionId,
+    is_host: bool,
+}
+
+impl Debug for SessionCipher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCipher")
+            .field("session_id", &self.session_id)
+            .field("is_host", &self.is_host)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionCipher {
+    /// Derive the key of the session from the secret, the host and the clients have to use the same secret and session id
+    pub fn new(secret: &str, session_id: &SessionId, is_host: bool) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(session_id.as_str().as_bytes()), secret.as_bytes());
+        let mut key = [0u8; 32];
+        hkdf.expand(b"ezrtc signaling payloads", &mut key).expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            session_id: session_id.clone(),
+            is_host,
+        }
+    }
+
+    /// Random secret to share with the peers of a session, 256 bits encoded as base64
+    pub fn generate_secret() -> String {
+        BASE64_STANDARD.encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    /// Seal the SDP and ICE payloads of a message that the user `from` sends to the other peer, other messages are returned unchanged
+    pub fn seal(&self, message: SignalMessage, from: UserId) -> SignalMessage {
+        match message {
+            SignalMessage::SdpOffer(session_id, to, offer) => SignalMessage::SdpOffer(session_id, to, self.seal_sdp(offer, SdpType::Offer, (from, to))),
+            SignalMessage::SdpAnswer(session_id, to, answer) => SignalMessage::SdpAnswer(session_id, to, self.seal_sdp(answer, SdpType::Answer, (from, to))),
+            SignalMessage::IceCandidate(session_id, to, candidate) => SignalMessage::IceCandidate(session_id, to, self.seal_candidate(candidate, (from, to))),
+            SignalMessage::Request(request_id, message) => SignalMessage::Request(request_id, Box::new(self.seal(*message, from))),
+            message => message,
+        }
+    }
+
+    /// Open the sealed payloads of a message from the other peer to the user `to`, fails if they were changed or aren't sealed
+    pub fn open(&self, message: SignalMessage, to: UserId) -> Result<SignalMessage, SealError> {
+        let message = match message {
+            SignalMessage::SdpOffer(session_id, from, offer) => SignalMessage::SdpOffer(session_id, from, self.open_sdp(offer, SdpType::Offer, (from, to))?),
+            SignalMessage::SdpAnswer(session_id, from, answer) => SignalMessage::SdpAnswer(session_id, from, self.open_sdp(answer, SdpType::Answer, (from, to))?),
+            SignalMessage::IceCandidate(session_id, from, candidate) => SignalMessage::IceCandidate(session_id, from, self.open_candidate(candidate, (from, to))?),
+            message => message,
+        };
+
+        Ok(message)
+    }
+
+    fn seal_sdp(&self, payload: SdpPayload, sdp_type: SdpType, users: (UserId, UserId)) -> SdpPayload {
+        if let SdpPayload::Sealed(_) = payload {
+            return payload;
+        }
+
+        let description = SessionDescription { sdp_type, sdp: payload.into_sdp() };
+        let plaintext = serde_json::to_vec(&description).expect("session descriptions are always valid JSON");
+
+        SdpPayload::Sealed(self.encrypt(&plaintext, kind(sdp_type), self.is_host, users))
+    }
+
+    fn open_sdp(&self, payload: SdpPayload, sdp_type: SdpType, users: (UserId, UserId)) -> Result<SdpPayload, SealError> {
+        let SdpPayload::Sealed(sealed) = payload else {
+            return Err(SealError::Unsealed);
+        };
+
+        let plaintext = self.decrypt(&sealed, kind(sdp_type), !self.is_host, users)?;
+        let description: SessionDescription = serde_json::from_slice(&plaintext).map_err(|_| SealError::Invalid)?;
+        if description.sdp_type != sdp_type {
+            return Err(SealError::Invalid);
+        }
+
+        Ok(SdpPayload::Description(description))
+    }
+
+    fn seal_candidate(&self, payload: IcePayload, users: (UserId, UserId)) -> IcePayload {
+        let candidate = match payload.candidate() {
+            Ok(candidate) => candidate,
+            Err(_) => return payload,
+        };
+
+        let plaintext = serde_json::to_vec(&candidate).expect("ICE candidates are always valid JSON");
+        IcePayload::Sealed(self.encrypt(&plaintext, "ice", self.is_host, users))
+    }
+
+    fn open_candidate(&self, payload: IcePayload, users: (UserId, UserId)) -> Result<IcePayload, SealError> {
+        let IcePayload::Sealed(sealed) = payload else {
+            return Err(SealError::Unsealed);
+        };
+
+        let plaintext = self.decrypt(&sealed, "ice", !self.is_host, users)?;
+        let candidate: IceCandidateJSON = serde_json::from_slice(&plaintext).map_err(|_| SealError::Invalid)?;
+
+        Ok(IcePayload::Candidate(candidate))
+    }
+
+    /// Payloads are bound to the session, the kind of message, the role of the peer that sent it and the sender and recipient,
+    /// so the server can't replay them in another session, in the other direction or to another user of the session
+    fn associated_data(&self, kind: &str, from_host: bool, (from, to): (UserId, UserId)) -> Vec<u8> {
+        let sender = if from_host { "host" } else { "client" };
+        format!("ezrtc:{}:{}:{}:{}:{}", self.session_id, kind, sender, from, to).into_bytes()
+    }
+
+    fn encrypt(&self, plaintext: &[u8], kind: &str, from_host: bool, users: (UserId, UserId)) -> SealedPayload {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = self.associated_data(kind, from_host, users);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &aad })
+            .expect("signaling payloads are small enough to encrypt");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+
+        SealedPayload {
+            sealed: BASE64_STANDARD.encode(sealed),
+        }
+    }
+
+    fn decrypt(&self, payload: &SealedPayload, kind: &str, from_host: bool, users: (UserId, UserId)) -> Result<Vec<u8>, SealError> {
+        let sealed = BASE64_STANDARD.decode(&payload.sealed).map_err(|_| SealError::Invalid)?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(SealError::Invalid);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let aad = self.associated_data(kind, from_host, users);
+
+        self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad }).map_err(|_| SealError::Invalid)
+    }
+}
+
+fn kind(sdp_type: SdpType) -> &'static str {
+    match sdp_type {
+        SdpType::Offer => "offer",
+        SdpType::Answer => "answer",
+    }
+}
+
+/// `true` if the message contains an SDP or ICE payload, which has to be sealed if the session has a secret
+pub fn has_payload(message: &SignalMessage) -> bool {
+    match message {
+        SignalMessage::SdpOffer(..) | SignalMessage::SdpAnswer(..) | SignalMessage::IceCandidate(..) => true,
+        SignalMessage::Request(_, message) => has_payload(message),
+        _ => false,
+    }
+}
+
+/// `true` if the message contains a sealed payload
+pub fn is_sealed(message: &SignalMessage) -> bool {
+    match message {
+        SignalMessage::SdpOffer(_, _, payload) | SignalMessage::SdpAnswer(_, _, payload) => matches!(payload, SdpPayload::Sealed(_)),
+        SignalMessage::IceCandidate(_, _, payload) => matches!(payload, IcePayload::Sealed(_)),
+        _ => false,
+    }
+}
@@ -1,10 +1,13 @@
-use crate::protocol::{SessionId, UserId};
-use crate::socket::{DataChannelHandler, WSHost};
-use ezsockets::{ClientConfig, SocketConfig};
-use log::info;
+use crate::channel::RelayChannel;
+use crate::e2e::SessionCipher;
+use crate::options::ConnectionOptions;
+use crate::protocol::{SessionId, SignalMessage, UserId};
+use crate::socket::{self, DataChannelHandler, WSHost};
+use crate::transport::{self, DeliveryError, SignalingHandle};
+use crate::turn::TurnCache;
+use log::warn;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::peer_connection::RTCPeerConnection;
@@ -12,53 +15,154 @@ use webrtc::peer_connection::RTCPeerConnection;
 pub struct EzRTCHost {
     pub peer_connections: Arc<Mutex<HashMap<UserId, Arc<RTCPeerConnection>>>>,
     pub data_channels: Arc<Mutex<HashMap<UserId, Arc<RTCDataChannel>>>>,
+    pub relay_channels: Arc<Mutex<HashMap<UserId, RelayChannel>>>,
+    /// Short authentication strings of the connected users, see [`sas`](crate::sas)
+    pub short_auth_strings: Arc<Mutex<HashMap<UserId, String>>>,
+    /// Metadata the users of the session joined with, see [`ConnectionOptions::metadata`]
+    pub roster: Arc<Mutex<HashMap<UserId, serde_json::Value>>>,
+    /// Session the host is in, `None` after leaving it
+    pub session_id: Arc<Mutex<Option<SessionId>>>,
+    /// Claim token of the session if it's reserved
+    pub claim_token: Arc<Mutex<Option<String>>>,
     pub ice_servers: Vec<RTCIceServer>,
-    pub handle: ezsockets::Client<WSHost>,
+    pub handle: SignalingHandle,
+    options: ConnectionOptions,
 }
 
 impl EzRTCHost {
     pub async fn new(host_url: String, session_id: String, ice_servers: Vec<RTCIceServer>, data_channel_handler: Arc<Box<dyn DataChannelHandler>>) -> Self {
+        Self::with_options(host_url, session_id, ice_servers, data_channel_handler, ConnectionOptions::default()).await
+    }
+
+    pub async fn with_options(host_url: String, session_id: String, ice_servers: Vec<RTCIceServer>, data_channel_handler: Arc<Box<dyn DataChannelHandler>>, options: ConnectionOptions) -> Self {
         let global_peer_connections = Arc::new(Mutex::new(HashMap::new()));
         let global_data_channels = Arc::new(Mutex::new(HashMap::new()));
+        let global_relay_channels = Arc::new(Mutex::new(HashMap::new()));
+        let global_short_auth_strings = Arc::new(Mutex::new(HashMap::new()));
+        let global_roster = Arc::new(Mutex::new(HashMap::new()));
+        let global_session_id = Arc::new(Mutex::new(Some(SessionId::new(session_id.clone()))));
+        let global_claim_token = Arc::new(Mutex::new(options.claim_token.clone()));
         let signaling_url = url::Url::parse(&host_url).expect("Invalid host URL");
+        let cipher = options.session_secret.as_deref().map(|secret| SessionCipher::new(secret, &SessionId::new(session_id.clone()), true));
+        let (handle, rx) = SignalingHandle::with_cipher(cipher);
 
         let dc = Arc::clone(&global_data_channels);
         let pc = Arc::clone(&global_peer_connections);
+        let rc = Arc::clone(&global_relay_channels);
+        let sas = Arc::clone(&global_short_auth_strings);
+        let roster = Arc::clone(&global_roster);
+        let sid = Arc::clone(&global_session_id);
+        let claim_token = Arc::clone(&global_claim_token);
         let ice = ice_servers.clone();
+        let hndl = handle.clone();
+        let url = signaling_url.clone();
+        let long_poll_fallback = options.long_poll_fallback;
+        let encoding = options.encoding;
+        let host_options = options.clone();
+
+        // Crypto provider, already installed if a host or client was created before in this process
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
 
-        // Crypto provider
-        rustls::crypto::aws_lc_rs::default_provider().install_default().expect("failed to install default crypto provider");
-
-        let config = ClientConfig::new(signaling_url);
-        let config = config.socket_config(SocketConfig {
-            heartbeat: Duration::from_secs(60),
-            timeout: Duration::from_secs(90),
-            ..SocketConfig::default()
-        });
-
-        let (handle, future) = ezsockets::connect(
-            |handle| WSHost {
-                handle,
-                session_id: SessionId::new(session_id),
-                data_channels: dc,
-                peer_connections: pc,
-                ice_servers: ice,
-                data_channel_handler,
+        transport::connect(
+            signaling_url,
+            rx,
+            move |fallback| WSHost {
+                handle: hndl.clone(),
+                fallback,
+                session_id: sid.clone(),
+                claim_token: claim_token.clone(),
+                data_channels: dc.clone(),
+                peer_connections: pc.clone(),
+                ice_servers: ice.clone(),
+                signaling_url: url.clone(),
+                turn_credentials: TurnCache::default(),
+                ice_config: None,
+                relay_channels: rc.clone(),
+                short_auth_strings: sas.clone(),
+                roster: roster.clone(),
+                options: options.clone(),
+                server: None,
+                data_channel_handler: data_channel_handler.clone(),
             },
-            config,
+            long_poll_fallback,
+            encoding,
         )
         .await;
 
-        tokio::spawn(async move {
-            info!("Connected to signaling server");
-            future.await.unwrap();
-        });
-
-        return Self {
+        Self {
             peer_connections: global_peer_connections.clone(),
             data_channels: global_data_channels.clone(),
+            relay_channels: global_relay_channels,
+            short_auth_strings: global_short_auth_strings,
+            roster: global_roster,
+            session_id: global_session_id,
+            claim_token: global_claim_token,
             ice_servers: ice_servers.clone(),
             handle,
+            options: host_options,
+        }
+    }
+
+    /// Short authentication string of the connection with the user, `None` until the peer connection is connected
+    pub fn short_auth_string(&self, user_id: UserId) -> Option<String> {
+        self.short_auth_strings.lock().unwrap().get(&user_id).cloned()
+    }
+
+    /// Metadata the user joined with, `null` if it didn't send any and `None` if the user isn't in the session
+    pub fn metadata(&self, user_id: UserId) -> Option<serde_json::Value> {
+        self.roster.lock().unwrap().get(&user_id).cloned()
+    }
+
+    /// Users of the session with the metadata they joined with
+    pub fn roster(&self) -> HashMap<UserId, serde_json::Value> {
+        self.roster.lock().unwrap().clone()
+    }
+
+    /// Leave the session without closing the signaling connection, the connections with its users are closed
+    pub async fn leave_session(&self) -> Result<(), DeliveryError> {
+        let Some(session_id) = self.session_id.lock().unwrap().take() else {
+            return Ok(());
         };
+        *self.claim_token.lock().unwrap() = None;
+
+        // close the relays first, the server only passes their close on to users of the session
+        self.close_connections().await;
+
+        self.handle.send_acknowledged(SignalMessage::SessionLeave(session_id)).await
+    }
+
+    /// Leave the current session and host `session_id` over the same signaling connection,
+    /// reserved sessions need their `claim_token`
+    pub async fn join_session(&self, session_id: String, claim_token: Option<String>) -> Result<(), DeliveryError> {
+        if let Err(e) = self.leave_session().await {
+            warn!("Failed to leave session: {}", e);
+        }
+
+        let session_id = SessionId::new(session_id);
+        let cipher = self.options.session_secret.as_deref().map(|secret| SessionCipher::new(secret, &session_id, true));
+        self.handle.set_cipher(cipher);
+
+        *self.session_id.lock().unwrap() = Some(session_id.clone());
+        *self.claim_token.lock().unwrap() = claim_token.clone();
+
+        let join = socket::host_join_message(session_id, claim_token, self.options.metadata.clone());
+        self.handle.send_acknowledged(join).await
+    }
+
+    /// Close the peer connections and relays of every user
+    async fn close_connections(&self) {
+        let peer_connections: Vec<_> = self.peer_connections.lock().unwrap().drain().map(|(_, peer_connection)| peer_connection).collect();
+        self.data_channels.lock().unwrap().clear();
+        for (_, relay_channel) in self.relay_channels.lock().unwrap().drain() {
+            relay_channel.close();
+        }
+        self.short_auth_strings.lock().unwrap().clear();
+        self.roster.lock().unwrap().clear();
+
+        for peer_connection in peer_connections {
+            if let Err(e) = peer_connection.close().await {
+                warn!("Failed to close peer connection: {:?}", e);
+            }
+        }
     }
 }
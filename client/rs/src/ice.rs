@@ -0,0 +1,40 @@
+use crate::protocol::{IceConfig, IceServer, IceTransportPolicy, SessionId};
+use crate::turn::{self, TurnCache};
+use url::Url;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+
+impl IceServer {
+    pub fn to_ice_server(&self) -> RTCIceServer {
+        RTCIceServer {
+            urls: self.urls.clone(),
+            username: self.username.clone().unwrap_or_default(),
+            credential: self.credential.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl From<IceTransportPolicy> for RTCIceTransportPolicy {
+    fn from(policy: IceTransportPolicy) -> Self {
+        match policy {
+            IceTransportPolicy::All => RTCIceTransportPolicy::All,
+            IceTransportPolicy::Relay => RTCIceTransportPolicy::Relay,
+        }
+    }
+}
+
+/// Build the peer connection configuration.
+/// ICE servers advertised by the signaling server replace `ice_servers`, TURN credentials of the session are added to both.
+pub async fn rtc_configuration(signaling_url: &Url, session_id: &SessionId, ice_servers: &[RTCIceServer], ice_config: Option<&IceConfig>, turn_credentials: &mut TurnCache) -> RTCConfiguration {
+    let ice_servers = match ice_config {
+        Some(ice_config) if !ice_config.ice_servers.is_empty() => ice_config.ice_servers.iter().map(IceServer::to_ice_server).collect(),
+        _ => ice_servers.to_vec(),
+    };
+
+    RTCConfiguration {
+        ice_servers: turn::merge_ice_servers(signaling_url, session_id, &ice_servers, turn_credentials).await,
+        ice_transport_policy: ice_config.and_then(|ice_config| ice_config.ice_transport_policy).map(Into::into).unwrap_or_default(),
+        ..Default::default()
+    }
+}
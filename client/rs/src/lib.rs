@@ -3,7 +3,20 @@ pub use webrtc::{
     ice_transport::ice_server::RTCIceServer,
 };
 
+pub use channel::DataChannel;
+pub use options::ConnectionOptions;
+
+pub mod channel;
 pub mod client;
+pub mod e2e;
 pub mod host;
+pub mod ice;
+pub mod options;
 pub mod protocol;
+pub mod sas;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod session;
 pub mod socket;
+pub mod transport;
+pub mod turn;
@@ -0,0 +1,35 @@
+use crate::protocol::Encoding;
+
+/// Optional behaviour of [`EzRTCHost`](crate::host::EzRTCHost) and [`EzRTCClient`](crate::client::EzRTCClient).
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    /// Relay data channel messages through the signaling server if the peer connection fails,
+    /// the server has to allow it too
+    pub relay_fallback: bool,
+    /// Use HTTP long polling if the signaling websocket can't connect
+    pub long_poll_fallback: bool,
+    /// Claim token of a session reserved with [`reserve_session`](crate::session::reserve_session), only used by the host
+    pub claim_token: Option<String>,
+    /// Encoding of messages on the signaling websocket, binary encodings need a server that supports them
+    pub encoding: Encoding,
+    /// Secret shared by the host and its clients out of band, encrypts and authenticates the SDP and ICE payloads
+    /// so the signaling server can't read or change them, every peer of the session has to use the same secret.
+    /// Use a random secret like [`SessionCipher::generate_secret`](crate::e2e::SessionCipher::generate_secret), not a password
+    pub session_secret: Option<String>,
+    /// JSON sent with the join, like a display name or the app version, the host gets the metadata of its clients
+    /// and the clients get the metadata of the host. Servers that predate it reject joins with metadata
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            relay_fallback: false,
+            long_poll_fallback: false,
+            claim_token: None,
+            encoding: Encoding::Json,
+            session_secret: None,
+            metadata: None,
+        }
+    }
+}
@@ -6,9 +6,13 @@ use serde::{Deserialize, Serialize};
 /// Unique identifier of signaling session that each user provides
 /// when communicating with the signaling server.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct SessionId(String);
 
 impl SessionId {
+    /// Longest accepted session id
+    pub const MAX_LENGTH: usize = 64;
+
     /// Wrap String into a `SessionId` `struct`
     pub fn new(inner: String) -> Self {
         SessionId(inner)
@@ -22,16 +26,55 @@ impl SessionId {
     pub fn into_inner(self) -> String {
         self.0
     }
+
+    /// Session ids are 1 to [`SessionId::MAX_LENGTH`] characters of `a-z`, `A-Z`, `0-9`, `-`, `_` and `.`
+    pub fn validate(&self) -> Result<(), SessionIdError> {
+        if self.0.is_empty() {
+            return Err(SessionIdError::Empty);
+        }
+
+        if self.0.len() > Self::MAX_LENGTH {
+            return Err(SessionIdError::TooLong(self.0.len()));
+        }
+
+        match self.0.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))) {
+            Some(c) => Err(SessionIdError::InvalidCharacter(c)),
+            None => Ok(()),
+        }
+    }
 }
 
 impl FromStr for SessionId {
     type Err = Box<dyn std::error::Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(SessionId(s.to_string()))
+        let session_id = SessionId(s.to_string());
+        session_id.validate()?;
+
+        Ok(session_id)
+    }
+}
+
+/// Reason a [`SessionId`] was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionIdError {
+    Empty,
+    TooLong(usize),
+    InvalidCharacter(char),
+}
+
+impl Display for SessionIdError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionIdError::Empty => write!(f, "session id is empty"),
+            SessionIdError::TooLong(length) => write!(f, "session id is {length} characters long, at most {} are allowed", SessionId::MAX_LENGTH),
+            SessionIdError::InvalidCharacter(c) => write!(f, "session id contains {c:?}, only a-z, A-Z, 0-9, '-', '_' and '.' are allowed"),
+        }
     }
 }
 
+impl std::error::Error for SessionIdError {}
+
 impl Display for SessionId {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
@@ -41,11 +84,12 @@ impl Display for SessionId {
 /// Unique identifier of each peer connected to signaling server
 /// useful when communicating in one-to-many and many-to-many .
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UserId(usize);
 
 impl UserId {
     /// Wrap `usize` into a `UserId` `struct`
-    pub fn new(inner: usize) -> Self {
+    pub const fn new(inner: usize) -> Self {
         UserId(inner)
     }
 
@@ -72,27 +116,21 @@ impl Display for UserId {
 pub type IsHost = bool;
 
 /// Status of the user
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Status {
     pub session_id: Option<SessionId>,
     pub is_host: Option<IsHost>,
     pub version: Option<String>,
     pub metadata: Option<serde_json::Value>,
-}
-
-impl Default for Status {
-    fn default() -> Self {
-        Self {
-            session_id: None,
-            is_host: None,
-            version: None,
-            metadata: None,
-        }
-    }
+    /// List the session with its metadata at `GET /sessions`
+    #[serde(default)]
+    pub public: Option<bool>,
 }
 
 /// The ice candidate sent from the user to the host.
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct IceCandidateJSON {
     pub candidate: String,
     #[serde(rename = "sdpMid")]
@@ -103,29 +141,659 @@ pub struct IceCandidateJSON {
     pub username_fragment: Option<String>,
 }
 
+/// First protocol version that sends ICE candidates and SDP as structured fields instead of strings
+pub const STRUCTURED_PAYLOADS_VERSION: u32 = 2;
+
+/// First protocol version that encodes messages as [`TaggedMessage`] instead of tuples
+pub const TAGGED_FORMAT_VERSION: u32 = 3;
+
+/// ICE candidate of [`SignalMessage::IceCandidate`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum IcePayload {
+    Candidate(IceCandidateJSON),
+    /// Candidate serialized to a JSON string, used before [`STRUCTURED_PAYLOADS_VERSION`]
+    Legacy(String),
+    /// Candidate encrypted with the session secret
+    Sealed(SealedPayload),
+}
+
+impl IcePayload {
+    /// Payload in the form used by protocol `version`
+    pub fn new(candidate: IceCandidateJSON, version: u32) -> Self {
+        IcePayload::Candidate(candidate).for_version(version)
+    }
+
+    pub fn candidate(&self) -> Result<IceCandidateJSON, serde_json::Error> {
+        match self {
+            IcePayload::Candidate(candidate) => Ok(candidate.clone()),
+            IcePayload::Legacy(candidate) => serde_json::from_str(candidate),
+            IcePayload::Sealed(_) => Err(serde::de::Error::custom("the candidate is sealed with the session secret")),
+        }
+    }
+
+    /// Convert the payload to the form used by protocol `version`, invalid legacy candidates are kept as they are
+    pub fn for_version(self, version: u32) -> Self {
+        match self {
+            IcePayload::Candidate(candidate) if version < STRUCTURED_PAYLOADS_VERSION => match serde_json::to_string(&candidate) {
+                Ok(candidate) => IcePayload::Legacy(candidate),
+                Err(_) => IcePayload::Candidate(candidate),
+            },
+            IcePayload::Legacy(candidate) if version >= STRUCTURED_PAYLOADS_VERSION => match serde_json::from_str(&candidate) {
+                Ok(candidate) => IcePayload::Candidate(candidate),
+                Err(_) => IcePayload::Legacy(candidate),
+            },
+            payload => payload,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum SdpType {
+    Offer,
+    Answer,
+}
+
+/// Session description in the format of the browser's `RTCSessionDescriptionInit`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub sdp_type: SdpType,
+    pub sdp: String,
+}
+
+/// SDP of [`SignalMessage::SdpOffer`] and [`SignalMessage::SdpAnswer`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum SdpPayload {
+    Description(SessionDescription),
+    /// Plain SDP string, used before [`STRUCTURED_PAYLOADS_VERSION`]
+    Legacy(String),
+    /// Session description encrypted with the session secret
+    Sealed(SealedPayload),
+}
+
+impl SdpPayload {
+    /// Payload in the form used by protocol `version`
+    pub fn new(sdp_type: SdpType, sdp: String, version: u32) -> Self {
+        SdpPayload::Description(SessionDescription { sdp_type, sdp }).for_version(version, sdp_type)
+    }
+
+    /// SDP of the payload, empty if it's sealed
+    pub fn sdp(&self) -> &str {
+        match self {
+            SdpPayload::Description(description) => &description.sdp,
+            SdpPayload::Legacy(sdp) => sdp,
+            SdpPayload::Sealed(_) => "",
+        }
+    }
+
+    /// SDP of the payload, empty if it's sealed
+    pub fn into_sdp(self) -> String {
+        match self {
+            SdpPayload::Description(description) => description.sdp,
+            SdpPayload::Legacy(sdp) => sdp,
+            SdpPayload::Sealed(_) => String::new(),
+        }
+    }
+
+    /// Convert the payload to the form used by protocol `version`, `sdp_type` depends on the message that contains it.
+    /// Sealed payloads are the same in every version.
+    pub fn for_version(self, version: u32, sdp_type: SdpType) -> Self {
+        if matches!(self, SdpPayload::Sealed(_)) {
+            self
+        } else if version < STRUCTURED_PAYLOADS_VERSION {
+            SdpPayload::Legacy(self.into_sdp())
+        } else {
+            SdpPayload::Description(SessionDescription { sdp_type, sdp: self.into_sdp() })
+        }
+    }
+}
+
+/// Payload encrypted and authenticated with the session secret, only the peers can read it,
+/// see [`SessionCipher`](crate::e2e::SessionCipher)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SealedPayload {
+    /// Base64 encoded nonce followed by the ciphertext
+    pub sealed: String,
+}
+
+/// Time-limited TURN credentials issued by the signaling server,
+/// using the shared secret REST API scheme.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TurnCredentials {
+    /// `<expiry timestamp>:<session>`, the session is prefixed with the tenant's name on servers with tenants
+    pub username: String,
+    /// Base64 encoded HMAC-SHA1 of the username
+    pub password: String,
+    /// Lifetime of the credentials in seconds
+    pub ttl: u64,
+    pub uris: Vec<String>,
+}
+
+impl TurnCredentials {
+    /// Unix timestamp after which the TURN server rejects the credentials
+    pub fn expires_at(&self) -> Option<u64> {
+        self.username.split(':').next()?.parse().ok()
+    }
+}
+
+/// STUN or TURN server advertised by the signaling server.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+/// Which ICE candidates the peers are allowed to use.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum IceTransportPolicy {
+    All,
+    Relay,
+}
+
+/// ICE configuration sent by the signaling server in response to [`SignalMessage::SessionJoin`].
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct IceConfig {
+    pub ice_servers: Vec<IceServer>,
+    pub ice_transport_policy: Option<IceTransportPolicy>,
+}
+
+/// Long polling connection created with `POST /poll`,
+/// messages are exchanged at `/poll/<token>`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PollSession {
+    pub token: String,
+    pub user_id: UserId,
+}
+
+/// Session reserved with `POST /sessions`, the host joins it with [`SignalMessage::SessionClaim`]
+/// and clients can find it with the join code at `GET /join/<code>`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SessionReservation {
+    pub session_id: SessionId,
+    /// Short code that is easy to share with other users
+    pub code: String,
+    /// Secret that allows joining the session as host
+    pub claim_token: String,
+    /// Seconds until the reservation expires if the host doesn't claim it
+    pub expires_in: u64,
+}
+
+/// Version of the signaling protocol implemented by this crate, sent in [`SignalMessage::Hello`] and [`SignalMessage::Welcome`]
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest protocol version this crate can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Encoding of signaling messages on a websocket, selected with the `Sec-WebSocket-Protocol` header.
+/// Long polling always uses JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Text messages in the wire format of the negotiated protocol version
+    #[default]
+    Json,
+    /// Binary MessagePack messages
+    MessagePack,
+    /// Binary CBOR messages
+    Cbor,
+}
+
+impl Encoding {
+    /// Subprotocols accepted by the server
+    pub const SUBPROTOCOLS: [&'static str; 3] = ["ezrtc.json", "ezrtc.msgpack", "ezrtc.cbor"];
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            Encoding::Json => Self::SUBPROTOCOLS[0],
+            Encoding::MessagePack => Self::SUBPROTOCOLS[1],
+            Encoding::Cbor => Self::SUBPROTOCOLS[2],
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        match subprotocol {
+            "ezrtc.json" => Some(Encoding::Json),
+            "ezrtc.msgpack" => Some(Encoding::MessagePack),
+            "ezrtc.cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// `true` if messages are sent as binary websocket frames
+    pub fn is_binary(self) -> bool {
+        self != Encoding::Json
+    }
+}
+
+/// Message that couldn't be encoded or decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecError(String);
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Reason of the close frame sent to users whose protocol version the server doesn't support
+pub const UNSUPPORTED_VERSION: &str = "Unsupported protocol version";
+
+/// Optional protocol feature that both sides have to support before it's used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Binary message encoding
+    BinaryEncoding,
+    /// Relaying data channel messages through the signaling server, see [`SignalMessage::RelayOpen`]
+    Relay,
+    /// Answering [`SignalMessage::Request`] with [`SignalMessage::Ack`] or [`SignalMessage::Nack`]
+    Acknowledgements,
+    /// Capability added by a newer version
+    #[serde(other)]
+    Unknown,
+}
+
+/// Protocol versions and capabilities of a user or the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Handshake {
+    /// Newest supported protocol version, the server answers with the version both sides use
+    pub version: u32,
+    pub min_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+impl Handshake {
+    /// Handshake of this crate with the given capabilities
+    pub fn new(capabilities: Vec<Capability>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// Newest version supported by both sides, `None` if their version ranges don't overlap
+    pub fn negotiate(&self, other: &Handshake) -> Option<u32> {
+        let version = self.version.min(other.version);
+
+        if version < self.min_version.max(other.min_version) {
+            return None;
+        }
+
+        Some(version)
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
 /// `Enum` consisting of two main categories are messages used to setup signaling session
 /// and messages used to setup `WebRTC` connection afterwards.
 /// Most of the include [`SessionId`] and [`UserId`] to uniquely identify each peer.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum SignalMessage {
-    /// Either client or server connecting to signaling session
-    SessionJoin(SessionId, IsHost),
+    /// Either client or server connecting to signaling session, with optional metadata of the user like a display name
+    #[cfg_attr(feature = "schema", schemars(schema_with = "crate::schema::with_metadata::<SessionId, IsHost>"))]
+    SessionJoin(SessionId, IsHost, #[serde(default, skip_serializing_if = "Option::is_none")] Option<serde_json::Value>),
+
+    /// Host joining a session reserved with `POST /sessions`, using the claim token, with optional metadata like [`SignalMessage::SessionJoin`]
+    #[cfg_attr(feature = "schema", schemars(schema_with = "crate::schema::with_metadata::<SessionId, String>"))]
+    SessionClaim(SessionId, String, #[serde(default, skip_serializing_if = "Option::is_none")] Option<serde_json::Value>),
+
+    /// Report back to the users that both of them are in session, with the metadata the other user joined with
+    #[cfg_attr(feature = "schema", schemars(schema_with = "crate::schema::with_metadata::<SessionId, UserId>"))]
+    SessionReady(SessionId, UserId, #[serde(default, skip_serializing_if = "Option::is_none")] Option<serde_json::Value>),
+
+    /// Leave the session without closing the connection, the user can join another session afterwards
+    SessionLeave(SessionId),
 
-    /// Report back to the users that both of them are in session
-    SessionReady(SessionId, UserId),
+    /// The user left the session or disconnected, sent to the host when a client leaves and to the clients when the host leaves
+    SessionLeft(SessionId, UserId),
 
-    /// `SDP` Offer that gets passed to the other user without modifications
-    SdpOffer(SessionId, UserId, String),
+    /// `SDP` Offer that gets passed to the other user, converted to the form of the user's protocol version
+    SdpOffer(SessionId, UserId, SdpPayload),
 
-    /// `SDP` Answer that gets passed to the other user without modifications
-    SdpAnswer(SessionId, UserId, String),
+    /// `SDP` Answer that gets passed to the other user, converted to the form of the user's protocol version
+    SdpAnswer(SessionId, UserId, SdpPayload),
 
-    /// Proposed ICE Candidate of one user passed to the other user without modifications
-    IceCandidate(SessionId, UserId, String),
+    /// Proposed ICE Candidate of one user passed to the other user, converted to the form of the user's protocol version
+    IceCandidate(SessionId, UserId, IcePayload),
 
     /// Generic error containing detailed information about the cause
     Error(SessionId, UserId, String),
 
     /// KeepAlive response
     KeepAlive(UserId, Status),
+
+    /// ICE servers and transport policy configured on the signaling server
+    IceConfig(SessionId, IceConfig),
+
+    /// Continue through the signaling server after the peer connection with the user failed
+    RelayOpen(SessionId, UserId),
+
+    /// Data channel message relayed through the signaling server
+    RelayData(SessionId, UserId, String),
+
+    /// Stop relaying data channel messages with the user
+    RelayClose(SessionId, UserId),
+
+    /// Arbitrary JSON payload pushed by a backend with `POST /sessions/:id/notify`
+    Notify(SessionId, serde_json::Value),
+
+    /// Protocol versions and capabilities of the user, sent before joining a session
+    Hello(Handshake),
+
+    /// Negotiated protocol version and the capabilities of the server in response to [`SignalMessage::Hello`]
+    Welcome(UserId, Handshake),
+
+    /// Message with an id the server answers with [`SignalMessage::Ack`] or [`SignalMessage::Nack`],
+    /// only sent to servers with [`Capability::Acknowledgements`]
+    Request(String, Box<SignalMessage>),
+
+    /// The server handled the request with the id, forwarded messages were passed to the recipient
+    Ack(String),
+
+    /// The server couldn't handle the request with the id, contains the reason
+    Nack(String, String),
+}
+
+impl SignalMessage {
+    /// Decode a message in either wire format
+    pub fn decode(text: &str) -> serde_json::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+
+        if value.get("type").is_some() {
+            Ok(serde_json::from_value::<TaggedMessage>(value)?.into())
+        } else {
+            serde_json::from_value(value)
+        }
+    }
+
+    /// Encode the message in the wire format of protocol `version`
+    pub fn encode(self, version: u32) -> serde_json::Result<String> {
+        serde_json::to_string(&self.to_value(version)?)
+    }
+
+    /// Encode the message for a websocket using `encoding`, binary encodings keep the tuple layout of the enum
+    pub fn encode_binary(self, encoding: Encoding, version: u32) -> Result<Vec<u8>, CodecError> {
+        let message = self.for_version(version);
+
+        match encoding {
+            Encoding::Json => message.encode(version).map(String::into_bytes).map_err(|e| CodecError(e.to_string())),
+            Encoding::MessagePack => rmp_serde::to_vec_named(&message).map_err(|e| CodecError(e.to_string())),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&message, &mut bytes).map_err(|e| CodecError(e.to_string()))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decode a message encoded with [`SignalMessage::encode_binary`]
+    pub fn decode_binary(bytes: &[u8], encoding: Encoding) -> Result<Self, CodecError> {
+        match encoding {
+            Encoding::Json => {
+                let text = std::str::from_utf8(bytes).map_err(|e| CodecError(e.to_string()))?;
+                Self::decode(text).map_err(|e| CodecError(e.to_string()))
+            }
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| CodecError(e.to_string())),
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| CodecError(e.to_string())),
+        }
+    }
+
+    /// JSON value of the message in the wire format of protocol `version`
+    pub fn to_value(self, version: u32) -> serde_json::Result<serde_json::Value> {
+        let message = self.for_version(version);
+
+        if version >= TAGGED_FORMAT_VERSION {
+            serde_json::to_value(TaggedMessage::new(message))
+        } else {
+            serde_json::to_value(message)
+        }
+    }
+
+    /// Convert the ICE and SDP payloads to the form used by protocol `version`
+    pub fn for_version(self, version: u32) -> Self {
+        match self {
+            SignalMessage::SdpOffer(session_id, user_id, offer) => SignalMessage::SdpOffer(session_id, user_id, offer.for_version(version, SdpType::Offer)),
+            SignalMessage::SdpAnswer(session_id, user_id, answer) => SignalMessage::SdpAnswer(session_id, user_id, answer.for_version(version, SdpType::Answer)),
+            SignalMessage::IceCandidate(session_id, user_id, candidate) => SignalMessage::IceCandidate(session_id, user_id, candidate.for_version(version)),
+            SignalMessage::Request(request_id, message) => SignalMessage::Request(request_id, Box::new(message.for_version(version))),
+            // users of older versions can't read the metadata, the server reads it in joins of every version
+            SignalMessage::SessionReady(session_id, user_id, _) if version < TAGGED_FORMAT_VERSION => SignalMessage::SessionReady(session_id, user_id, None),
+            message => message,
+        }
+    }
+
+    /// Session the message refers to
+    pub fn session_id_mut(&mut self) -> Option<&mut SessionId> {
+        match self {
+            SignalMessage::SessionJoin(session_id, _, _)
+            | SignalMessage::SessionClaim(session_id, _, _)
+            | SignalMessage::SessionReady(session_id, _, _)
+            | SignalMessage::SessionLeave(session_id)
+            | SignalMessage::SessionLeft(session_id, _)
+            | SignalMessage::SdpOffer(session_id, _, _)
+            | SignalMessage::SdpAnswer(session_id, _, _)
+            | SignalMessage::IceCandidate(session_id, _, _)
+            | SignalMessage::Error(session_id, _, _)
+            | SignalMessage::IceConfig(session_id, _)
+            | SignalMessage::RelayOpen(session_id, _)
+            | SignalMessage::RelayData(session_id, _, _)
+            | SignalMessage::RelayClose(session_id, _)
+            | SignalMessage::Notify(session_id, _) => Some(session_id),
+            SignalMessage::KeepAlive(_, status) => status.session_id.as_mut(),
+            SignalMessage::Request(_, message) => message.session_id_mut(),
+            SignalMessage::Hello(_) | SignalMessage::Welcome(_, _) | SignalMessage::Ack(_) | SignalMessage::Nack(_, _) => None,
+        }
+    }
+
+    /// Id of a request or of the request an acknowledgement answers
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            SignalMessage::Request(request_id, _) | SignalMessage::Ack(request_id) | SignalMessage::Nack(request_id, _) => Some(request_id),
+            _ => None,
+        }
+    }
+}
+
+/// Message in the self-describing wire format, e.g. `{"type":"sdp_offer","session_id":"id","user_id":3,"description":{...}}`.
+/// Fields other than `type` can be added without breaking older readers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TaggedMessage {
+    #[serde(flatten)]
+    pub message: TaggedBody,
+    /// Identifies the message in [`SignalMessage::Ack`] and [`SignalMessage::Nack`], see [`SignalMessage::Request`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Unix timestamp in milliseconds when the message was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+impl TaggedMessage {
+    pub fn new(message: SignalMessage) -> Self {
+        Self {
+            request_id: message.request_id().map(str::to_string),
+            message: message.into(),
+            timestamp: None,
+        }
+    }
+}
+
+impl From<TaggedMessage> for SignalMessage {
+    fn from(tagged: TaggedMessage) -> Self {
+        let request_id = tagged.request_id.unwrap_or_default();
+
+        match tagged.message {
+            TaggedBody::Ack => SignalMessage::Ack(request_id),
+            TaggedBody::Nack { reason } => SignalMessage::Nack(request_id, reason),
+            message if request_id.is_empty() => message.into(),
+            message => SignalMessage::Request(request_id, Box::new(message.into())),
+        }
+    }
+}
+
+/// [`SignalMessage`] with named fields, see [`TaggedMessage`]. Request ids are kept in [`TaggedMessage::request_id`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaggedBody {
+    SessionJoin {
+        session_id: SessionId,
+        is_host: IsHost,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<serde_json::Value>,
+    },
+    SessionClaim {
+        session_id: SessionId,
+        claim_token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<serde_json::Value>,
+    },
+    SessionReady {
+        session_id: SessionId,
+        user_id: UserId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<serde_json::Value>,
+    },
+    SessionLeave {
+        session_id: SessionId,
+    },
+    SessionLeft {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    SdpOffer {
+        session_id: SessionId,
+        user_id: UserId,
+        description: SdpPayload,
+    },
+    SdpAnswer {
+        session_id: SessionId,
+        user_id: UserId,
+        description: SdpPayload,
+    },
+    IceCandidate {
+        session_id: SessionId,
+        user_id: UserId,
+        candidate: IcePayload,
+    },
+    Error {
+        session_id: SessionId,
+        user_id: UserId,
+        message: String,
+    },
+    KeepAlive {
+        user_id: UserId,
+        status: Status,
+    },
+    IceConfig {
+        session_id: SessionId,
+        config: IceConfig,
+    },
+    RelayOpen {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    RelayData {
+        session_id: SessionId,
+        user_id: UserId,
+        data: String,
+    },
+    RelayClose {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    Notify {
+        session_id: SessionId,
+        payload: serde_json::Value,
+    },
+    Hello {
+        #[serde(flatten)]
+        handshake: Handshake,
+    },
+    Welcome {
+        user_id: UserId,
+        #[serde(flatten)]
+        handshake: Handshake,
+    },
+    Ack,
+    Nack {
+        reason: String,
+    },
+}
+
+impl From<SignalMessage> for TaggedBody {
+    fn from(message: SignalMessage) -> Self {
+        match message {
+            SignalMessage::SessionJoin(session_id, is_host, metadata) => TaggedBody::SessionJoin { session_id, is_host, metadata },
+            SignalMessage::SessionClaim(session_id, claim_token, metadata) => TaggedBody::SessionClaim { session_id, claim_token, metadata },
+            SignalMessage::SessionReady(session_id, user_id, metadata) => TaggedBody::SessionReady { session_id, user_id, metadata },
+            SignalMessage::SessionLeave(session_id) => TaggedBody::SessionLeave { session_id },
+            SignalMessage::SessionLeft(session_id, user_id) => TaggedBody::SessionLeft { session_id, user_id },
+            SignalMessage::SdpOffer(session_id, user_id, description) => TaggedBody::SdpOffer { session_id, user_id, description },
+            SignalMessage::SdpAnswer(session_id, user_id, description) => TaggedBody::SdpAnswer { session_id, user_id, description },
+            SignalMessage::IceCandidate(session_id, user_id, candidate) => TaggedBody::IceCandidate { session_id, user_id, candidate },
+            SignalMessage::Error(session_id, user_id, message) => TaggedBody::Error { session_id, user_id, message },
+            SignalMessage::KeepAlive(user_id, status) => TaggedBody::KeepAlive { user_id, status },
+            SignalMessage::IceConfig(session_id, config) => TaggedBody::IceConfig { session_id, config },
+            SignalMessage::RelayOpen(session_id, user_id) => TaggedBody::RelayOpen { session_id, user_id },
+            SignalMessage::RelayData(session_id, user_id, data) => TaggedBody::RelayData { session_id, user_id, data },
+            SignalMessage::RelayClose(session_id, user_id) => TaggedBody::RelayClose { session_id, user_id },
+            SignalMessage::Notify(session_id, payload) => TaggedBody::Notify { session_id, payload },
+            SignalMessage::Hello(handshake) => TaggedBody::Hello { handshake },
+            SignalMessage::Welcome(user_id, handshake) => TaggedBody::Welcome { user_id, handshake },
+            SignalMessage::Request(_, message) => (*message).into(),
+            SignalMessage::Ack(_) => TaggedBody::Ack,
+            SignalMessage::Nack(_, reason) => TaggedBody::Nack { reason },
+        }
+    }
+}
+
+impl From<TaggedBody> for SignalMessage {
+    fn from(message: TaggedBody) -> Self {
+        match message {
+            TaggedBody::SessionJoin { session_id, is_host, metadata } => SignalMessage::SessionJoin(session_id, is_host, metadata),
+            TaggedBody::SessionClaim { session_id, claim_token, metadata } => SignalMessage::SessionClaim(session_id, claim_token, metadata),
+            TaggedBody::SessionReady { session_id, user_id, metadata } => SignalMessage::SessionReady(session_id, user_id, metadata),
+            TaggedBody::SessionLeave { session_id } => SignalMessage::SessionLeave(session_id),
+            TaggedBody::SessionLeft { session_id, user_id } => SignalMessage::SessionLeft(session_id, user_id),
+            TaggedBody::SdpOffer { session_id, user_id, description } => SignalMessage::SdpOffer(session_id, user_id, description),
+            TaggedBody::SdpAnswer { session_id, user_id, description } => SignalMessage::SdpAnswer(session_id, user_id, description),
+            TaggedBody::IceCandidate { session_id, user_id, candidate } => SignalMessage::IceCandidate(session_id, user_id, candidate),
+            TaggedBody::Error { session_id, user_id, message } => SignalMessage::Error(session_id, user_id, message),
+            TaggedBody::KeepAlive { user_id, status } => SignalMessage::KeepAlive(user_id, status),
+            TaggedBody::IceConfig { session_id, config } => SignalMessage::IceConfig(session_id, config),
+            TaggedBody::RelayOpen { session_id, user_id } => SignalMessage::RelayOpen(session_id, user_id),
+            TaggedBody::RelayData { session_id, user_id, data } => SignalMessage::RelayData(session_id, user_id, data),
+            TaggedBody::RelayClose { session_id, user_id } => SignalMessage::RelayClose(session_id, user_id),
+            TaggedBody::Notify { session_id, payload } => SignalMessage::Notify(session_id, payload),
+            TaggedBody::Hello { handshake } => SignalMessage::Hello(handshake),
+            TaggedBody::Welcome { user_id, handshake } => SignalMessage::Welcome(user_id, handshake),
+            TaggedBody::Ack => SignalMessage::Ack(String::new()),
+            TaggedBody::Nack { reason } => SignalMessage::Nack(String::new(), reason),
+        }
+    }
 }
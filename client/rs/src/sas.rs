@@ -0,0 +1,63 @@
+use sha2::{Digest, Sha256};
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// Prefix of the data channel message sent by [`DataChannel::confirm_short_auth_string`](crate::DataChannel::confirm_short_auth_string)
+pub const CONFIRMATION_PREFIX: &str = "ezrtc-sas:";
+
+/// DTLS fingerprints of the `a=fingerprint` lines in the SDP, like `sha-256 AB:CD:...`
+pub fn fingerprints(sdp: &str) -> Vec<String> {
+    let mut fingerprints: Vec<String> = sdp
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .filter_map(|fingerprint| fingerprint.split_once(' '))
+        .map(|(algorithm, value)| format!("{} {}", algorithm.to_lowercase(), value.trim().to_uppercase()))
+        .collect();
+
+    // every media section repeats the fingerprint
+    fingerprints.sort();
+    fingerprints.dedup();
+    fingerprints
+}
+
+/// Six digit string derived from the fingerprints of both peers, like `042 917`.
+/// Both peers get the same string unless someone changed the fingerprints in the signaling messages.
+/// `None` if either description has no fingerprint.
+pub fn derive(local_sdp: &str, remote_sdp: &str) -> Option<String> {
+    let local = fingerprints(local_sdp);
+    let remote = fingerprints(remote_sdp);
+    if local.is_empty() || remote.is_empty() {
+        return None;
+    }
+
+    // the order can't depend on which side computes it
+    let (first, second) = if local <= remote { (local, remote) } else { (remote, local) };
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"ezrtc short authentication string\n");
+    hasher.update(first.join("\n"));
+    hasher.update(b"\n\n");
+    hasher.update(second.join("\n"));
+    let hash = hasher.finalize();
+
+    let number = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+    Some(format!("{:03} {:03}", number / 1000, number % 1000))
+}
+
+/// Short authentication string of a connected peer connection, see [`derive`]
+pub async fn short_auth_string(peer_connection: &RTCPeerConnection) -> Option<String> {
+    let local = peer_connection.local_description().await?;
+    let remote = peer_connection.remote_description().await?;
+
+    derive(&local.sdp, &remote.sdp)
+}
+
+/// Data channel message confirming that the user compared the string with the other user
+pub fn confirmation(sas: &str) -> String {
+    format!("{CONFIRMATION_PREFIX}{sas}")
+}
+
+/// `Some(true)` if the message confirms `sas`, `Some(false)` if the other peer has a different string,
+/// `None` if the message isn't a confirmation
+pub fn check_confirmation(message: &str, sas: &str) -> Option<bool> {
+    message.strip_prefix(CONFIRMATION_PREFIX).map(|confirmed| confirmed == sas)
+}
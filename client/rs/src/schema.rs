@@ -0,0 +1,44 @@
+use crate::protocol::{SignalMessage, TaggedMessage};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{ArrayValidation, InstanceType, Metadata, RootSchema, Schema, SchemaObject, SingleOrVec, SubschemaValidation};
+use schemars::JsonSchema;
+
+/// JSON Schema of a signaling message in any wire format, the tuple format of protocol versions 1 and 2
+/// or the tagged format of version 3. Written to `protocol/schema.json` by `cargo r --example schema --features schema`.
+pub fn json_schema() -> RootSchema {
+    let mut generator = SchemaSettings::draft07().into_generator();
+    let formats = vec![generator.subschema_for::<SignalMessage>(), generator.subschema_for::<TaggedMessage>()];
+
+    RootSchema {
+        meta_schema: generator.settings().meta_schema.clone(),
+        schema: SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                title: Some("ezrtc signaling message".to_string()),
+                ..Default::default()
+            })),
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(formats),
+                ..Default::default()
+            })),
+            ..Default::default()
+        },
+        definitions: generator.take_definitions(),
+    }
+}
+
+/// Tuple of two fields followed by optional metadata, which is left out of the array when there is none
+pub(crate) fn with_metadata<A: JsonSchema, B: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    let items = vec![generator.subschema_for::<A>(), generator.subschema_for::<B>(), generator.subschema_for::<serde_json::Value>()];
+
+    SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(SingleOrVec::Vec(items)),
+            min_items: Some(2),
+            max_items: Some(3),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
@@ -0,0 +1,31 @@
+use crate::protocol::{SessionId, SessionReservation};
+use crate::transport::http_url;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use url::Url;
+
+#[derive(Deserialize)]
+struct JoinCodeMessage {
+    session_id: SessionId,
+}
+
+/// Reserve a session id and join code on the signaling server,
+/// pass the claim token to the host with [`ConnectionOptions::claim_token`](crate::options::ConnectionOptions::claim_token)
+pub async fn reserve_session(signaling_url: &Url) -> Result<SessionReservation, reqwest::Error> {
+    let client = reqwest::Client::new();
+    let response = client.post(http_url(signaling_url, "sessions")).send().await?.error_for_status()?;
+
+    response.json().await
+}
+
+/// Find the session reserved with the join code, returns `None` if the code doesn't exist or expired
+pub async fn resolve_join_code(signaling_url: &Url, code: &str) -> Result<Option<SessionId>, reqwest::Error> {
+    let response = reqwest::get(http_url(signaling_url, &format!("join/{code}"))).await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let message: JoinCodeMessage = response.error_for_status()?.json().await?;
+    Ok(Some(message.session_id))
+}
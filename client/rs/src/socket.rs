@@ -1,4 +1,11 @@
-use crate::protocol::{IceCandidateJSON, SessionId, SignalMessage, UserId};
+use crate::channel::{DataChannel, RelayChannel};
+use crate::client;
+use crate::ice;
+use crate::options::ConnectionOptions;
+use crate::protocol::{Capability, Handshake, IceCandidateJSON, IceConfig, IcePayload, SdpPayload, SdpType, SessionId, SignalMessage, UserId, PROTOCOL_VERSION, UNSUPPORTED_VERSION};
+use crate::sas;
+use crate::transport::{SignalingHandle, SignalingPeer};
+use crate::turn::TurnCache;
 use async_trait::async_trait;
 use ezsockets::client::ClientCloseMode;
 use ezsockets::Bytes;
@@ -10,6 +17,7 @@ use log::{error, info, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
+use tokio::sync::oneshot;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::APIBuilder;
@@ -19,7 +27,6 @@ use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
-use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::signaling_state::RTCSignalingState;
@@ -27,227 +34,533 @@ use webrtc::peer_connection::RTCPeerConnection;
 
 pub enum WSCall {}
 
+/// Times an offer or answer is sent while the server doesn't acknowledge it
+const SDP_ATTEMPTS: u32 = 3;
+
 pub struct WSHost {
-    pub session_id: SessionId,
+    /// Session the host is in, `None` after leaving it
+    pub session_id: Arc<Mutex<Option<SessionId>>>,
+    /// Claim token of the session if it's reserved, see [`ConnectionOptions::claim_token`]
+    pub claim_token: Arc<Mutex<Option<String>>>,
     pub peer_connections: Arc<Mutex<HashMap<UserId, Arc<RTCPeerConnection>>>>,
     pub data_channels: Arc<Mutex<HashMap<UserId, Arc<RTCDataChannel>>>>,
     pub ice_servers: Vec<RTCIceServer>,
-    pub handle: ezsockets::Client<Self>,
+    pub signaling_url: url::Url,
+    pub turn_credentials: TurnCache,
+    pub ice_config: Option<IceConfig>,
+    pub relay_channels: Arc<Mutex<HashMap<UserId, RelayChannel>>>,
+    pub short_auth_strings: Arc<Mutex<HashMap<UserId, String>>>,
+    /// Metadata the users of the session joined with, `null` if they didn't send any
+    pub roster: Arc<Mutex<HashMap<UserId, serde_json::Value>>>,
+    pub options: ConnectionOptions,
+    /// Versions and capabilities of the server, `None` until it answers the hello or if it's too old to know it
+    pub server: Option<Handshake>,
+    pub handle: SignalingHandle,
+    pub(crate) fallback: Option<oneshot::Sender<()>>,
     pub data_channel_handler: Arc<Box<dyn DataChannelHandler>>,
 }
 
 pub struct WSClient {
-    pub session_id: SessionId,
+    /// Session the client is in, `None` after leaving it
+    pub session_id: Arc<Mutex<Option<SessionId>>>,
     pub peer_connection: Arc<Mutex<Arc<RTCPeerConnection>>>,
     pub ice_servers: Vec<RTCIceServer>,
-    pub handle: ezsockets::Client<Self>,
+    pub signaling_url: url::Url,
+    pub turn_credentials: TurnCache,
+    /// ICE configuration advertised by the signaling server, shared with [`EzRTCClient`](crate::client::EzRTCClient) to join other sessions
+    pub ice_config: Arc<Mutex<Option<IceConfig>>>,
+    pub relay_channel: Arc<Mutex<Option<RelayChannel>>>,
+    pub short_auth_string: Arc<Mutex<Option<String>>>,
+    /// Metadata the host joined with
+    pub host_metadata: Arc<Mutex<Option<serde_json::Value>>>,
+    pub options: ConnectionOptions,
+    /// Versions and capabilities of the server, `None` until it answers the hello or if it's too old to know it
+    pub server: Option<Handshake>,
+    pub handle: SignalingHandle,
+    pub(crate) fallback: Option<oneshot::Sender<()>>,
     pub data_channel_handler: Arc<Box<dyn DataChannelHandler>>,
 }
 
 pub trait DataChannelHandler: Send + Sync {
-    fn handle_data_channel_open(&self, dc: Arc<RTCDataChannel>);
+    fn handle_data_channel_open(&self, dc: DataChannel);
     fn handle_data_channel_message(&self, message: String);
     fn handle_keep_alive(&self, handle: &mut WSHost, user_id: UserId);
+
+    /// Called with the payload of a [`SignalMessage::Notify`] sent by a backend
+    fn handle_notification(&self, _payload: serde_json::Value) {}
 }
 
 #[async_trait]
-impl ezsockets::ClientExt for WSHost {
-    type Call = WSCall;
+impl SignalingPeer for WSHost {
+    async fn handle_message(&mut self, mut request: SignalMessage) {
+        info!("Message received from signaling server: {:?}", request);
 
-    async fn on_text(&mut self, text: Utf8Bytes) -> Result<(), Error> {
-        info!("Message received from signaling server: {:?}", text);
+        if is_stale(&mut request, &self.session_id) {
+            warn!("Ignoring message of a session the host left");
+            return;
+        }
 
-        match serde_json::from_str::<SignalMessage>(&text) {
-            Ok(request) => match request {
-                SignalMessage::SessionReady(session_id, user_id) => {
-                    // Setup WebRTC
-                    let mut m = MediaEngine::default();
-                    m.register_default_codecs().unwrap();
+        match request {
+            SignalMessage::SessionReady(session_id, user_id, metadata) => {
+                self.roster.lock().unwrap().insert(user_id, metadata.unwrap_or_default());
 
-                    let mut registry = Registry::new();
-                    registry = register_default_interceptors(registry, &mut m).unwrap();
+                // Setup WebRTC
+                let mut m = MediaEngine::default();
+                m.register_default_codecs().unwrap();
 
-                    let api = APIBuilder::new().with_media_engine(m).with_interceptor_registry(registry).build();
+                let mut registry = Registry::new();
+                registry = register_default_interceptors(registry, &mut m).unwrap();
 
-                    let config = RTCConfiguration {
-                        ice_servers: self.ice_servers.clone(),
-                        ..Default::default()
-                    };
+                let api = APIBuilder::new().with_media_engine(m).with_interceptor_registry(registry).build();
 
-                    let peer_connection = Arc::new(api.new_peer_connection(config).await.unwrap());
-                    let data_channel = peer_connection.create_data_channel("ezrtc-dc", None).await.unwrap();
+                let config = ice::rtc_configuration(&self.signaling_url, &session_id, &self.ice_servers, self.ice_config.as_ref(), &mut self.turn_credentials).await;
 
-                    // Set up ICE candidate handler to send candidates to the client
-                    let hndl = self.handle.clone();
-                    let session_id_clone = session_id.clone();
-
-                    peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
-                        let hndl2 = hndl.clone();
-                        let session_id2 = session_id_clone.clone();
-
-                        Box::pin(async move {
-                            if let Some(c) = candidate {
-                                info!("Host sending ICE candidate: {:?}", c);
-
-                                match c.to_json() {
-                                    Ok(ice_candidate_init) => {
-                                        let ice_json = IceCandidateJSON {
-                                            candidate: ice_candidate_init.candidate,
-                                            sdp_mid: ice_candidate_init.sdp_mid,
-                                            sdp_mline_index: ice_candidate_init.sdp_mline_index,
-                                            username_fragment: ice_candidate_init.username_fragment,
-                                        };
-
-                                        let ice_candidate_str = serde_json::to_string(&ice_json).unwrap();
-
-                                        hndl2
-                                            .text(serde_json::to_string(&SignalMessage::IceCandidate(session_id2, user_id, ice_candidate_str)).unwrap())
-                                            .unwrap();
-                                    }
-                                    Err(e) => {
-                                        warn!("Failed to convert ICE candidate to JSON: {:?}", e);
-                                    }
+                let peer_connection = Arc::new(api.new_peer_connection(config).await.unwrap());
+                let data_channel = peer_connection.create_data_channel("ezrtc-dc", None).await.unwrap();
+
+                // Set up ICE candidate handler to send candidates to the client
+                let hndl = self.handle.clone();
+                let session_id_clone = session_id.clone();
+                let version = self.handle.version();
+
+                peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+                    let hndl2 = hndl.clone();
+                    let session_id2 = session_id_clone.clone();
+
+                    Box::pin(async move {
+                        if let Some(c) = candidate {
+                            info!("Host sending ICE candidate: {:?}", c);
+
+                            match c.to_json() {
+                                Ok(ice_candidate_init) => {
+                                    let ice_json = IceCandidateJSON {
+                                        candidate: ice_candidate_init.candidate,
+                                        sdp_mid: ice_candidate_init.sdp_mid,
+                                        sdp_mline_index: ice_candidate_init.sdp_mline_index,
+                                        username_fragment: ice_candidate_init.username_fragment,
+                                    };
+
+                                    let payload = IcePayload::new(ice_json, version);
+
+                                    hndl2.send(SignalMessage::IceCandidate(session_id2, user_id, payload)).unwrap();
+                                }
+                                Err(e) => {
+                                    warn!("Failed to convert ICE candidate to JSON: {:?}", e);
                                 }
-                            } else {
-                                info!("Host ICE gathering complete (null candidate)");
                             }
-                        })
-                    }));
+                        } else {
+                            info!("Host ICE gathering complete (null candidate)");
+                        }
+                    })
+                }));
+
+                let offer = peer_connection.create_offer(None).await.unwrap();
+
+                peer_connection.set_local_description(offer.clone()).await.unwrap();
+
+                self.peer_connections.lock().unwrap().insert(user_id, peer_connection);
+                self.data_channels.lock().unwrap().insert(user_id, data_channel);
+
+                // Waiting for the acknowledgement would block the message that contains it
+                let hndl = self.handle.clone();
+                let pcs = Arc::clone(&self.peer_connections);
+                let dcs = Arc::clone(&self.data_channels);
+                let roster = Arc::clone(&self.roster);
+                let offer = SignalMessage::SdpOffer(session_id, user_id, SdpPayload::new(SdpType::Offer, offer.sdp, version));
+                tokio::spawn(async move {
+                    if let Err(e) = hndl.send_with_retry(offer, SDP_ATTEMPTS).await {
+                        warn!("Offer to user {:?} wasn't delivered, aborting negotiation: {}", user_id, e);
+
+                        roster.lock().unwrap().remove(&user_id);
+                        dcs.lock().unwrap().remove(&user_id);
+                        let peer_connection = pcs.lock().unwrap().remove(&user_id);
+                        if let Some(peer_connection) = peer_connection {
+                            if let Err(e) = peer_connection.close().await {
+                                warn!("Failed to close peer connection: {:?}", e);
+                            }
+                        }
+                    }
+                });
+            }
+            SignalMessage::SdpAnswer(session_id, user_id, sdp_answer) => {
+                let peer_connection = self.peer_connections.lock().unwrap().get(&user_id).cloned();
+                let data_channel = self.data_channels.lock().unwrap().get(&user_id).cloned();
+                let (peer_connection, data_channel) = match (peer_connection, data_channel) {
+                    (Some(peer_connection), Some(data_channel)) => (peer_connection, data_channel),
+                    _ => {
+                        warn!("Ignoring answer of user {:?} without a connection", user_id);
+                        return;
+                    }
+                };
 
-                    let offer = peer_connection.create_offer(None).await.unwrap();
+                let answer = match RTCSessionDescription::answer(sdp_answer.into_sdp()) {
+                    Ok(answer) => answer,
+                    Err(e) => {
+                        warn!("Invalid answer from user {:?}: {:?}", user_id, e);
+                        return;
+                    }
+                };
 
-                    peer_connection.set_local_description(offer.clone()).await.unwrap();
+                let pc = Arc::clone(&peer_connection);
+                if peer_connection.signaling_state() == RTCSignalingState::HaveLocalOffer {
+                    if let Err(e) = pc.set_remote_description(answer).await {
+                        warn!("Failed to set the answer of user {:?}: {:?}", user_id, e);
+                        return;
+                    }
 
-                    self.peer_connections.lock().unwrap().insert(user_id, peer_connection);
-                    self.data_channels.lock().unwrap().insert(user_id, data_channel);
+                    info!("Remote description set");
 
-                    self.handle
-                        .text(serde_json::to_string(&SignalMessage::SdpOffer(session_id, user_id, offer.clone().sdp)).unwrap())
-                        .unwrap();
-                }
-                SignalMessage::SdpAnswer(_session_id, user_id, sdp_answer) => {
-                    let peer_connection = {
-                        let peer_connections = self.peer_connections.lock().unwrap();
-                        peer_connections.get(&user_id).unwrap().clone()
-                    };
-                    let data_channel = {
-                        let data_channels = self.data_channels.lock().unwrap();
-                        data_channels.get(&user_id).unwrap().clone()
-                    };
+                    // Set up data channel handlers after setting remote description
+                    let dc = Arc::clone(&data_channel);
+                    let pc = Arc::clone(&peer_connection);
+                    let dcs = Arc::clone(&self.data_channels);
+                    let pcs = Arc::clone(&self.peer_connections);
+                    let rcs = Arc::clone(&self.relay_channels);
+                    let auth_strings = Arc::clone(&self.short_auth_strings);
+                    let roster = Arc::clone(&self.roster);
+                    let relay_fallback = self.options.relay_fallback && server_supports(self.server.as_ref(), Capability::Relay);
+                    let hndl = self.handle.clone();
+                    let dc_handler = self.data_channel_handler.clone();
+                    peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+                        warn!("State changed => {:?}", state);
+
+                        let dc2 = Arc::clone(&dc);
+                        let pc2 = Arc::clone(&pc);
+                        let dcs = Arc::clone(&dcs);
+                        let pcs = Arc::clone(&pcs);
+                        match state {
+                            RTCPeerConnectionState::Connected => {
+                                let auth_strings = Arc::clone(&auth_strings);
+                                tokio::spawn(async move {
+                                    if let Some(short_auth_string) = sas::short_auth_string(&pc2).await {
+                                        auth_strings.lock().unwrap().insert(user_id, short_auth_string);
+                                    }
+                                });
+                            }
+                            // Only close on Failed or Closed states, not Disconnected
+                            // Disconnected is a temporary state during network transitions
+                            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                                auth_strings.lock().unwrap().remove(&user_id);
+                                // users that continue over the relay are still in the session
+                                if state == RTCPeerConnectionState::Closed || !relay_fallback {
+                                    roster.lock().unwrap().remove(&user_id);
+                                }
+                                tokio::spawn(async move {
+                                    pc2.close().await.unwrap();
+                                    dc2.close().await.unwrap();
 
-                    let answer = RTCSessionDescription::answer(sdp_answer.clone()).unwrap();
+                                    let mut data_channels = dcs.lock().unwrap();
+                                    let mut peer_connections = pcs.lock().unwrap();
 
-                    let pc = Arc::clone(&peer_connection);
-                    if peer_connection.signaling_state() == RTCSignalingState::HaveLocalOffer {
-                        pc.set_remote_description(answer).await.unwrap();
-
-                        info!("Remote description set");
-
-                        // Set up data channel handlers after setting remote description
-                        let dc = Arc::clone(&data_channel);
-                        let pc = Arc::clone(&peer_connection);
-                        let dcs = Arc::clone(&self.data_channels);
-                        let pcs = Arc::clone(&self.peer_connections);
-                        peer_connection.on_peer_connection_state_change(Box::new(move |state| {
-                            warn!("State changed => {:?}", state);
-
-                            let dc2 = Arc::clone(&dc);
-                            let pc2 = Arc::clone(&pc);
-                            let dcs = Arc::clone(&dcs);
-                            let pcs = Arc::clone(&pcs);
-                            match state {
-                                // Only close on Failed or Closed states, not Disconnected
-                                // Disconnected is a temporary state during network transitions
-                                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
-                                    tokio::spawn(async move {
-                                        pc2.close().await.unwrap();
-                                        dc2.close().await.unwrap();
+                                    // Collect keys to remove
+                                    let keys_to_remove: Vec<UserId> = data_channels.iter().filter(|(_, v)| v.ready_state() == RTCDataChannelState::Closed).map(|(k, _)| *k).collect();
 
-                                        let mut data_channels = dcs.lock().unwrap();
-                                        let mut peer_connections = pcs.lock().unwrap();
+                                    // Remove data channels
+                                    for k in keys_to_remove {
+                                        data_channels.remove(&k);
+                                        peer_connections.remove(&k);
+                                    }
+                                });
 
-                                        // Collect keys to remove
-                                        let keys_to_remove: Vec<UserId> = data_channels.iter().filter(|(_, v)| v.ready_state() == RTCDataChannelState::Closed).map(|(k, _)| k.clone()).collect();
+                                // Continue through the signaling server if peer-to-peer failed
+                                if state == RTCPeerConnectionState::Failed && relay_fallback {
+                                    warn!("Peer connection failed, relaying messages through the signaling server");
 
-                                        // Remove data channels
-                                        for k in keys_to_remove {
-                                            data_channels.remove(&k);
-                                            peer_connections.remove(&k);
+                                    let session_id = session_id.clone();
+                                    let rcs = Arc::clone(&rcs);
+                                    let hndl = hndl.clone();
+                                    let dc_handler = dc_handler.clone();
+                                    tokio::spawn(async move {
+                                        // the server only relays between users of the same session
+                                        if let Err(e) = hndl.send_acknowledged(SignalMessage::RelayOpen(session_id.clone(), user_id)).await {
+                                            warn!("Failed to open relay to user {:?}: {}", user_id, e);
+                                            return;
                                         }
+
+                                        let relay_channel = RelayChannel::new(session_id, user_id, hndl);
+                                        rcs.lock().unwrap().insert(user_id, relay_channel.clone());
+                                        dc_handler.handle_data_channel_open(DataChannel::Relay(relay_channel));
                                     });
                                 }
-                                _ => {}
                             }
+                            _ => {}
+                        }
 
-                            Box::pin(async move {})
-                        }));
+                        Box::pin(async move {})
+                    }));
 
-                        let dc_handler = self.data_channel_handler.clone();
-                        let dc = Arc::clone(&data_channel);
-                        data_channel.on_open(Box::new(move || {
-                            let dc2 = Arc::clone(&dc);
-                            dc_handler.handle_data_channel_open(dc2);
+                    let dc_handler = self.data_channel_handler.clone();
+                    let dc = Arc::clone(&data_channel);
+                    data_channel.on_open(Box::new(move || {
+                        let dc2 = Arc::clone(&dc);
+                        dc_handler.handle_data_channel_open(DataChannel::WebRTC(dc2));
 
-                            Box::pin(async move {})
-                        }));
+                        Box::pin(async move {})
+                    }));
 
-                        let dc_handler = self.data_channel_handler.clone();
-                        data_channel.on_message(Box::new(move |msg| {
-                            // Convert message to string
-                            let message = String::from_utf8(msg.data.to_vec()).unwrap();
-                            dc_handler.handle_data_channel_message(message);
+                    let dc_handler = self.data_channel_handler.clone();
+                    data_channel.on_message(Box::new(move |msg| {
+                        // Convert message to string
+                        let message = String::from_utf8(msg.data.to_vec()).unwrap();
+                        dc_handler.handle_data_channel_message(message);
 
-                            Box::pin(async move {})
-                        }));
+                        Box::pin(async move {})
+                    }));
+                }
+            }
+            // Users signaling over HTTP send their own offer and create the data channel
+            SignalMessage::SdpOffer(session_id, user_id, sdp_offer) => {
+                // the host offers to the clients that joined, and a user connecting over HTTP offers only once
+                if self.peer_connections.lock().unwrap().contains_key(&user_id) {
+                    warn!("Ignoring offer of user {:?}, it's already connected", user_id);
+                    if let Err(e) = self.handle.send(SignalMessage::Error(session_id, user_id, "Already connected".to_string())) {
+                        error!("Failed to reject offer: {:?}", e);
                     }
+                    return;
                 }
-                // SignalMessage::SdpOffer(session_id, user_id, sdp_offer) => {}
-                SignalMessage::IceCandidate(_session_id, user_id, ice_candidate) => {
-                    info!("Host received ICE candidate");
 
-                    let peer_connection = {
-                        let peer_connections = self.peer_connections.lock().unwrap();
-                        peer_connections.get(&user_id).unwrap().clone()
+                let config = ice::rtc_configuration(&self.signaling_url, &session_id, &self.ice_servers, self.ice_config.as_ref(), &mut self.turn_credentials).await;
+                let dcs = Arc::clone(&self.data_channels);
+                let peer_connection = client::create_peer_connection_with(config, self.data_channel_handler.clone(), move |data_channel| {
+                    dcs.lock().unwrap().insert(user_id, data_channel);
+                })
+                .await;
+
+                let pcs = Arc::clone(&self.peer_connections);
+                let dcs = Arc::clone(&self.data_channels);
+                let auth_strings = Arc::clone(&self.short_auth_strings);
+                let pc = Arc::downgrade(&peer_connection);
+                peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+                    warn!("State changed => {:?}", state);
+
+                    if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                        pcs.lock().unwrap().remove(&user_id);
+                        dcs.lock().unwrap().remove(&user_id);
+                        auth_strings.lock().unwrap().remove(&user_id);
+                    }
+
+                    let pc = pc.clone();
+                    let auth_strings = Arc::clone(&auth_strings);
+                    Box::pin(async move {
+                        if let (RTCPeerConnectionState::Connected, Some(pc)) = (state, pc.upgrade()) {
+                            if let Some(short_auth_string) = sas::short_auth_string(&pc).await {
+                                auth_strings.lock().unwrap().insert(user_id, short_auth_string);
+                            }
+                        }
+                    })
+                }));
+
+                self.peer_connections.lock().unwrap().insert(user_id, peer_connection.clone());
+
+                // Gathering candidates takes a while, don't block other messages
+                let hndl = self.handle.clone();
+                let version = self.handle.version();
+                tokio::spawn(async move {
+                    let answer = match answer_offer(&peer_connection, sdp_offer.into_sdp()).await {
+                        Ok(answer) => SignalMessage::SdpAnswer(session_id, user_id, SdpPayload::new(SdpType::Answer, answer, version)),
+                        Err(e) => {
+                            error!("Failed to answer offer: {:?}", e);
+                            hndl.send(SignalMessage::Error(session_id, user_id, e.to_string())).unwrap();
+                            return;
+                        }
                     };
 
-                    let candidate = serde_json::from_str::<IceCandidateJSON>(ice_candidate.as_str()).unwrap();
+                    if let Err(e) = hndl.send_with_retry(answer, SDP_ATTEMPTS).await {
+                        warn!("Answer to user {:?} wasn't delivered, aborting negotiation: {}", user_id, e);
+                        if let Err(e) = peer_connection.close().await {
+                            warn!("Failed to close peer connection: {:?}", e);
+                        }
+                    }
+                });
+            }
+            SignalMessage::IceCandidate(_session_id, user_id, ice_candidate) => {
+                info!("Host received ICE candidate");
+
+                let Some(peer_connection) = self.peer_connections.lock().unwrap().get(&user_id).cloned() else {
+                    warn!("Ignoring ICE candidate of user {:?} without a connection", user_id);
+                    return;
+                };
+
+                let candidate = match ice_candidate.candidate() {
+                    Ok(candidate) => candidate,
+                    Err(e) => {
+                        warn!("Invalid ICE candidate: {:?}", e);
+                        return;
+                    }
+                };
+
+                let candidate_init = RTCIceCandidateInit {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_mline_index: candidate.sdp_mline_index,
+                    username_fragment: candidate.username_fragment,
+                };
+
+                if let Err(e) = peer_connection.add_ice_candidate(candidate_init).await {
+                    warn!("Failed to add ICE candidate of user {:?}: {:?}", user_id, e);
+                    return;
+                }
 
-                    let candidate_init = RTCIceCandidateInit {
-                        candidate: candidate.candidate,
-                        sdp_mid: candidate.sdp_mid,
-                        sdp_mline_index: candidate.sdp_mline_index,
-                        username_fragment: candidate.username_fragment,
-                    };
+                info!("Host ICE candidate added successfully");
+            }
+            SignalMessage::KeepAlive(user_id, _status) => {
+                let dc_handler = self.data_channel_handler.clone();
 
-                    peer_connection.add_ice_candidate(candidate_init).await.unwrap();
+                dc_handler.handle_keep_alive(self, user_id);
+            }
+            SignalMessage::IceConfig(_session_id, ice_config) => {
+                info!("Received ICE configuration from server: {:?}", ice_config);
 
-                    info!("Host ICE candidate added successfully");
+                self.ice_config = Some(ice_config);
+            }
+            SignalMessage::RelayData(_session_id, user_id, message) => {
+                let open = self.relay_channels.lock().unwrap().get(&user_id).is_some_and(|relay_channel| relay_channel.ready_state() == RTCDataChannelState::Open);
+                if open {
+                    self.data_channel_handler.handle_data_channel_message(message);
+                } else {
+                    warn!("Relayed message from user without relay channel: {:?}", user_id);
+                    self.relay_channels.lock().unwrap().remove(&user_id);
+                }
+            }
+            SignalMessage::RelayClose(_session_id, user_id) => {
+                if let Some(relay_channel) = self.relay_channels.lock().unwrap().remove(&user_id) {
+                    info!("User {:?} closed the relay", user_id);
+                    relay_channel.closed_by_peer();
+                }
+            }
+            SignalMessage::SessionLeft(_session_id, user_id) => {
+                info!("User {:?} left the session", user_id);
+
+                self.data_channels.lock().unwrap().remove(&user_id);
+                self.short_auth_strings.lock().unwrap().remove(&user_id);
+                self.roster.lock().unwrap().remove(&user_id);
+                if let Some(relay_channel) = self.relay_channels.lock().unwrap().remove(&user_id) {
+                    relay_channel.closed_by_peer();
                 }
-                SignalMessage::KeepAlive(user_id, _status) => {
-                    let dc_handler = self.data_channel_handler.clone();
 
-                    dc_handler.handle_keep_alive(self, user_id);
+                let peer_connection = self.peer_connections.lock().unwrap().remove(&user_id);
+                if let Some(peer_connection) = peer_connection {
+                    if let Err(e) = peer_connection.close().await {
+                        warn!("Failed to close peer connection: {:?}", e);
+                    }
                 }
-                _ => {}
-            },
-            Err(error) => {
-                error!("Error parsing message from server: {:?}", error);
             }
+            SignalMessage::Error(_session_id, _user_id, error) => {
+                error!("Error from signaling server: {}", error);
+            }
+            SignalMessage::Notify(_session_id, payload) => {
+                self.data_channel_handler.handle_notification(payload);
+            }
+            SignalMessage::Welcome(user_id, server) => {
+                self.handle.set_user_id(user_id);
+                welcome_received(&self.options, &server);
+                self.handle.set_version(server.version);
+                self.handle.set_acknowledgements(server.supports(Capability::Acknowledgements));
+                self.server = Some(server);
+            }
+            _ => {}
         }
+    }
+
+    fn join_message(&self) -> Option<SignalMessage> {
+        let session_id = self.session_id.lock().unwrap().clone()?;
+        let claim_token = self.claim_token.lock().unwrap().clone();
+
+        Some(host_join_message(session_id, claim_token, self.options.metadata.clone()))
+    }
+
+    fn options(&self) -> &ConnectionOptions {
+        &self.options
+    }
+
+    fn handle(&self) -> &SignalingHandle {
+        &self.handle
+    }
+}
+
+/// Join of a host, reserved sessions are joined with their claim token
+pub(crate) fn host_join_message(session_id: SessionId, claim_token: Option<String>, metadata: Option<serde_json::Value>) -> SignalMessage {
+    match claim_token {
+        Some(claim_token) => SignalMessage::SessionClaim(session_id, claim_token, metadata),
+        None => SignalMessage::SessionJoin(session_id, true, metadata),
+    }
+}
+
+/// Messages of a session the peer left can still arrive after it joined another one
+fn is_stale(message: &mut SignalMessage, current: &Mutex<Option<SessionId>>) -> bool {
+    match message.session_id_mut() {
+        // errors that don't belong to a session have an empty id
+        Some(session_id) if !session_id.as_str().is_empty() => current.lock().unwrap().as_ref() != Some(session_id),
+        _ => false,
+    }
+}
+
+/// Servers that don't answer the hello predate capabilities and are assumed to support everything
+fn server_supports(server: Option<&Handshake>, capability: Capability) -> bool {
+    server.is_none_or(|server| server.supports(capability))
+}
 
+fn welcome_received(options: &ConnectionOptions, server: &Handshake) {
+    info!("Using protocol version {} with capabilities {:?}", server.version, server.capabilities);
+
+    if options.relay_fallback && !server.supports(Capability::Relay) {
+        warn!("Relay fallback is enabled, but the signaling server doesn't relay messages");
+    }
+}
+
+/// The server closes connections with an unsupported protocol version, reconnecting wouldn't help
+fn close_mode(frame: Option<&CloseFrame>) -> ClientCloseMode {
+    match frame {
+        Some(frame) if frame.reason.as_str() == UNSUPPORTED_VERSION => {
+            error!("The signaling server doesn't support protocol version {}", PROTOCOL_VERSION);
+            ClientCloseMode::Close
+        }
+        _ => ClientCloseMode::Reconnect,
+    }
+}
+
+/// Answer an offer with every local candidate included, the other user can't receive trickled candidates
+async fn answer_offer(peer_connection: &RTCPeerConnection, sdp_offer: String) -> Result<String, webrtc::Error> {
+    peer_connection.set_remote_description(RTCSessionDescription::offer(sdp_offer)?).await?;
+
+    let answer = peer_connection.create_answer(None).await?;
+    let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+    peer_connection.set_local_description(answer).await?;
+    let _ = gathering_complete.recv().await;
+
+    match peer_connection.local_description().await {
+        Some(description) => Ok(description.sdp),
+        None => Err(webrtc::Error::new("local description missing after ICE gathering".to_string())),
+    }
+}
+
+#[async_trait]
+impl ezsockets::ClientExt for WSHost {
+    type Call = WSCall;
+
+    async fn on_text(&mut self, text: Utf8Bytes) -> Result<(), Error> {
+        self.handle_text(&text).await;
         Ok(())
     }
 
     async fn on_binary(&mut self, bytes: Bytes) -> Result<(), Error> {
-        info!("received bytes: {bytes:?}");
+        self.handle_binary(&bytes).await;
         Ok(())
     }
 
     async fn on_connect(&mut self) -> Result<(), Error> {
         info!("Connected to server");
-        let join_message = SignalMessage::SessionJoin(self.session_id.clone(), true);
-
-        self.handle.text(serde_json::to_string(&join_message).unwrap()).unwrap();
+        // the websocket works, never fall back to long polling
+        self.fallback = None;
+        self.server = None;
+        self.handle.set_version(1);
+        self.handle.set_encoding(self.options.encoding);
+        self.handle.cancel_requests();
+
+        self.handle.send(self.hello_message()).unwrap();
+        if let Some(join) = self.join_message() {
+            self.handle.send(join).unwrap();
+        }
         Ok(())
     }
 
@@ -257,12 +570,18 @@ impl ezsockets::ClientExt for WSHost {
 
     async fn on_connect_fail(&mut self, error: WSError) -> Result<ClientCloseMode, Error> {
         error!("Connection failed: {:?}", error);
+
+        if let Some(fallback) = self.fallback.take() {
+            let _ = fallback.send(());
+            return Ok(ClientCloseMode::Close);
+        }
+
         Ok(ClientCloseMode::Reconnect)
     }
 
     async fn on_close(&mut self, frame: Option<CloseFrame>) -> Result<ClientCloseMode, Error> {
         error!("Connection closed: {:?}", frame);
-        Ok(ClientCloseMode::Reconnect)
+        Ok(close_mode(frame.as_ref()))
     }
 
     async fn on_disconnect(&mut self) -> Result<ClientCloseMode, Error> {
@@ -272,113 +591,252 @@ impl ezsockets::ClientExt for WSHost {
 }
 
 #[async_trait]
-impl ezsockets::ClientExt for WSClient {
-    type Call = WSCall;
+impl SignalingPeer for WSClient {
+    async fn handle_message(&mut self, mut request: SignalMessage) {
+        info!("Message received from signaling server: {:?}", request);
 
-    async fn on_text(&mut self, text: Utf8Bytes) -> Result<(), Error> {
-        info!("Message received from signaling server: {:?}", text);
+        if is_stale(&mut request, &self.session_id) {
+            warn!("Ignoring message of a session the client left");
+            return;
+        }
 
-        match serde_json::from_str::<SignalMessage>(&text) {
-            Ok(request) => match request {
-                SignalMessage::SdpOffer(session_id, user_id, sdp_offer) => {
-                    let peer_connection = self.peer_connection.lock().unwrap().clone();
+        match request {
+            SignalMessage::SdpOffer(session_id, user_id, sdp_offer) => {
+                let peer_connection = self.peer_connection.lock().unwrap().clone();
 
-                    let offer = RTCSessionDescription::offer(sdp_offer).unwrap();
+                let offer = RTCSessionDescription::offer(sdp_offer.into_sdp()).unwrap();
 
-                    // Set up ICE candidate handler before setting remote description
-                    let hndl = self.handle.clone();
-                    let session_id_clone = session_id.clone();
-
-                    peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
-                        let hndl2 = hndl.clone();
-                        let session_id2 = session_id_clone.clone();
-
-                        Box::pin(async move {
-                            if let Some(c) = candidate {
-                                info!("Client sending ICE candidate: {:?}", c);
-
-                                match c.to_json() {
-                                    Ok(ice_candidate_init) => {
-                                        let ice_json = IceCandidateJSON {
-                                            candidate: ice_candidate_init.candidate,
-                                            sdp_mid: ice_candidate_init.sdp_mid,
-                                            sdp_mline_index: ice_candidate_init.sdp_mline_index,
-                                            username_fragment: ice_candidate_init.username_fragment,
-                                        };
-
-                                        let ice_candidate_str = serde_json::to_string(&ice_json).unwrap();
-
-                                        hndl2
-                                            .text(serde_json::to_string(&SignalMessage::IceCandidate(session_id2, user_id, ice_candidate_str)).unwrap())
-                                            .unwrap();
-                                    }
-                                    Err(e) => {
-                                        warn!("Failed to convert ICE candidate to JSON: {:?}", e);
-                                    }
-                                }
-                            } else {
-                                info!("Client ICE gathering complete (null candidate)");
-                            }
-                        })
-                    }));
+                // Set up ICE candidate handler before setting remote description
+                let hndl = self.handle.clone();
+                let session_id_clone = session_id.clone();
+                let version = self.handle.version();
 
-                    peer_connection.set_remote_description(offer).await.unwrap();
+                peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+                    let hndl2 = hndl.clone();
+                    let session_id2 = session_id_clone.clone();
 
-                    let answer = peer_connection.create_answer(None).await.unwrap();
+                    Box::pin(async move {
+                        if let Some(c) = candidate {
+                            info!("Client sending ICE candidate: {:?}", c);
 
-                    peer_connection.set_local_description(answer.clone()).await.unwrap();
+                            match c.to_json() {
+                                Ok(ice_candidate_init) => {
+                                    let ice_json = IceCandidateJSON {
+                                        candidate: ice_candidate_init.candidate,
+                                        sdp_mid: ice_candidate_init.sdp_mid,
+                                        sdp_mline_index: ice_candidate_init.sdp_mline_index,
+                                        username_fragment: ice_candidate_init.username_fragment,
+                                    };
 
-                    info!("Remote description set and answer created");
+                                    let payload = IcePayload::new(ice_json, version);
 
-                    // Send the answer
-                    self.handle.text(serde_json::to_string(&SignalMessage::SdpAnswer(session_id, user_id, answer.sdp)).unwrap()).unwrap();
+                                    hndl2.send(SignalMessage::IceCandidate(session_id2, user_id, payload)).unwrap();
+                                }
+                                Err(e) => {
+                                    warn!("Failed to convert ICE candidate to JSON: {:?}", e);
+                                }
+                            }
+                        } else {
+                            info!("Client ICE gathering complete (null candidate)");
+                        }
+                    })
+                }));
+
+                // The fingerprints of both sides are known once connected
+                let auth_string = Arc::clone(&self.short_auth_string);
+                let pc = Arc::downgrade(&peer_connection);
+                peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+                    if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                        *auth_string.lock().unwrap() = None;
+                    }
 
-                    info!("Answer sent");
+                    let pc = pc.clone();
+                    let auth_string = Arc::clone(&auth_string);
+                    Box::pin(async move {
+                        if let (RTCPeerConnectionState::Connected, Some(pc)) = (state, pc.upgrade()) {
+                            let short_auth_string = sas::short_auth_string(&pc).await;
+                            *auth_string.lock().unwrap() = short_auth_string;
+                        }
+                    })
+                }));
+
+                peer_connection.set_remote_description(offer).await.unwrap();
+
+                let answer = peer_connection.create_answer(None).await.unwrap();
+
+                peer_connection.set_local_description(answer.clone()).await.unwrap();
+
+                info!("Remote description set and answer created");
+
+                // Send the answer without blocking the message that acknowledges it
+                let answer = SignalMessage::SdpAnswer(session_id, user_id, SdpPayload::new(SdpType::Answer, answer.sdp, version));
+                let hndl = self.handle.clone();
+                tokio::spawn(async move {
+                    match hndl.send_with_retry(answer, SDP_ATTEMPTS).await {
+                        Ok(()) => info!("Answer sent"),
+                        Err(e) => {
+                            warn!("Answer wasn't delivered to the host, aborting negotiation: {}", e);
+                            if let Err(e) = peer_connection.close().await {
+                                warn!("Failed to close peer connection: {:?}", e);
+                            }
+                        }
+                    }
+                });
+            }
+            SignalMessage::IceCandidate(_session_id, _user_id, ice_candidate) => {
+                info!("Client received ICE candidate");
+
+                let peer_connection = self.peer_connection.lock().unwrap().clone();
+
+                let candidate = match ice_candidate.candidate() {
+                    Ok(candidate) => candidate,
+                    Err(e) => {
+                        warn!("Invalid ICE candidate: {:?}", e);
+                        return;
+                    }
+                };
+
+                let candidate_init = RTCIceCandidateInit {
+                    candidate: candidate.candidate,
+                    sdp_mid: candidate.sdp_mid,
+                    sdp_mline_index: candidate.sdp_mline_index,
+                    username_fragment: candidate.username_fragment,
+                };
+
+                if let Err(e) = peer_connection.add_ice_candidate(candidate_init).await {
+                    warn!("Failed to add ICE candidate of the host: {:?}", e);
+                    return;
                 }
-                SignalMessage::IceCandidate(_session_id, _user_id, ice_candidate) => {
-                    info!("Client received ICE candidate");
 
-                    let peer_connection = self.peer_connection.lock().unwrap().clone();
+                info!("Client ICE candidate added successfully");
+            }
+            SignalMessage::IceConfig(session_id, ice_config) => {
+                info!("Received ICE configuration from server: {:?}", ice_config);
 
-                    let candidate = serde_json::from_str::<IceCandidateJSON>(ice_candidate.as_str()).unwrap();
+                *self.ice_config.lock().unwrap() = Some(ice_config.clone());
 
-                    let candidate_init = RTCIceCandidateInit {
-                        candidate: candidate.candidate,
-                        sdp_mid: candidate.sdp_mid,
-                        sdp_mline_index: candidate.sdp_mline_index,
-                        username_fragment: candidate.username_fragment,
-                    };
+                // Recreate the peer connection with the new configuration if negotiation hasn't started yet
+                let old_peer_connection = self.peer_connection.lock().unwrap().clone();
+                if old_peer_connection.remote_description().await.is_none() {
+                    let config = ice::rtc_configuration(&self.signaling_url, &session_id, &self.ice_servers, Some(&ice_config), &mut self.turn_credentials).await;
+                    let peer_connection = client::create_peer_connection(config, self.data_channel_handler.clone()).await;
 
-                    peer_connection.add_ice_candidate(candidate_init).await.unwrap();
+                    *self.peer_connection.lock().unwrap() = peer_connection;
+
+                    if let Err(e) = old_peer_connection.close().await {
+                        warn!("Failed to close old peer connection: {:?}", e);
+                    }
+                }
+            }
+            SignalMessage::SessionReady(_session_id, _host_id, metadata) => {
+                info!("Host joined with metadata {:?}", metadata);
+                *self.host_metadata.lock().unwrap() = metadata;
+            }
+            SignalMessage::RelayOpen(session_id, host_id) => {
+                if self.options.relay_fallback {
+                    warn!("Peer connection failed, relaying messages through the signaling server");
+
+                    let relay_channel = RelayChannel::new(session_id, host_id, self.handle.clone());
+                    *self.relay_channel.lock().unwrap() = Some(relay_channel.clone());
+                    self.data_channel_handler.handle_data_channel_open(DataChannel::Relay(relay_channel));
+                } else {
+                    warn!("Host wants to relay messages, but relay fallback is disabled");
+                }
+            }
+            SignalMessage::RelayData(_session_id, _user_id, message) if self.relay_channel.lock().unwrap().as_ref().is_some_and(|relay_channel| relay_channel.ready_state() == RTCDataChannelState::Open) => {
+                self.data_channel_handler.handle_data_channel_message(message);
+            }
+            SignalMessage::RelayClose(_session_id, host_id) => {
+                if let Some(relay_channel) = self.relay_channel.lock().unwrap().take_if(|relay_channel| relay_channel.user_id() == host_id) {
+                    info!("Host closed the relay");
+                    relay_channel.closed_by_peer();
+                }
+            }
+            SignalMessage::SessionLeft(session_id, _host_id) => {
+                info!("Host left the session");
 
-                    info!("Client ICE candidate added successfully");
+                if let Some(relay_channel) = self.relay_channel.lock().unwrap().take() {
+                    relay_channel.closed_by_peer();
                 }
-                // SignalMessage::Ping(_is_host, user_id) => {
-                //     let ping_message = SignalMessage::Ping(true, user_id);
-                //     self.handle.text(serde_json::to_string(&ping_message).unwrap()).unwrap();
-
-                //     info!("Sending pong to server");
-                // }
-                _ => {}
-            },
-            Err(error) => {
-                error!("Error parsing message from server: {:?}", error);
+                *self.short_auth_string.lock().unwrap() = None;
+                *self.host_metadata.lock().unwrap() = None;
+
+                // the closed peer connection can't be used again, the next host offers on a new one
+                let old_peer_connection = self.peer_connection.lock().unwrap().clone();
+                let ice_config = self.ice_config.lock().unwrap().clone();
+                let config = ice::rtc_configuration(&self.signaling_url, &session_id, &self.ice_servers, ice_config.as_ref(), &mut self.turn_credentials).await;
+                let peer_connection = client::create_peer_connection(config, self.data_channel_handler.clone()).await;
+                *self.peer_connection.lock().unwrap() = peer_connection;
+
+                if let Err(e) = old_peer_connection.close().await {
+                    warn!("Failed to close peer connection: {:?}", e);
+                }
+            }
+            SignalMessage::Error(_session_id, _user_id, error) => {
+                error!("Error from signaling server: {}", error);
+            }
+            SignalMessage::Notify(_session_id, payload) => {
+                self.data_channel_handler.handle_notification(payload);
             }
+            SignalMessage::Welcome(user_id, server) => {
+                self.handle.set_user_id(user_id);
+                welcome_received(&self.options, &server);
+                self.handle.set_version(server.version);
+                self.handle.set_acknowledgements(server.supports(Capability::Acknowledgements));
+                self.server = Some(server);
+            }
+            // SignalMessage::Ping(_is_host, user_id) => {
+            //     let ping_message = SignalMessage::Ping(true, user_id);
+            //     self.handle.text(serde_json::to_string(&ping_message).unwrap()).unwrap();
+
+            //     info!("Sending pong to server");
+            // }
+            _ => {}
         }
+    }
+
+    fn join_message(&self) -> Option<SignalMessage> {
+        let session_id = self.session_id.lock().unwrap().clone()?;
+
+        Some(SignalMessage::SessionJoin(session_id, false, self.options.metadata.clone()))
+    }
+
+    fn options(&self) -> &ConnectionOptions {
+        &self.options
+    }
+
+    fn handle(&self) -> &SignalingHandle {
+        &self.handle
+    }
+}
+
+#[async_trait]
+impl ezsockets::ClientExt for WSClient {
+    type Call = WSCall;
 
+    async fn on_text(&mut self, text: Utf8Bytes) -> Result<(), Error> {
+        self.handle_text(&text).await;
         Ok(())
     }
 
     async fn on_binary(&mut self, bytes: Bytes) -> Result<(), Error> {
-        info!("received bytes: {bytes:?}");
+        self.handle_binary(&bytes).await;
         Ok(())
     }
 
     async fn on_connect(&mut self) -> Result<(), Error> {
         info!("Connected to server");
-        let join_message = SignalMessage::SessionJoin(self.session_id.clone(), false);
-
-        self.handle.text(serde_json::to_string(&join_message).unwrap()).unwrap();
+        // the websocket works, never fall back to long polling
+        self.fallback = None;
+        self.server = None;
+        self.handle.set_version(1);
+        self.handle.set_encoding(self.options.encoding);
+        self.handle.cancel_requests();
+
+        self.handle.send(self.hello_message()).unwrap();
+        if let Some(join) = self.join_message() {
+            self.handle.send(join).unwrap();
+        }
         Ok(())
     }
 
@@ -388,12 +846,18 @@ impl ezsockets::ClientExt for WSClient {
 
     async fn on_connect_fail(&mut self, error: WSError) -> Result<ClientCloseMode, Error> {
         error!("Connection failed: {:?}", error);
+
+        if let Some(fallback) = self.fallback.take() {
+            let _ = fallback.send(());
+            return Ok(ClientCloseMode::Close);
+        }
+
         Ok(ClientCloseMode::Reconnect)
     }
 
     async fn on_close(&mut self, frame: Option<CloseFrame>) -> Result<ClientCloseMode, Error> {
         error!("Connection closed: {:?}", frame);
-        Ok(ClientCloseMode::Reconnect)
+        Ok(close_mode(frame.as_ref()))
     }
 
     async fn on_disconnect(&mut self) -> Result<ClientCloseMode, Error> {
@@ -0,0 +1,466 @@
+use crate::e2e::{self, SealError, SessionCipher};
+use crate::options::ConnectionOptions;
+use crate::protocol::{Capability, Encoding, Handshake, PollSession, SignalMessage, UserId};
+use crate::socket::WSCall;
+use async_trait::async_trait;
+use ezsockets::{ClientConfig, SocketConfig};
+use log::{error, info, warn};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use url::Url;
+
+/// Message queued for the signaling server
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// How long [`SignalingHandle::send_acknowledged`] waits for the server to answer a request
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reason a message sent with [`SignalingHandle::send_acknowledged`] wasn't delivered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// The server rejected the message, e.g. because the recipient isn't connected anymore
+    Rejected(String),
+    /// The server didn't answer within [`ACK_TIMEOUT`], the message may have been delivered
+    Timeout,
+    /// The connection to the server was closed before it answered
+    Closed,
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Rejected(reason) => write!(f, "rejected by the signaling server: {reason}"),
+            DeliveryError::Timeout => write!(f, "the signaling server didn't answer"),
+            DeliveryError::Closed => write!(f, "the signaling connection was closed"),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+type PendingRequests = Arc<Mutex<HashMap<String, oneshot::Sender<Result<(), String>>>>>;
+
+/// Sends messages to the signaling server over whichever transport is connected.
+#[derive(Clone, Debug)]
+pub struct SignalingHandle {
+    tx: mpsc::UnboundedSender<Frame>,
+    version: Arc<AtomicU32>,
+    encoding: Arc<Mutex<Encoding>>,
+    acknowledgements: Arc<AtomicBool>,
+    next_request_id: Arc<AtomicU64>,
+    pending: PendingRequests,
+    cipher: Arc<Mutex<Option<Arc<SessionCipher>>>>,
+    user_id: Arc<Mutex<Option<UserId>>>,
+}
+
+impl SignalingHandle {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Frame>) {
+        Self::with_cipher(None)
+    }
+
+    /// Handle that seals the SDP and ICE payloads it sends with the cipher and only accepts sealed payloads
+    pub fn with_cipher(cipher: Option<SessionCipher>) -> (Self, mpsc::UnboundedReceiver<Frame>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let version = Arc::new(AtomicU32::new(1));
+        (
+            Self {
+                tx,
+                version,
+                encoding: Arc::default(),
+                acknowledgements: Arc::default(),
+                next_request_id: Arc::default(),
+                pending: Arc::default(),
+                cipher: Arc::new(Mutex::new(cipher.map(Arc::new))),
+                user_id: Arc::default(),
+            },
+            rx,
+        )
+    }
+
+    pub fn text(&self, text: impl Into<String>) -> Result<(), mpsc::error::SendError<Frame>> {
+        self.tx.send(Frame::Text(text.into()))
+    }
+
+    /// Send the message in the encoding of the connection and the wire format of the protocol version negotiated with the server
+    pub fn send(&self, message: SignalMessage) -> Result<(), mpsc::error::SendError<Frame>> {
+        let encoding = self.encoding();
+        let message = match (self.cipher(), self.user_id()) {
+            (Some(cipher), Some(user_id)) => cipher.seal(message, user_id),
+            (Some(_), None) if e2e::has_payload(&message) => {
+                error!("Not sending the message: {}", SealError::UnknownUser);
+                return Ok(());
+            }
+            _ => message,
+        };
+
+        if encoding.is_binary() {
+            let bytes = message.encode_binary(encoding, self.version()).expect("signal messages can always be encoded");
+            self.tx.send(Frame::Binary(bytes))
+        } else {
+            self.text(message.encode(self.version()).expect("signal messages are always valid JSON"))
+        }
+    }
+
+    /// Encoding used on the current connection, always JSON over long polling
+    pub fn encoding(&self) -> Encoding {
+        *self.encoding.lock().unwrap()
+    }
+
+    pub fn set_encoding(&self, encoding: Encoding) {
+        *self.encoding.lock().unwrap() = encoding;
+    }
+
+    /// Id the server assigned to the user on the current connection, `None` until it answers the hello
+    pub fn user_id(&self) -> Option<UserId> {
+        *self.user_id.lock().unwrap()
+    }
+
+    pub fn set_user_id(&self, user_id: UserId) {
+        *self.user_id.lock().unwrap() = Some(user_id);
+    }
+
+    /// Protocol version negotiated with the server, 1 until it answers the hello
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    pub fn set_version(&self, version: u32) {
+        self.version.store(version, Ordering::Relaxed);
+    }
+
+    /// `true` if the server answers requests, see [`SignalingHandle::send_acknowledged`]
+    pub fn acknowledgements(&self) -> bool {
+        self.acknowledgements.load(Ordering::Relaxed)
+    }
+
+    pub fn set_acknowledgements(&self, acknowledgements: bool) {
+        self.acknowledgements.store(acknowledgements, Ordering::Relaxed);
+    }
+
+    /// Send the message and wait until the server passed it on.
+    /// Returns `Ok` right after sending if the server doesn't support acknowledgements.
+    pub async fn send_acknowledged(&self, message: SignalMessage) -> Result<(), DeliveryError> {
+        self.send_with_retry(message, 1).await
+    }
+
+    /// [`SignalingHandle::send_acknowledged`], sending the message again while the server doesn't answer, at most `attempts` times.
+    /// Every attempt uses the same request id, so an answer to an earlier attempt that arrives late still counts.
+    pub async fn send_with_retry(&self, message: SignalMessage, attempts: u32) -> Result<(), DeliveryError> {
+        if !self.acknowledgements() {
+            return self.send(message).map_err(|_| DeliveryError::Closed);
+        }
+
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (tx, mut rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id.clone(), tx);
+
+        let mut result = Err(DeliveryError::Timeout);
+        for attempt in 1..=attempts {
+            if self.send(SignalMessage::Request(request_id.clone(), Box::new(message.clone()))).is_err() {
+                result = Err(DeliveryError::Closed);
+                break;
+            }
+
+            result = match tokio::time::timeout(ACK_TIMEOUT, &mut rx).await {
+                Ok(Ok(Ok(()))) => Ok(()),
+                Ok(Ok(Err(reason))) => Err(DeliveryError::Rejected(reason)),
+                Ok(Err(_)) => Err(DeliveryError::Closed),
+                Err(_) => Err(DeliveryError::Timeout),
+            };
+            if result != Err(DeliveryError::Timeout) {
+                break;
+            }
+
+            warn!("Signaling server didn't acknowledge message, attempt {} of {}", attempt, attempts);
+        }
+
+        if result.is_err() {
+            self.pending.lock().unwrap().remove(&request_id);
+        }
+
+        result
+    }
+
+    /// Resolve the request answered by an [`SignalMessage::Ack`] or [`SignalMessage::Nack`]
+    pub(crate) fn acknowledge(&self, request_id: &str, result: Result<(), String>) {
+        match self.pending.lock().unwrap().remove(request_id) {
+            Some(tx) => {
+                let _ = tx.send(result);
+            }
+            None => warn!("Acknowledgement for unknown request {:?}", request_id),
+        }
+    }
+
+    /// Open the sealed payloads of a message from the other peer, see [`SessionCipher::open`]
+    pub fn open(&self, message: SignalMessage) -> Result<SignalMessage, SealError> {
+        match (self.cipher(), self.user_id()) {
+            (Some(cipher), Some(user_id)) => cipher.open(message, user_id),
+            (Some(_), None) if e2e::has_payload(&message) => Err(SealError::UnknownUser),
+            (Some(_), None) => Ok(message),
+            (None, _) if e2e::is_sealed(&message) => Err(SealError::MissingSecret),
+            (None, _) => Ok(message),
+        }
+    }
+
+    fn cipher(&self) -> Option<Arc<SessionCipher>> {
+        self.cipher.lock().unwrap().clone()
+    }
+
+    /// Replace the cipher after switching sessions, the key depends on the session id
+    pub(crate) fn set_cipher(&self, cipher: Option<SessionCipher>) {
+        *self.cipher.lock().unwrap() = cipher.map(Arc::new);
+    }
+
+    /// Fail the requests of a previous connection, the server won't answer them anymore, and forget its user id
+    pub(crate) fn cancel_requests(&self) {
+        self.set_acknowledgements(false);
+        *self.user_id.lock().unwrap() = None;
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+/// Message handling shared by the websocket and the long polling transport
+#[async_trait]
+pub trait SignalingPeer: ezsockets::ClientExt<Call = WSCall> {
+    async fn handle_message(&mut self, message: SignalMessage);
+
+    /// Message sent to the server after connecting, `None` after leaving the session
+    fn join_message(&self) -> Option<SignalMessage>;
+
+    fn options(&self) -> &ConnectionOptions;
+
+    fn handle(&self) -> &SignalingHandle;
+
+    /// Handle a text message in either JSON wire format
+    async fn handle_text(&mut self, text: &str) {
+        match SignalMessage::decode(text) {
+            Ok(message) => self.dispatch(message).await,
+            Err(error) => error!("Error parsing message from server: {:?}", error),
+        }
+    }
+
+    /// Handle a binary message in the encoding selected for the websocket
+    async fn handle_binary(&mut self, bytes: &[u8]) {
+        match SignalMessage::decode_binary(bytes, self.handle().encoding()) {
+            Ok(message) => self.dispatch(message).await,
+            Err(error) => error!("Error parsing binary message from server: {:?}", error),
+        }
+    }
+
+    /// Resolve acknowledgements of requests sent with the handle, pass everything else to [`SignalingPeer::handle_message`]
+    /// once its payloads are opened
+    async fn dispatch(&mut self, message: SignalMessage) {
+        let message = match self.handle().open(message) {
+            Ok(message) => message,
+            Err(error) => {
+                error!("Rejected message from the other peer: {}", error);
+                return;
+            }
+        };
+
+        match message {
+            SignalMessage::Ack(request_id) => self.handle().acknowledge(&request_id, Ok(())),
+            SignalMessage::Nack(request_id, reason) => {
+                warn!("Signaling server rejected request {:?}: {}", request_id, reason);
+                self.handle().acknowledge(&request_id, Err(reason));
+            }
+            message => self.handle_message(message).await,
+        }
+    }
+
+    /// Protocol versions and capabilities sent to the server before [`SignalingPeer::join_message`]
+    fn hello_message(&self) -> SignalMessage {
+        let mut capabilities = Vec::new();
+        if self.options().relay_fallback {
+            capabilities.push(Capability::Relay);
+        }
+        if self.options().encoding.is_binary() {
+            capabilities.push(Capability::BinaryEncoding);
+        }
+
+        SignalMessage::Hello(Handshake::new(capabilities))
+    }
+}
+
+/// Connect to the signaling server over websocket, or over long polling if the websocket can't connect.
+/// `create` is called with a sender the peer uses to report that the websocket failed.
+/// Binary encodings are requested with the `Sec-WebSocket-Protocol` header.
+pub async fn connect<E, F>(signaling_url: Url, mut rx: mpsc::UnboundedReceiver<Frame>, create: F, long_poll_fallback: bool, encoding: Encoding)
+where
+    E: SignalingPeer + 'static,
+    F: Fn(Option<oneshot::Sender<()>>) -> E + Send + Sync + 'static,
+{
+    let (fallback_tx, mut fallback_rx) = oneshot::channel();
+    let fallback_tx = if long_poll_fallback { Some(fallback_tx) } else { None };
+
+    let mut config = ClientConfig::new(signaling_url.clone());
+    if encoding.is_binary() {
+        config = config.header("Sec-WebSocket-Protocol", encoding.subprotocol());
+    }
+
+    let config = config.socket_config(SocketConfig {
+        heartbeat: Duration::from_secs(60),
+        timeout: Duration::from_secs(90),
+        ..SocketConfig::default()
+    });
+
+    let create = Arc::new(create);
+    let ws_create = Arc::clone(&create);
+    let (ws, future) = ezsockets::connect(move |_handle| ws_create(fallback_tx), config).await;
+
+    tokio::spawn(async move {
+        info!("Connected to signaling server");
+        if let Err(e) = future.await {
+            error!("Signaling connection stopped: {:?}", e);
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut waiting_for_fallback = long_poll_fallback;
+
+        // Forward messages to the websocket until it fails to connect
+        loop {
+            tokio::select! {
+                frame = rx.recv() => {
+                    let result = match frame {
+                        Some(Frame::Text(text)) => ws.text(text),
+                        Some(Frame::Binary(bytes)) => ws.binary(bytes),
+                        None => return,
+                    };
+
+                    if let Err(e) = result {
+                        error!("Failed to send message to signaling server: {:?}", e);
+                    }
+                },
+                result = &mut fallback_rx, if waiting_for_fallback => {
+                    waiting_for_fallback = false;
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        warn!("Websocket connection failed, falling back to long polling");
+        long_poll(signaling_url, create(None), rx).await;
+    });
+}
+
+/// Url of an HTTP endpoint next to the signaling websocket, keeping the `app_key` of the signaling url
+pub fn http_url(signaling_url: &Url, path: &str) -> Url {
+    let mut url = signaling_url.clone();
+    let scheme = if url.scheme() == "wss" { "https" } else { "http" };
+    url.set_scheme(scheme).expect("failed to change signaling url scheme");
+    url.set_query(None);
+
+    let mut url = url.join(path).expect("failed to create signaling server url");
+    if let Some((_, app_key)) = signaling_url.query_pairs().find(|(key, _)| key == "app_key") {
+        url.query_pairs_mut().append_pair("app_key", &app_key);
+    }
+
+    url
+}
+
+/// Url of the long polling endpoint next to the signaling websocket, `token` selects an existing connection
+pub fn poll_url(signaling_url: &Url, token: Option<&str>) -> Url {
+    match token {
+        Some(token) => http_url(signaling_url, &format!("poll/{token}")),
+        None => http_url(signaling_url, "poll"),
+    }
+}
+
+/// Exchange messages with the server over HTTP long polling, reconnecting if the server forgets the connection
+async fn long_poll<E: SignalingPeer>(signaling_url: Url, mut peer: E, rx: mpsc::UnboundedReceiver<Frame>) {
+    peer.handle().set_encoding(Encoding::Json);
+
+    let client = reqwest::Client::new();
+    let connect_url = poll_url(&signaling_url, None);
+    let (messages_tx, mut messages_rx) = mpsc::unbounded_channel::<Url>();
+
+    // Send outgoing messages to the current connection
+    let sender = client.clone();
+    tokio::spawn(async move {
+        let mut rx = rx;
+        let mut messages_url = messages_rx.recv().await;
+
+        while let Some(frame) = rx.recv().await {
+            while let Ok(url) = messages_rx.try_recv() {
+                messages_url = Some(url);
+            }
+
+            let Frame::Text(text) = frame else {
+                warn!("Dropping binary message, long polling only supports JSON");
+                continue;
+            };
+
+            let Some(url) = &messages_url else { continue };
+            if let Err(e) = sender.post(url.clone()).body(text).send().await.and_then(|response| response.error_for_status()) {
+                error!("Failed to send message over long polling: {:?}", e);
+            }
+        }
+    });
+
+    loop {
+        let session = match client.post(connect_url.clone()).send().await.and_then(|response| response.error_for_status()) {
+            Ok(response) => response.json::<PollSession>().await,
+            Err(e) => Err(e),
+        };
+
+        let session = match session {
+            Ok(session) => session,
+            Err(e) => {
+                error!("Failed to connect over long polling: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        info!("Connected to signaling server over long polling as {:?}", session.user_id);
+        // a new connection negotiates again, like the websocket in `on_connect`
+        peer.handle().set_version(1);
+        peer.handle().cancel_requests();
+        peer.handle().set_user_id(session.user_id);
+        let messages_url = poll_url(&signaling_url, Some(&session.token));
+        let _ = messages_tx.send(messages_url.clone());
+
+        for message in [Some(peer.hello_message()), peer.join_message()].into_iter().flatten() {
+            if peer.handle().send(message).is_err() {
+                error!("Failed to join session over long polling, the handle is closed");
+            }
+        }
+
+        // Receive messages until the server forgets the connection
+        loop {
+            let response = client.get(messages_url.clone()).query(&[("timeout", "30")]).timeout(Duration::from_secs(40)).send().await;
+
+            match response {
+                Ok(response) if response.status() == StatusCode::NOT_FOUND || response.status() == StatusCode::GONE => {
+                    warn!("Long polling connection closed by server");
+                    break;
+                }
+                Ok(response) => match response.json::<Vec<serde_json::Value>>().await {
+                    Ok(messages) => {
+                        for message in messages {
+                            peer.handle_text(&message.to_string()).await;
+                        }
+                    }
+                    Err(e) => error!("Invalid long polling response: {:?}", e),
+                },
+                Err(e) if e.is_timeout() => {}
+                Err(e) => {
+                    error!("Long polling failed: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::protocol::{SessionId, TurnCredentials};
+use crate::transport;
+use log::{info, warn};
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+impl TurnCredentials {
+    /// Credentials expiring in less than a minute are treated as expired
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+
+        self.expires_at().is_none_or(|expires_at| expires_at <= now + 60)
+    }
+
+    /// Whether the credentials were issued for the session, they count towards its TURN bandwidth
+    pub fn is_for(&self, session_id: &SessionId) -> bool {
+        self.username.ends_with(&format!(":{session_id}"))
+    }
+
+    pub fn to_ice_server(&self) -> RTCIceServer {
+        RTCIceServer {
+            urls: self.uris.clone(),
+            username: self.username.clone(),
+            credential: self.password.clone(),
+        }
+    }
+}
+
+/// Url of the TURN credentials endpoint next to the signaling websocket
+pub fn credentials_url(signaling_url: &Url, session_id: &SessionId) -> Url {
+    let mut url = transport::http_url(signaling_url, "turn-credentials");
+    url.query_pairs_mut().append_pair("session", session_id.as_str());
+
+    url
+}
+
+/// How long to wait for the signaling server to issue credentials before connecting without them
+const CREDENTIALS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TURN credentials of the current session
+#[derive(Debug, Clone, Default)]
+pub struct TurnCache {
+    pub credentials: Option<TurnCredentials>,
+    /// Session the signaling server doesn't issue credentials for, they aren't requested again for it
+    unavailable: Option<SessionId>,
+}
+
+/// Request new TURN credentials for the session, returns `None` if the server doesn't issue them
+pub async fn fetch_credentials(signaling_url: &Url, session_id: &SessionId) -> Result<Option<TurnCredentials>, reqwest::Error> {
+    let client = reqwest::Client::builder().timeout(CREDENTIALS_TIMEOUT).build()?;
+    let response = client.get(credentials_url(signaling_url, session_id)).send().await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    Ok(Some(response.error_for_status()?.json().await?))
+}
+
+/// Refresh the credentials if they are missing, expired or for another session, then add them to `ice_servers`
+pub async fn merge_ice_servers(signaling_url: &Url, session_id: &SessionId, ice_servers: &[RTCIceServer], cache: &mut TurnCache) -> Vec<RTCIceServer> {
+    let outdated = cache.credentials.as_ref().is_none_or(|credentials| credentials.is_expired() || !credentials.is_for(session_id));
+    if outdated && cache.unavailable.as_ref() != Some(session_id) {
+        cache.credentials = match fetch_credentials(signaling_url, session_id).await {
+            Ok(Some(credentials)) => Some(credentials),
+            Ok(None) => {
+                info!("Signaling server doesn't issue TURN credentials");
+                cache.unavailable = Some(session_id.clone());
+                None
+            }
+            Err(e) => {
+                warn!("Failed to request TURN credentials: {:?}", e);
+                None
+            }
+        };
+    }
+
+    let mut ice_servers = ice_servers.to_vec();
+    if let Some(credentials) = &cache.credentials {
+        ice_servers.push(credentials.to_ice_server());
+    }
+
+    ice_servers
+}
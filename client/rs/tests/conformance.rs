@@ -0,0 +1,124 @@
+//! Checks the protocol against the shared fixtures in `protocol/`, which the other clients can use as well.
+
+use ezrtc::protocol::{Encoding, SignalMessage};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::PathBuf;
+
+#[derive(Deserialize)]
+struct Fixtures {
+    version: u32,
+    messages: Vec<Fixture>,
+}
+
+#[derive(Deserialize)]
+struct Fixture {
+    name: String,
+    message: Value,
+}
+
+fn protocol_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../protocol")
+}
+
+fn load(version: u32) -> Fixtures {
+    let path = protocol_dir().join(format!("fixtures/v{version}.json"));
+    let text = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("failed to read {path:?}: {e}"));
+
+    serde_json::from_str(&text).unwrap()
+}
+
+fn all() -> Vec<Fixtures> {
+    (1..=3).map(load).collect()
+}
+
+fn decode(fixture: &Fixture) -> SignalMessage {
+    SignalMessage::decode(&fixture.message.to_string()).unwrap_or_else(|e| panic!("failed to decode {}: {e}", fixture.name))
+}
+
+#[test]
+fn fixtures_round_trip() {
+    for fixtures in all() {
+        for fixture in &fixtures.messages {
+            let encoded = decode(fixture).encode(fixtures.version).unwrap();
+            let encoded: Value = serde_json::from_str(&encoded).unwrap();
+
+            assert_eq!(encoded, fixture.message, "{} changed in version {}", fixture.name, fixtures.version);
+        }
+    }
+}
+
+#[test]
+fn fixtures_convert_between_versions() {
+    let all = all();
+
+    for from in &all {
+        for to in &all {
+            for fixture in &from.messages {
+                // only newer versions have messages for the fields they added
+                let Some(expected) = to.messages.iter().find(|message| message.name == fixture.name) else {
+                    assert!(to.version < from.version, "{} is missing in version {}", fixture.name, to.version);
+                    continue;
+                };
+                let converted = decode(fixture).to_value(to.version).unwrap();
+
+                assert_eq!(converted, expected.message, "{} from version {} to {}", fixture.name, from.version, to.version);
+            }
+        }
+    }
+}
+
+#[test]
+fn metadata_is_dropped_for_older_versions() {
+    let fixtures = load(3);
+    let fixture = fixtures.messages.iter().find(|message| message.name == "session_ready_metadata").unwrap();
+    let v1 = load(1);
+    let expected = &v1.messages.iter().find(|message| message.name == "session_ready").unwrap().message;
+
+    assert_eq!(&decode(fixture).to_value(1).unwrap(), expected);
+}
+
+#[test]
+fn fixtures_round_trip_binary() {
+    for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+        for fixtures in all() {
+            for fixture in &fixtures.messages {
+                let bytes = decode(fixture).encode_binary(encoding, fixtures.version).unwrap();
+                let decoded = SignalMessage::decode_binary(&bytes, encoding).unwrap_or_else(|e| panic!("failed to decode {} as {encoding:?}: {e}", fixture.name));
+
+                assert_eq!(decoded.to_value(fixtures.version).unwrap(), fixture.message, "{} as {encoding:?}", fixture.name);
+            }
+        }
+    }
+}
+
+fn assert_valid(schema: &Value) {
+    let validator = jsonschema::validator_for(schema).expect("invalid schema");
+
+    for fixtures in all() {
+        for fixture in &fixtures.messages {
+            let errors: Vec<_> = validator.iter_errors(&fixture.message).map(|e| e.to_string()).collect();
+            assert!(errors.is_empty(), "{} in version {} doesn't match the schema: {errors:?}", fixture.name, fixtures.version);
+        }
+    }
+}
+
+#[test]
+fn fixtures_match_schema() {
+    let schema = std::fs::read_to_string(protocol_dir().join("schema.json")).unwrap();
+    assert_valid(&serde_json::from_str(&schema).unwrap());
+}
+
+#[cfg(feature = "schema")]
+#[test]
+fn fixtures_match_generated_schema() {
+    assert_valid(&serde_json::to_value(ezrtc::schema::json_schema()).unwrap());
+}
+
+#[cfg(feature = "schema")]
+#[test]
+fn schema_file_is_up_to_date() {
+    let schema: Value = serde_json::from_str(&std::fs::read_to_string(protocol_dir().join("schema.json")).unwrap()).unwrap();
+
+    assert_eq!(schema, serde_json::to_value(ezrtc::schema::json_schema()).unwrap(), "regenerate protocol/schema.json with `cargo r --example schema --features schema`");
+}
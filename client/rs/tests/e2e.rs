@@ -0,0 +1,123 @@
+//! Sealing of SDP and ICE payloads with the session secret
+
+use ezrtc::e2e::{SealError, SessionCipher};
+use ezrtc::protocol::{IceCandidateJSON, IcePayload, SdpPayload, SdpType, SealedPayload, SessionId, SignalMessage, UserId, PROTOCOL_VERSION};
+
+const HOST: UserId = UserId::new(1);
+const CLIENT: UserId = UserId::new(7);
+
+fn ciphers(host_secret: &str, client_secret: &str) -> (SessionCipher, SessionCipher) {
+    let session_id = SessionId::new("room-1".to_string());
+    (SessionCipher::new(host_secret, &session_id, true), SessionCipher::new(client_secret, &session_id, false))
+}
+
+fn offer() -> SignalMessage {
+    SignalMessage::SdpOffer(
+        SessionId::new("room-1".to_string()),
+        CLIENT,
+        SdpPayload::new(SdpType::Offer, "v=0\r\n".to_string(), PROTOCOL_VERSION),
+    )
+}
+
+/// The server replaces the recipient with the sender when it passes a message on
+fn forward(message: SignalMessage, from: UserId) -> SignalMessage {
+    match message {
+        SignalMessage::SdpOffer(session_id, _, payload) => SignalMessage::SdpOffer(session_id, from, payload),
+        SignalMessage::SdpAnswer(session_id, _, payload) => SignalMessage::SdpAnswer(session_id, from, payload),
+        SignalMessage::IceCandidate(session_id, _, payload) => SignalMessage::IceCandidate(session_id, from, payload),
+        message => message,
+    }
+}
+
+#[test]
+fn sealed_offer_opens_for_the_other_peer() {
+    let (host, client) = ciphers("secret", "secret");
+
+    let sealed = host.seal(offer(), HOST);
+    let SignalMessage::SdpOffer(_, _, SdpPayload::Sealed(_)) = &sealed else {
+        panic!("offer wasn't sealed: {sealed:?}");
+    };
+
+    match client.open(forward(sealed, HOST), CLIENT) {
+        Ok(SignalMessage::SdpOffer(_, _, offer)) => assert_eq!(offer.sdp(), "v=0\r\n"),
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+#[test]
+fn sealed_candidate_opens_for_the_other_peer() {
+    let (host, client) = ciphers("secret", "secret");
+    let candidate = IceCandidateJSON {
+        candidate: "candidate:1 1 udp 2130706431 192.0.2.1 54321 typ host".to_string(),
+        sdp_mid: Some("0".to_string()),
+        sdp_mline_index: Some(0),
+        username_fragment: None,
+    };
+
+    let sealed = client.seal(SignalMessage::IceCandidate(SessionId::new("room-1".to_string()), HOST, IcePayload::Candidate(candidate)), CLIENT);
+    match host.open(forward(sealed, CLIENT), HOST) {
+        Ok(SignalMessage::IceCandidate(_, _, payload)) => assert_eq!(payload.candidate().unwrap().sdp_mid.as_deref(), Some("0")),
+        other => panic!("unexpected result {other:?}"),
+    }
+}
+
+#[test]
+fn tampered_payloads_are_rejected() {
+    let (host, client) = ciphers("secret", "secret");
+
+    let SignalMessage::SdpOffer(session_id, _, SdpPayload::Sealed(SealedPayload { sealed })) = host.seal(offer(), HOST) else {
+        panic!("offer wasn't sealed");
+    };
+
+    // flip a character of the ciphertext
+    let mut tampered = sealed.into_bytes();
+    let last = tampered.len() - 3;
+    tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+    let tampered = SdpPayload::Sealed(SealedPayload {
+        sealed: String::from_utf8(tampered).unwrap(),
+    });
+
+    assert_eq!(client.open(SignalMessage::SdpOffer(session_id, HOST, tampered), CLIENT).unwrap_err(), SealError::Invalid);
+}
+
+#[test]
+fn payloads_are_bound_to_secret_sender_and_kind() {
+    let (host, client) = ciphers("secret", "other secret");
+    assert_eq!(client.open(forward(host.seal(offer(), HOST), HOST), CLIENT).unwrap_err(), SealError::Invalid);
+
+    // the server can't reflect the host's offer back to it
+    let (host, _) = ciphers("secret", "secret");
+    assert_eq!(host.open(forward(host.seal(offer(), HOST), CLIENT), HOST).unwrap_err(), SealError::Invalid);
+
+    // or turn an offer into an answer
+    let (host, client) = ciphers("secret", "secret");
+    let SignalMessage::SdpOffer(session_id, _, payload) = host.seal(offer(), HOST) else {
+        unreachable!()
+    };
+    assert_eq!(client.open(SignalMessage::SdpAnswer(session_id, HOST, payload), CLIENT).unwrap_err(), SealError::Invalid);
+}
+
+#[test]
+fn payloads_are_bound_to_both_users() {
+    let (host, client) = ciphers("secret", "secret");
+    let sealed = host.seal(offer(), HOST);
+
+    // the server can't pass the offer on to another client of the session
+    assert_eq!(client.open(forward(sealed.clone(), HOST), UserId::new(8)).unwrap_err(), SealError::Invalid);
+    // or claim it came from another user
+    assert_eq!(client.open(forward(sealed, UserId::new(2)), CLIENT).unwrap_err(), SealError::Invalid);
+}
+
+#[test]
+fn unsealed_payloads_are_rejected() {
+    let (_, client) = ciphers("secret", "secret");
+    assert_eq!(client.open(offer(), CLIENT).unwrap_err(), SealError::Unsealed);
+}
+
+#[test]
+fn generated_secrets_are_random() {
+    let secret = SessionCipher::generate_secret();
+
+    assert_eq!(secret.len(), 44);
+    assert_ne!(secret, SessionCipher::generate_secret());
+}
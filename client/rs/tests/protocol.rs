@@ -0,0 +1,75 @@
+use ezrtc::protocol::{Capability, Handshake, SessionId, SessionIdError, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+fn handshake(min_version: u32, version: u32) -> Handshake {
+    Handshake {
+        version,
+        min_version,
+        capabilities: Vec::new(),
+    }
+}
+
+#[test]
+fn accepts_valid_session_ids() {
+    for session_id in ["a", "room-1", "Room_2", "v1.2", &"x".repeat(SessionId::MAX_LENGTH)] {
+        assert_eq!(SessionId::new(session_id.to_string()).validate(), Ok(()), "{session_id:?} was rejected");
+        assert!(session_id.parse::<SessionId>().is_ok());
+    }
+}
+
+#[test]
+fn rejects_invalid_session_ids() {
+    let validate = |session_id: &str| SessionId::new(session_id.to_string()).validate();
+
+    assert_eq!(validate(""), Err(SessionIdError::Empty));
+    assert_eq!(validate(&"x".repeat(SessionId::MAX_LENGTH + 1)), Err(SessionIdError::TooLong(SessionId::MAX_LENGTH + 1)));
+    assert_eq!(validate("tenant:room"), Err(SessionIdError::InvalidCharacter(':')));
+    assert_eq!(validate("room 1"), Err(SessionIdError::InvalidCharacter(' ')));
+    assert_eq!(validate("../room"), Err(SessionIdError::InvalidCharacter('/')));
+    assert_eq!(validate("räum"), Err(SessionIdError::InvalidCharacter('ä')));
+    assert!("tenant:room".parse::<SessionId>().is_err());
+}
+
+#[test]
+fn negotiates_the_newest_shared_version() {
+    assert_eq!(handshake(1, 3).negotiate(&handshake(1, 3)), Some(3));
+    assert_eq!(handshake(1, 3).negotiate(&handshake(1, 2)), Some(2));
+    assert_eq!(handshake(2, 5).negotiate(&handshake(1, 3)), Some(3));
+    assert_eq!(handshake(1, 1).negotiate(&handshake(1, 3)), Some(1));
+
+    // ranges that only touch at one version
+    assert_eq!(handshake(3, 4).negotiate(&handshake(1, 3)), Some(3));
+    assert_eq!(handshake(1, 3).negotiate(&handshake(3, 4)), Some(3));
+}
+
+#[test]
+fn refuses_versions_outside_both_ranges() {
+    assert_eq!(handshake(4, 5).negotiate(&handshake(1, 3)), None);
+    assert_eq!(handshake(1, 3).negotiate(&handshake(4, 5)), None);
+
+    // a peer that claims a minimum above its own version
+    assert_eq!(handshake(1, 3).negotiate(&handshake(3, 2)), None);
+    assert_eq!(handshake(1, 3).negotiate(&handshake(0, 0)), None);
+}
+
+#[test]
+fn this_crate_negotiates_its_own_version() {
+    let handshake = Handshake::new(vec![Capability::Relay]);
+
+    assert_eq!(handshake.version, PROTOCOL_VERSION);
+    assert_eq!(handshake.min_version, MIN_PROTOCOL_VERSION);
+    assert_eq!(handshake.negotiate(&handshake), Some(PROTOCOL_VERSION));
+    assert!(handshake.supports(Capability::Relay));
+    assert!(!handshake.supports(Capability::BinaryEncoding));
+}
+
+#[test]
+fn decodes_capabilities_of_newer_versions() {
+    let handshake: Handshake = serde_json::from_str(r#"{ "version": 9, "min_version": 1, "capabilities": ["relay", "time_travel"] }"#).unwrap();
+
+    assert_eq!(handshake.capabilities, [Capability::Relay, Capability::Unknown]);
+    assert_eq!(handshake.negotiate(&Handshake::new(Vec::new())), Some(PROTOCOL_VERSION));
+
+    // older users don't send capabilities
+    let handshake: Handshake = serde_json::from_str(r#"{ "version": 1, "min_version": 1 }"#).unwrap();
+    assert!(handshake.capabilities.is_empty());
+}
@@ -0,0 +1,39 @@
+//! Short authentication strings derived from the DTLS fingerprints
+
+use ezrtc::sas;
+
+const HOST: &str = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=fingerprint:sha-256 AA:BB:CC\r\n";
+const CLIENT: &str = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=fingerprint:sha-256 11:22:33\r\n";
+const ATTACKER: &str = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=fingerprint:sha-256 DE:AD:BE\r\n";
+
+#[test]
+fn both_peers_derive_the_same_string() {
+    let host = sas::derive(HOST, CLIENT).unwrap();
+    let client = sas::derive(CLIENT, HOST).unwrap();
+
+    assert_eq!(host, client);
+    assert_eq!(host.len(), 7);
+}
+
+#[test]
+fn replaced_fingerprint_changes_the_string() {
+    // the server answered the host in place of the client
+    let host = sas::derive(HOST, ATTACKER).unwrap();
+    let client = sas::derive(CLIENT, HOST).unwrap();
+
+    assert_ne!(host, client);
+}
+
+#[test]
+fn missing_fingerprint_has_no_string() {
+    assert_eq!(sas::derive(HOST, "v=0\r\n"), None);
+}
+
+#[test]
+fn confirmation_is_checked() {
+    let message = sas::confirmation("042 917");
+
+    assert_eq!(sas::check_confirmation(&message, "042 917"), Some(true));
+    assert_eq!(sas::check_confirmation(&message, "042 918"), Some(false));
+    assert_eq!(sas::check_confirmation("hello", "042 917"), None);
+}
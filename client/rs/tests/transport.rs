@@ -0,0 +1,48 @@
+//! Signaling transport: urls of the HTTP endpoints next to the signaling websocket and acknowledged sends
+
+use ezrtc::options::ConnectionOptions;
+use ezrtc::protocol::{SessionId, SignalMessage};
+use ezrtc::transport::{http_url, poll_url, DeliveryError, Frame, SignalingHandle};
+use url::Url;
+
+fn signaling_url() -> Url {
+    Url::parse("wss://example.com/one-to-many?app_key=secret&version=3").unwrap()
+}
+
+#[test]
+fn http_url_keeps_the_app_key() {
+    let url = http_url(&signaling_url(), "sessions");
+
+    assert_eq!(url.as_str(), "https://example.com/sessions?app_key=secret");
+}
+
+#[test]
+fn poll_url_matches_the_server_routes() {
+    assert_eq!(poll_url(&signaling_url(), None).as_str(), "https://example.com/poll?app_key=secret");
+    assert_eq!(poll_url(&signaling_url(), Some("token")).as_str(), "https://example.com/poll/token?app_key=secret");
+}
+
+#[test]
+fn long_polling_is_opt_in() {
+    assert!(!ConnectionOptions::default().long_poll_fallback);
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_reuse_the_request_id() {
+    let (handle, mut frames) = SignalingHandle::new();
+    handle.set_acknowledgements(true);
+
+    let result = handle.send_with_retry(SignalMessage::SessionLeave(SessionId::new("room".to_string())), 2).await;
+
+    let mut request_ids = Vec::new();
+    while let Ok(Frame::Text(text)) = frames.try_recv() {
+        match SignalMessage::decode(&text).unwrap() {
+            SignalMessage::Request(request_id, _) => request_ids.push(request_id),
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    assert_eq!(result, Err(DeliveryError::Timeout));
+    assert_eq!(request_ids.len(), 2);
+    assert_eq!(request_ids[0], request_ids[1]);
+}
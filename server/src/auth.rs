@@ -0,0 +1,22 @@
+use crate::config::Config;
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+
+/// Check the `Authorization: Bearer <api_token>` header of backend requests,
+/// the API is disabled if no token is configured
+pub fn authorize(headers: &HeaderMap, config: &Config) -> Result<(), StatusCode> {
+    let Some(api_token) = &config.api_token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let token = headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok()).and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), api_token.as_bytes()) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,83 @@
+use crate::firewall::FirewallConfig;
+use crate::relay::RelayConfig;
+use crate::stun_server::StunConfig;
+use crate::tenant::{Quotas, TenantConfig};
+use crate::turn::TurnConfig;
+use crate::webhook::WebhookConfig;
+use ezrtc::protocol::IceConfig;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+
+/// Server configuration, read from the JSON file given in `EZRTC_CONFIG`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Share sessions between server instances through Redis (requires the `redis` feature)
+    pub redis_url: Option<String>,
+    /// Bearer token required by the backend API, e.g. `/sessions/:id/notify`
+    pub api_token: Option<String>,
+    /// Serve `/metrics` without the API token, e.g. to a scraper on a private network
+    pub public_metrics: bool,
+    /// Issue time-limited credentials for a TURN server at `/turn-credentials`
+    pub turn: Option<TurnConfig>,
+    /// ICE servers and transport policy sent to users when they join a session
+    pub ice: Option<IceConfig>,
+    /// Answer STUN binding requests on a UDP port
+    pub stun: Option<StunConfig>,
+    /// Relay data channel messages over the signaling connection when peer-to-peer fails
+    pub relay: Option<RelayConfig>,
+    /// Post signed session lifecycle events to these urls
+    pub webhooks: Option<WebhookConfig>,
+    /// Seconds until sessions reserved with `POST /sessions` expire if nobody uses them
+    pub reservation_ttl: Option<u64>,
+    /// Apps with their own session namespace and quotas, selected with an app key
+    pub tenants: Vec<TenantConfig>,
+    /// Quotas of users connecting without an app key
+    pub quotas: Quotas,
+    /// Origins allowed to use the HTTP routes and open websockets, e.g. `https://*.example.com`, every origin is allowed if not set
+    pub allowed_origins: Option<Vec<String>>,
+    /// Address allow and deny lists and bans of abusive addresses
+    pub firewall: FirewallConfig,
+}
+
+impl Config {
+    pub fn reservation_duration(&self) -> Duration {
+        Duration::from_secs(self.reservation_ttl.unwrap_or(600))
+    }
+
+    pub fn load() -> crate::Result<Self> {
+        match env::var("EZRTC_CONFIG") {
+            Ok(path) => {
+                let file = std::fs::read_to_string(&path)?;
+                let config: Self = serde_json::from_str(&file)?;
+                config.validate()?;
+                Ok(config)
+            }
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Reject tenants that would share a namespace or an app key
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut names = HashSet::new();
+        let mut app_keys = HashSet::new();
+
+        for tenant in &self.tenants {
+            // scoped session ids are `<tenant>:<session_id>`, users without an app key have the empty name
+            if tenant.name.is_empty() || tenant.name.contains(':') {
+                bail!("invalid tenant name {:?}, it can't be empty or contain ':'", tenant.name);
+            }
+            if !names.insert(&tenant.name) {
+                bail!("tenant {:?} is configured more than once", tenant.name);
+            }
+            if !app_keys.insert(&tenant.app_key) {
+                bail!("tenant {:?} has the app key of another tenant", tenant.name);
+            }
+        }
+
+        Ok(())
+    }
+}
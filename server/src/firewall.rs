@@ -0,0 +1,326 @@
+use crate::config::Config;
+use crate::metrics::increment;
+use crate::router::ServerState;
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use ezrtc::protocol::UserId;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FirewallConfig {
+    /// Only addresses in these ranges can connect, every address is allowed if empty
+    pub allow: Vec<String>,
+    /// Addresses in these ranges can't connect
+    pub deny: Vec<String>,
+    /// Abusive messages from one address within `strike_window` seconds before it's banned
+    pub strikes: u32,
+    pub strike_window: u64,
+    /// Seconds until a ban expires
+    pub ban_duration: u64,
+    /// Read the client address from `X-Forwarded-For`, only enable behind a reverse proxy
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            strikes: 10,
+            strike_window: 60,
+            ban_duration: 600,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+/// Address range like `10.0.0.0/8` or `2001:db8::/32`, a plain address matches only itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => mask(u32::from(network).into(), u32::from(address).into(), 32, self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(address)) => mask(u128::from(network), u128::from(address), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+/// `true` if the first `prefix` bits of the `bits` long addresses are equal
+fn mask(network: u128, address: u128, bits: u8, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+
+    let shift = u32::from(bits - prefix);
+    network >> shift == address >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+
+        let address = IpAddr::from_str(address.trim()).map_err(|e| format!("Invalid address {s:?}: {e}"))?.to_canonical();
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|prefix| *prefix <= bits).ok_or_else(|| format!("Invalid prefix length in {s:?}"))?,
+            None => bits,
+        };
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Allow and deny lists, replaced as a whole when they are reloaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FirewallLists {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct ParsedLists {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl TryFrom<&FirewallLists> for ParsedLists {
+    type Error = String;
+
+    fn try_from(lists: &FirewallLists) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allow: lists.allow.iter().map(|cidr| cidr.parse()).collect::<Result<_, _>>()?,
+            deny: lists.deny.iter().map(|cidr| cidr.parse()).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub address: IpAddr,
+    /// Seconds until the ban expires
+    pub expires_in: u64,
+}
+
+/// Lists and bans returned by `GET /admin/firewall`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirewallStatus {
+    #[serde(flatten)]
+    pub lists: FirewallLists,
+    pub bans: Vec<Ban>,
+}
+
+/// Blocks addresses from the deny list and bans addresses that keep sending abusive messages
+pub struct Firewall {
+    config: FirewallConfig,
+    lists: RwLock<(FirewallLists, ParsedLists)>,
+    strikes: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+    users: Mutex<HashMap<UserId, IpAddr>>,
+}
+
+impl Firewall {
+    pub fn new(config: FirewallConfig) -> Self {
+        let lists = FirewallLists {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        };
+
+        let firewall = Self {
+            config,
+            lists: RwLock::default(),
+            strikes: Mutex::default(),
+            bans: Mutex::default(),
+            users: Mutex::default(),
+        };
+
+        if let Err(e) = firewall.reload(lists) {
+            warn!("Ignoring firewall lists: {}", e);
+        }
+
+        firewall
+    }
+
+    /// Replace the allow and deny lists, the current lists are kept if any range is invalid
+    pub fn reload(&self, lists: FirewallLists) -> Result<(), String> {
+        let parsed = ParsedLists::try_from(&lists)?;
+        *self.lists.write().unwrap() = (lists, parsed);
+
+        Ok(())
+    }
+
+    pub fn is_allowed(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        if self.ban_expiry(address).is_some() {
+            return false;
+        }
+
+        let lists = self.lists.read().unwrap();
+        let (_, parsed) = &*lists;
+
+        if parsed.deny.iter().any(|cidr| cidr.contains(address)) {
+            return false;
+        }
+
+        parsed.allow.is_empty() || parsed.allow.iter().any(|cidr| cidr.contains(address))
+    }
+
+    fn ban_expiry(&self, address: IpAddr) -> Option<Instant> {
+        let mut bans = self.bans.lock().unwrap();
+
+        match bans.get(&address) {
+            Some(expiry) if *expiry > Instant::now() => Some(*expiry),
+            Some(_) => {
+                bans.remove(&address);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn status(&self) -> FirewallStatus {
+        let now = Instant::now();
+        let mut bans: Vec<_> = self
+            .bans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, expiry)| **expiry > now)
+            .map(|(address, expiry)| Ban {
+                address: *address,
+                expires_in: (*expiry - now).as_secs(),
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.address);
+
+        FirewallStatus {
+            lists: self.lists.read().unwrap().0.clone(),
+            bans,
+        }
+    }
+
+    /// Lift the ban of the address, returns `false` if it wasn't banned
+    pub fn unban(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        self.strikes.lock().unwrap().remove(&address);
+        self.bans.lock().unwrap().remove(&address).is_some()
+    }
+
+    /// Remember the address of a connected user so its messages can be counted against it
+    pub fn track(&self, user_id: UserId, address: Option<IpAddr>) {
+        if let Some(address) = address {
+            self.users.lock().unwrap().insert(user_id, address.to_canonical());
+        }
+    }
+
+    pub fn forget(&self, user_id: UserId) {
+        self.users.lock().unwrap().remove(&user_id);
+    }
+
+    /// Count an abusive message of the user, returns the users connected from the address if it's banned now
+    pub fn strike(&self, user_id: UserId) -> Vec<UserId> {
+        let Some(address) = self.users.lock().unwrap().get(&user_id).copied() else {
+            return Vec::new();
+        };
+
+        let strike_window = Duration::from_secs(self.config.strike_window);
+        {
+            let mut strikes = self.strikes.lock().unwrap();
+            // strikes are only counted here, dropping expired windows keeps addresses that stopped from piling up
+            strikes.retain(|_, (start, _)| start.elapsed() < strike_window);
+            let window = strikes.entry(address).or_insert_with(|| (Instant::now(), 0));
+
+            window.1 += 1;
+            if window.1 < self.config.strikes {
+                return Vec::new();
+            }
+
+            strikes.remove(&address);
+        }
+
+        warn!("Banning {} for {} seconds", address, self.config.ban_duration);
+        {
+            let now = Instant::now();
+            let mut bans = self.bans.lock().unwrap();
+            bans.retain(|_, expiry| *expiry > now);
+            bans.insert(address, now + Duration::from_secs(self.config.ban_duration));
+        }
+
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, user_address)| **user_address == address)
+            .map(|(user_id, _)| *user_id)
+            .collect()
+    }
+}
+
+/// Address of the client, `None` if the server wasn't started with connect info
+pub fn client_ip(config: &Config, headers: &HeaderMap, extensions: &Extensions) -> Option<IpAddr> {
+    if config.firewall.trust_forwarded_for {
+        // the proxy appends the address it received the request from
+        let forwarded = headers.get("x-forwarded-for").and_then(|value| value.to_str().ok()).and_then(|value| value.rsplit(',').next());
+        if let Some(address) = forwarded.and_then(|address| address.trim().parse().ok()) {
+            return Some(address);
+        }
+    }
+
+    extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.ip())
+}
+
+/// Address of the client, see [`client_ip`]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<ServerState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> Result<Self, Self::Rejection> {
+        Ok(Self(client_ip(&state.config, &parts.headers, &parts.extensions)))
+    }
+}
+
+/// Reject every request from denied or banned addresses
+pub async fn guard(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    if let Some(address) = client_ip(&state.config, request.headers(), request.extensions()) {
+        if !state.firewall.is_allowed(address) {
+            warn!("Blocked request from {}", address);
+            increment(&state.metrics.firewall.blocked);
+
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    next.run(request).await
+}
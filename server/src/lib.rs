@@ -1,7 +1,26 @@
+pub mod auth;
+pub mod config;
 mod error;
+pub mod firewall;
+pub mod lobby;
+pub mod metrics;
 // pub mod many_to_many;
 pub mod one_to_many;
+pub mod origin;
+pub mod poll;
+pub mod rate_limit;
 // pub mod one_to_one;
+pub mod relay;
+pub mod request;
+pub mod reservation;
 pub mod router;
+pub mod store;
+pub mod stun_server;
+pub mod tenant;
+pub mod turn;
+pub mod turn_server;
+pub mod version;
+pub mod webhook;
+pub mod whep;
 
 pub use error::{Error, Result};
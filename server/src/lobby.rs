@@ -0,0 +1,70 @@
+use crate::router::ServerState;
+use crate::tenant::Tenant;
+use ezrtc::protocol::SessionId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+/// Public session listed at `GET /sessions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub session_id: SessionId,
+    pub metadata: Option<serde_json::Value>,
+    /// Users connected to the host
+    pub clients: usize,
+    /// Version reported by the host
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LobbyPage {
+    pub sessions: Vec<SessionSummary>,
+    /// Number of sessions matching the filters on every page
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+/// List public sessions of the tenant, `query` contains `page`, `per_page` and `metadata.<field>=<value>` filters
+pub async fn list(state: &ServerState, tenant: &Tenant, query: &HashMap<String, String>) -> crate::Result<LobbyPage> {
+    let page = query.get("page").and_then(|page| page.parse().ok()).unwrap_or(1).max(1);
+    let per_page = query.get("per_page").and_then(|per_page| per_page.parse().ok()).unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let filters: Vec<(&str, &str)> = query.iter().filter_map(|(key, value)| Some((key.strip_prefix("metadata.")?, value.as_str()))).collect();
+
+    let pings = state.store.public_pings(tenant.name()).await?;
+    let mut pings: Vec<_> = pings
+        .into_iter()
+        .filter_map(|ping| Some((ping.session_id.clone()?, ping)))
+        .filter(|(_, ping)| matches(ping.metadata.as_ref(), &filters))
+        .collect();
+    pings.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+    let total = pings.len();
+    let pings: Vec<_> = pings.into_iter().skip((page - 1) * per_page).take(per_page).collect();
+    let session_ids: Vec<SessionId> = pings.iter().map(|(session_id, _)| session_id.clone()).collect();
+    let clients = state.store.client_counts(&session_ids).await?;
+
+    let sessions = pings
+        .into_iter()
+        .zip(clients)
+        .map(|((session_id, ping), clients)| SessionSummary {
+            session_id: tenant.unscope(&session_id),
+            metadata: ping.metadata,
+            clients,
+            version: ping.version,
+        })
+        .collect();
+
+    Ok(LobbyPage { sessions, total, page, per_page })
+}
+
+/// Every filter has to match a top level metadata field, strings are compared as is and other values as JSON
+fn matches(metadata: Option<&serde_json::Value>, filters: &[(&str, &str)]) -> bool {
+    filters.iter().all(|(field, expected)| match metadata.and_then(|metadata| metadata.get(field)) {
+        Some(serde_json::Value::String(value)) => value == expected,
+        Some(value) => serde_json::from_str::<serde_json::Value>(expected).is_ok_and(|expected| expected == *value),
+        None => false,
+    })
+}
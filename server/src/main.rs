@@ -2,22 +2,62 @@ use std::env;
 use std::net::SocketAddr;
 use std::str::FromStr;
 
+use ezrtc_server::config::Config;
 use ezrtc_server::router::{self, ServerState};
-use log::LevelFilter;
-use simplelog::{ColorChoice, Config, TermLogger, TerminalMode};
+use ezrtc_server::turn::TurnConfig;
+use ezrtc_server::{stun_server, turn_server};
+use log::{error, LevelFilter};
+use simplelog::{ColorChoice, Config as LogConfig, TermLogger, TerminalMode};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Mixed, ColorChoice::Auto)?;
+    TermLogger::init(LevelFilter::Info, LogConfig::default(), TerminalMode::Mixed, ColorChoice::Auto)?;
+
+    let config = Config::load()?;
+    let server_state = create_state(&config).await?;
+
+    if let Some(stun_config) = config.stun.clone() {
+        let metrics = server_state.metrics();
+        tokio::spawn(async move {
+            if let Err(e) = stun_server::run(stun_config, metrics).await {
+                error!("STUN responder stopped: {}", e);
+            }
+        });
+    }
+
+    // keep the TURN server running until the signaling server stops
+    let _turn_server = match &config.turn {
+        Some(TurnConfig { secret, server: Some(turn_server_config), .. }) => Some(turn_server::run(turn_server_config.clone(), secret.clone(), server_state.metrics()).await?),
+        _ => None,
+    };
 
-    let server_state = ServerState::default();
     let app = router::create(server_state);
 
     let address = env::args().nth(1).unwrap_or_else(|| "0.0.0.0:9001".to_string());
     let socket_addr = SocketAddr::from_str(&address)?;
     let listener = tokio::net::TcpListener::bind(socket_addr).await?;
 
-    axum::serve::serve(listener, app.into_make_service()).await?;
+    axum::serve::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
+
+#[cfg(feature = "redis")]
+async fn create_state(config: &Config) -> anyhow::Result<ServerState> {
+    use ezrtc_server::store::{RedisBus, RedisStore};
+    use std::sync::Arc;
+
+    match &config.redis_url {
+        Some(url) => Ok(ServerState::new(config.clone(), Arc::new(RedisStore::connect(url).await?), Arc::new(RedisBus::connect(url).await?))),
+        None => Ok(ServerState::with_config(config.clone())),
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+async fn create_state(config: &Config) -> anyhow::Result<ServerState> {
+    if config.redis_url.is_some() {
+        anyhow::bail!("redis_url is set, but the server was built without the redis feature");
+    }
+
+    Ok(ServerState::with_config(config.clone()))
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters exposed at `/metrics`
+#[derive(Default, Debug, Serialize)]
+pub struct Metrics {
+    pub stun: StunMetrics,
+    pub turn: TurnMetrics,
+    pub relay: RelayMetrics,
+    pub webhooks: WebhookMetrics,
+    pub origins: OriginMetrics,
+    pub firewall: FirewallMetrics,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct StunMetrics {
+    /// Binding requests received
+    pub requests: AtomicU64,
+    /// Binding responses sent
+    pub responses: AtomicU64,
+    /// Packets that weren't valid binding requests
+    pub invalid: AtomicU64,
+    /// Responses that failed to send
+    pub errors: AtomicU64,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct TurnMetrics {
+    /// Relay ports allocated
+    pub allocations: AtomicU64,
+    /// Bytes relayed by the TURN server
+    pub relayed_bytes: AtomicU64,
+    /// Packets dropped because their session exceeded its bandwidth limit
+    pub dropped_packets: AtomicU64,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct RelayMetrics {
+    /// Relays opened after a failed peer connection
+    pub opened: AtomicU64,
+    /// Bytes relayed through the signaling server
+    pub relayed_bytes: AtomicU64,
+    /// Messages dropped because the user exceeded the bandwidth limit
+    pub dropped_messages: AtomicU64,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct WebhookMetrics {
+    /// Events accepted by a webhook url
+    pub delivered: AtomicU64,
+    /// Events dropped after every retry failed
+    pub failed: AtomicU64,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct OriginMetrics {
+    /// Websocket upgrades from origins that aren't allowed
+    pub rejected_upgrades: AtomicU64,
+    /// HTTP requests from origins that aren't allowed
+    pub rejected_requests: AtomicU64,
+}
+
+#[derive(Default, Debug, Serialize)]
+pub struct FirewallMetrics {
+    /// Requests and websocket upgrades from denied or banned addresses
+    pub blocked: AtomicU64,
+    /// Addresses banned for sending abusive messages
+    pub bans: AtomicU64,
+}
+
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
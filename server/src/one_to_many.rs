@@ -1,39 +1,48 @@
-use anyhow::anyhow;
+use crate::auth;
+use crate::metrics::increment;
+use crate::request::SeenRequest;
+use crate::router::ServerState;
+use crate::store::{Outbound, Removal};
+use crate::tenant::Tenant;
+use crate::webhook::WebhookEvent;
 use axum::extract::ws::{CloseFrame, Message, WebSocket};
-use ezrtc::protocol::{SessionId, SignalMessage, Status, UserId};
+use ezrtc::protocol::{Capability, Encoding, Handshake, SessionId, SignalMessage, Status, UserId, UNSUPPORTED_VERSION};
 use futures_util::{SinkExt, StreamExt};
 use log::{error, info, warn};
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc;
 use tokio::time;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-#[derive(Default, Debug)]
-pub struct Session {
-    pub host: Option<UserId>,
-    pub users: HashSet<UserId>,
-}
-
-#[derive(Default, Debug)]
-pub struct Ping {
-    pub online: bool,
-    pub session_id: Option<SessionId>,
-    pub metadata: Option<serde_json::Value>,
-}
+pub use crate::store::{Ping, Session};
 
-pub type Connections = Arc<RwLock<HashMap<UserId, mpsc::UnboundedSender<Message>>>>;
-pub type Sessions = Arc<RwLock<HashMap<SessionId, Session>>>;
-pub type Pings = Arc<Mutex<HashMap<UserId, Arc<Ping>>>>;
+pub async fn user_connected(mut ws: WebSocket, tenant: Tenant, address: Option<IpAddr>, state: ServerState) {
+    let user_id = match state.store.next_user_id().await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            error!("Failed to allocate user id: {}", e);
+            return;
+        }
+    };
 
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
+    if !state.tenant_usage.connect(&tenant) {
+        warn!("Too many users connected to tenant {:?}", tenant.name());
+        let _ = ws
+            .send(Message::Close(Some(CloseFrame {
+                code: axum::extract::ws::close_code::POLICY,
+                reason: Cow::from("Too many users"),
+            })))
+            .await;
+        return;
+    }
 
-pub async fn user_connected(ws: WebSocket, connections: Connections, sessions: Sessions, pings: Pings) {
-    let user_id = UserId::new(NEXT_USER_ID.fetch_add(1, Ordering::Relaxed));
-    info!("new user connected: {:?}", user_id);
+    // binary encodings are selected with the websocket subprotocol
+    let encoding = ws.protocol().and_then(|protocol| protocol.to_str().ok()).and_then(Encoding::from_subprotocol).unwrap_or_default();
+    info!("new user connected: {:?} ({:?})", user_id, encoding);
+    state.firewall.track(user_id, address);
 
     let (mut ws_send, mut ws_recv) = ws.split();
 
@@ -42,51 +51,21 @@ pub async fn user_connected(ws: WebSocket, connections: Connections, sessions: S
     let mut rx = UnboundedReceiverStream::new(rx);
 
     // Ping client every 60 seconds
-    let tx2 = tx.clone();
-    let user_id2 = user_id.clone();
-    let pings2 = pings.clone();
-
-    let mut ping_task = tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(60));
-
-        loop {
-            interval.tick().await;
-
-            let status = {
-                let pings = pings2.lock().unwrap();
-                pings.get(&user_id2).cloned()
-            };
-
-            if let Some(ping) = status {
-                if ping.online {
-                    pings2.lock().unwrap().insert(
-                        user_id2.clone(),
-                        Arc::new(Ping {
-                            online: false,
-                            session_id: ping.session_id.clone(),
-                            metadata: ping.metadata.clone(),
-                        }),
-                    );
-                } else {
-                    error!("User failed to respond, closing connection: {:?}", user_id2);
-                    break;
-                }
-            }
-
-            warn!("Sending ping to user: {:?}", user_id2);
-
-            let response = SignalMessage::KeepAlive(user_id2.clone(), Status::default());
-            let response = serde_json::to_string(&response).unwrap();
-            if let Err(e) = tx2.send(Message::Text(response)) {
-                error!("Websocket ping error: {}", e);
-                break;
-            }
-        }
-    });
+    let mut ping_task = tokio::spawn(keep_alive(user_id, tx.clone(), state.clone()));
 
     // Send messages to websocket from channel
+    let tenant2 = tenant.clone();
+    let protocol_versions = state.protocol_versions.clone();
     let mut send_task = tokio::spawn(async move {
         while let Some(message) = rx.next().await {
+            let message = match tenant2.unscope_outbound(message).into_message(protocol_versions.get(user_id), encoding) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Failed to encode message: {}", e);
+                    continue;
+                }
+            };
+
             if ws_send.send(message).await.is_err() {
                 break;
             }
@@ -105,15 +84,14 @@ pub async fn user_connected(ws: WebSocket, connections: Connections, sessions: S
     });
 
     // Receive messages from websocket
-    let connections2 = connections.clone();
-    let sessions2 = sessions.clone();
-    let pings2 = pings.clone();
+    let state2 = state.clone();
+    let tenant2 = tenant.clone();
 
     let mut recv_task = tokio::spawn(async move {
         while let Some(msg) = ws_recv.next().await {
             match msg {
                 Ok(msg) => {
-                    if let Err(err) = user_message(user_id, msg, &connections2, &sessions2, &pings2).await {
+                    if let Err(err) = user_message(user_id, &tenant2, encoding, msg, &state2).await {
                         error!("error while handling user message: {}", err);
                     }
                 }
@@ -125,7 +103,9 @@ pub async fn user_connected(ws: WebSocket, connections: Connections, sessions: S
         }
     });
 
-    connections.write().await.insert(user_id, tx);
+    if let Err(e) = state.bus.register(user_id, tx).await {
+        error!("Failed to register user {:?}: {}", user_id, e);
+    }
 
     // Run all tasks and abort if any of them fails
     tokio::select! {
@@ -156,134 +136,520 @@ pub async fn user_connected(ws: WebSocket, connections: Connections, sessions: S
     }
 
     error!("User disconnected: {:?}", user_id);
-    pings.lock().unwrap().remove(&user_id);
-    user_disconnected(user_id, &connections, &sessions).await;
+    if let Err(e) = user_disconnected(user_id, &tenant, &state).await {
+        error!("error while removing user: {}", e);
+    }
 }
 
-async fn user_message(sender_id: UserId, msg: Message, connections: &Connections, sessions: &Sessions, pings: &Pings) -> crate::Result<()> {
-    if let Ok(msg) = msg.to_text() {
-        if msg.is_empty() || msg == "ping" {
-            // warn!("empty message from user {:?}", sender_id);
-            return Ok(());
+/// Ping the user every 60 seconds, returns once the user stops responding
+pub(crate) async fn keep_alive(user_id: UserId, tx: mpsc::UnboundedSender<Outbound>, state: ServerState) {
+    let mut interval = time::interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = state.store.touch(user_id).await {
+            error!("Failed to refresh user {:?}: {}", user_id, e);
         }
 
-        match serde_json::from_str::<SignalMessage>(msg) {
-            Ok(request) => {
-                info!("message received from user {:?}: {:?}", sender_id, request);
-                match request {
-                    SignalMessage::SessionJoin(session_id, is_host) => {
-                        let mut sessions_writer = sessions.write().await;
-                        let session = sessions_writer.entry(session_id.clone()).or_insert_with(Session::default);
-                        let connections_reader = connections.read().await;
-
-                        if is_host && session.host.is_none() {
-                            session.host = Some(sender_id);
-                            // start connections with all already present users
-                            for client_id in &session.users {
-                                {
-                                    let host_tx = connections_reader.get(&sender_id).expect("host not in connections");
-                                    let host_response = SignalMessage::SessionReady(session_id.clone(), *client_id);
-                                    let host_response = serde_json::to_string(&host_response)?;
-                                    host_tx.send(Message::Text(host_response)).expect("failed to send SessionReady message to host");
-                                }
-                            }
-                        } else if is_host && session.host.is_some() {
-                            warn!("connecting user wants to be a host, but host is already present, closing connection soon");
-
-                            let connections2 = connections.clone();
-
-                            tokio::task::spawn(async move {
-                                let new_host_tx = {
-                                    let connections_reader2 = connections2.read().await;
-                                    connections_reader2.get(&sender_id).cloned()
-                                };
-
-                                tokio::time::sleep(Duration::from_secs(60)).await;
-                                if let Some(new_host_tx) = new_host_tx {
-                                    new_host_tx
-                                        .send(Message::Close(Some(CloseFrame {
-                                            code: 3001,
-                                            reason: "Multiple hosts".into(),
-                                        })))
-                                        .expect("failed to send close message to host");
-                                }
-                            });
-                        } else {
-                            // connect new user with host
-                            session.users.insert(sender_id);
-
-                            if let Some(host_id) = session.host {
-                                let host_tx = connections_reader.get(&host_id).expect("host not in connections");
-                                let host_response = SignalMessage::SessionReady(session_id.clone(), sender_id);
-                                let host_response = serde_json::to_string(&host_response)?;
-                                host_tx.send(Message::Text(host_response)).expect("failed to send SessionReady message to host");
-                            }
-                        }
-                    }
-                    // pass offer to the other user in session without changing anything
-                    SignalMessage::SdpOffer(session_id, recipient_id, offer) => {
-                        let response = SignalMessage::SdpOffer(session_id, sender_id, offer);
-                        let response = serde_json::to_string(&response)?;
-                        let connections_reader = connections.read().await;
-                        if let Some(recipient_tx) = connections_reader.get(&recipient_id) {
-                            recipient_tx.send(Message::Text(response))?;
-                        } else {
-                            warn!("tried to send offer to non existing user");
-                        }
-                    }
-                    // pass answer to the other user in session without changing anything
-                    SignalMessage::SdpAnswer(session_id, recipient_id, answer) => {
-                        let response = SignalMessage::SdpAnswer(session_id, sender_id, answer);
-                        let response = serde_json::to_string(&response)?;
-                        let connections_reader = connections.read().await;
-                        if let Some(recipient_tx) = connections_reader.get(&recipient_id) {
-                            recipient_tx.send(Message::Text(response))?;
-                        } else {
-                            warn!("tried to send offer to non existing user");
-                        }
-                    }
-                    SignalMessage::IceCandidate(session_id, recipient_id, candidate) => {
-                        let response = SignalMessage::IceCandidate(session_id, sender_id, candidate);
-                        let response = serde_json::to_string(&response)?;
-                        let connections_reader = connections.read().await;
-                        let recipient_tx = connections_reader.get(&recipient_id).ok_or_else(|| anyhow!("no sender for given id"))?;
+        let status = match state.store.ping(user_id).await {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Failed to read ping: {}", e);
+                None
+            }
+        };
 
-                        recipient_tx.send(Message::Text(response))?;
-                    }
-                    SignalMessage::KeepAlive(user_id, status) => {
-                        if status.is_host.is_some() {
-                            warn!("Received ping from user {:?}", status.session_id);
-                            pings.lock().unwrap().insert(user_id, Arc::new(Ping { online: true, session_id: status.session_id, metadata: status.metadata }));
-                        }
-                    }
-                    _ => {}
+        if let Some(ping) = status {
+            if ping.online {
+                let ping = Ping { online: false, ..ping };
+                if let Err(e) = state.store.set_ping(user_id, ping).await {
+                    error!("Failed to store ping: {}", e);
                 }
+            } else {
+                error!("User failed to respond, closing connection: {:?}", user_id);
+                break;
             }
-            Err(error) => {
-                error!("An error occurred: {:?} {:?}", error, msg);
+        }
+
+        warn!("Sending ping to user: {:?}", user_id);
+
+        let response = SignalMessage::KeepAlive(user_id, Status::default());
+        if let Err(e) = tx.send(Outbound::Signal(response)) {
+            error!("Websocket ping error: {}", e);
+            break;
+        }
+    }
+}
+
+async fn user_message(sender_id: UserId, tenant: &Tenant, encoding: Encoding, msg: Message, state: &ServerState) -> crate::Result<()> {
+    match msg {
+        Message::Text(msg) => handle_text(sender_id, tenant, &msg, state).await,
+        Message::Binary(bytes) => {
+            let message = SignalMessage::decode_binary(&bytes, encoding).map_err(|e| e.to_string());
+            handle_message(sender_id, tenant, message, state).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Handle a JSON signaling message from the user, regardless of the transport it came from
+pub(crate) async fn handle_text(sender_id: UserId, tenant: &Tenant, msg: &str, state: &ServerState) -> crate::Result<()> {
+    if msg.is_empty() || msg == "ping" {
+        // warn!("empty message from user {:?}", sender_id);
+        return Ok(());
+    }
+
+    let message = SignalMessage::decode(msg).map_err(|e| format!("{e} in {msg:?}"));
+    handle_message(sender_id, tenant, message, state).await
+}
+
+/// Handle a decoded signaling message, `Err` contains the reason the message couldn't be decoded
+async fn handle_message(sender_id: UserId, tenant: &Tenant, message: Result<SignalMessage, String>, state: &ServerState) -> crate::Result<()> {
+    if !state.tenant_usage.allow_message(tenant, sender_id) {
+        warn!("message rate exceeded by user {:?}", sender_id);
+        strike(sender_id, state).await?;
+
+        if let Ok(SignalMessage::Request(request_id, _)) = message {
+            let response = SignalMessage::Nack(request_id, "Message rate exceeded".to_string());
+            state.bus.send(sender_id, Outbound::Signal(response)).await?;
+        }
+        return Ok(());
+    }
+
+    match message {
+        // answer requests with the outcome of the message they contain
+        Ok(SignalMessage::Request(request_id, request)) => {
+            // retries reuse the request id, answer them without delivering the message again
+            match state.recent_requests.start(sender_id, &request_id) {
+                Some(SeenRequest::Answered(response)) => {
+                    state.bus.send(sender_id, Outbound::Signal(response)).await?;
+                    return Ok(());
+                }
+                Some(SeenRequest::Pending) => return Ok(()),
+                None => {}
             }
+
+            let delivery = match handle_request(sender_id, tenant, *request, state).await {
+                Ok(delivery) => delivery,
+                Err(e) => {
+                    state.recent_requests.forget(sender_id, &request_id);
+                    return Err(e);
+                }
+            };
+            let response = match delivery {
+                Delivery::Delivered => SignalMessage::Ack(request_id.clone()),
+                Delivery::Rejected(reason) => SignalMessage::Nack(request_id.clone(), reason),
+            };
+            state.recent_requests.finish(sender_id, &request_id, &response);
+            state.bus.send(sender_id, Outbound::Signal(response)).await?;
+        }
+        Ok(request) => {
+            handle_request(sender_id, tenant, request, state).await?;
+        }
+        Err(error) => {
+            error!("Invalid message from user {:?}: {}", sender_id, error);
+            strike(sender_id, state).await?;
         }
     }
+
     Ok(())
 }
 
-async fn user_disconnected(user_id: UserId, connections: &Connections, sessions: &Sessions) {
-    connections.write().await.remove(&user_id);
+/// Outcome of a message, reported to users that sent it in a [`SignalMessage::Request`]
+enum Delivery {
+    Delivered,
+    /// The message wasn't handled or passed on, contains the reason
+    Rejected(String),
+}
+
+const RECIPIENT_NOT_CONNECTED: &str = "Recipient is not connected";
+const RELAY_DISABLED: &str = "Relay is disabled on this server";
+const NOT_IN_SESSION: &str = "User is not in the session";
+
+/// Largest join metadata in bytes of JSON, it's stored for the whole session and sent to every user it's connected with
+pub const MAX_METADATA_SIZE: usize = 4096;
+
+async fn handle_request(sender_id: UserId, tenant: &Tenant, mut request: SignalMessage, state: &ServerState) -> crate::Result<Delivery> {
+    info!("message received from user {:?}: {:?}", sender_id, request);
+
+    // keep the tenant's sessions in its own namespace
+    let original_session_id = request.session_id_mut().map(|session_id| session_id.clone());
+    if let Err(error) = tenant.scope_message(&mut request) {
+        warn!("rejected message from user {:?}: {}", sender_id, error);
+        let response = SignalMessage::Error(original_session_id.unwrap_or_else(|| SessionId::new(String::new())), sender_id, error.clone());
+        state.bus.send(sender_id, Outbound::Signal(response)).await?;
+        return Ok(Delivery::Rejected(error));
+    }
+
+    match request {
+        SignalMessage::Hello(hello) => {
+            let server = handshake(state);
 
-    let mut session_to_delete = None;
-    for (session_id, session) in sessions.write().await.iter_mut() {
-        if session.host == Some(user_id) {
-            session.host = None;
-        } else if session.users.contains(&user_id) {
-            session.users.remove(&user_id);
+            let Some(version) = server.negotiate(&hello) else {
+                warn!("Unsupported protocol version {} from user {:?}", hello.version, sender_id);
+                let error = format!("{UNSUPPORTED_VERSION} {}, the server supports versions {} to {}", hello.version, server.min_version, server.version);
+                state.bus.send(sender_id, Outbound::Signal(SignalMessage::Error(SessionId::new(String::new()), sender_id, error.clone()))).await?;
+                state.bus.send(sender_id, Outbound::Close(axum::extract::ws::close_code::POLICY, UNSUPPORTED_VERSION.to_string())).await?;
+                return Ok(Delivery::Rejected(error));
+            };
+
+            state.protocol_versions.set(sender_id, version);
+            let welcome = Handshake { version, ..server };
+            state.bus.send(sender_id, Outbound::Signal(SignalMessage::Welcome(sender_id, welcome))).await?;
         }
-        if session.host.is_none() && session.users.is_empty() {
-            session_to_delete = Some(session_id.clone());
-            break;
+        SignalMessage::SessionJoin(session_id, is_host, metadata) => {
+            if is_host && state.store.reservation(&session_id).await?.is_some() {
+                let error = "Session is reserved, join it with SessionClaim".to_string();
+                state.bus.send(sender_id, Outbound::Signal(SignalMessage::Error(session_id, sender_id, error.clone()))).await?;
+                return Ok(Delivery::Rejected(error));
+            }
+
+            return join_session(sender_id, session_id, is_host, metadata, tenant, state).await;
+        }
+        SignalMessage::SessionClaim(session_id, claim_token, metadata) => {
+            let valid = match state.store.reservation(&session_id).await? {
+                Some(reservation) => auth::constant_time_eq(reservation.claim_token.as_bytes(), claim_token.as_bytes()),
+                None => false,
+            };
+
+            if !valid {
+                warn!("invalid claim token for session {:?}", session_id);
+                let error = "Invalid claim token".to_string();
+                state.bus.send(sender_id, Outbound::Signal(SignalMessage::Error(session_id, sender_id, error.clone()))).await?;
+                return Ok(Delivery::Rejected(error));
+            }
+
+            // keep the reservation while the session is used
+            state.store.set_reservation_expiry(&session_id, None).await?;
+            return join_session(sender_id, session_id, true, metadata, tenant, state).await;
+        }
+        SignalMessage::SessionLeave(session_id) => {
+            let Some(removal) = state.store.leave_session(&session_id, sender_id).await? else {
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            };
+
+            // the host sends a new keep alive for the next session
+            if removal.was_host {
+                state.store.remove_ping(sender_id).await?;
+            }
+
+            user_removed(sender_id, removal, true, tenant, state).await?;
+        }
+        // pass offer to the other user in session without changing anything
+        SignalMessage::SdpOffer(session_id, recipient_id, offer) => {
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                warn!("user {:?} tried to send offer to {:?} outside of its session", sender_id, recipient_id);
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            let response = SignalMessage::SdpOffer(session_id, sender_id, offer);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to send offer to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
+        }
+        // pass answer to the other user in session without changing anything
+        SignalMessage::SdpAnswer(session_id, recipient_id, answer) => {
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                warn!("user {:?} tried to send answer to {:?} outside of its session", sender_id, recipient_id);
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            let response = SignalMessage::SdpAnswer(session_id, sender_id, answer);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to send offer to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
         }
+        SignalMessage::IceCandidate(session_id, recipient_id, candidate) => {
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                warn!("user {:?} tried to send ICE candidate to {:?} outside of its session", sender_id, recipient_id);
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            let response = SignalMessage::IceCandidate(session_id, sender_id, candidate);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to send ICE candidate to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
+        }
+        // let the other user know that its message couldn't be handled
+        SignalMessage::Error(session_id, recipient_id, error) => {
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                warn!("user {:?} tried to send error to {:?} outside of its session", sender_id, recipient_id);
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            let response = SignalMessage::Error(session_id, sender_id, error);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to send error to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
+        }
+        SignalMessage::RelayOpen(session_id, recipient_id) => {
+            if state.config.relay.is_none() {
+                let error = RELAY_DISABLED.to_string();
+                state.bus.send(sender_id, Outbound::Signal(SignalMessage::Error(session_id, sender_id, error.clone()))).await?;
+                return Ok(Delivery::Rejected(error));
+            }
+
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                warn!("user {:?} tried to open relay to {:?} outside of its session", sender_id, recipient_id);
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            increment(&state.metrics.relay.opened);
+            let response = SignalMessage::RelayOpen(session_id, sender_id);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to open relay to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
+        }
+        SignalMessage::RelayData(session_id, recipient_id, data) => {
+            let Some(relay_config) = &state.config.relay else {
+                return Ok(Delivery::Rejected(RELAY_DISABLED.to_string()));
+            };
+
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                warn!("user {:?} tried to relay data to {:?} outside of its session", sender_id, recipient_id);
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            if !state.relay_limiter.allow(sender_id, data.len() as u64, relay_config.bandwidth_limit) {
+                warn!("relay bandwidth limit exceeded by user {:?}", sender_id);
+                increment(&state.metrics.relay.dropped_messages);
+                strike(sender_id, state).await?;
+                return Ok(Delivery::Rejected("Relay bandwidth limit exceeded".to_string()));
+            }
+
+            state.metrics.relay.relayed_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            let response = SignalMessage::RelayData(session_id, sender_id, data);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to relay data to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
+        }
+        SignalMessage::RelayClose(session_id, recipient_id) => {
+            if !in_same_session(&session_id, sender_id, recipient_id, state).await? {
+                return Ok(Delivery::Rejected(NOT_IN_SESSION.to_string()));
+            }
+
+            let response = SignalMessage::RelayClose(session_id, sender_id);
+            if !state.bus.send(recipient_id, Outbound::Signal(response)).await? {
+                warn!("tried to close relay to non existing user");
+                return Ok(Delivery::Rejected(RECIPIENT_NOT_CONNECTED.to_string()));
+            }
+        }
+        SignalMessage::KeepAlive(_, status) if status.is_host.is_some() => {
+            warn!("Received ping from user {:?}", status.session_id);
+
+            // only the host of a session can announce it, the ping belongs to the sender whatever user id the message names
+            let session_id = match status.session_id {
+                Some(session_id) if is_host(&session_id, sender_id, state).await? => Some(session_id),
+                Some(session_id) => {
+                    warn!("user {:?} sent keep alive for session {:?} it doesn't host", sender_id, session_id);
+                    None
+                }
+                None => None,
+            };
+
+            // the first keep alive for the session means the host is ready
+            let previous = state.store.ping(sender_id).await?.and_then(|ping| ping.session_id);
+            if let Some(session_id) = session_id.as_ref().filter(|session_id| previous.as_ref() != Some(*session_id)) {
+                state.webhooks.send(WebhookEvent::HostOnline {
+                    session_id: session_id.clone(),
+                    user_id: sender_id,
+                    metadata: status.metadata.clone(),
+                });
+            }
+
+            let ping = Ping {
+                online: true,
+                public: session_id.is_some() && status.public.unwrap_or(false),
+                session_id,
+                metadata: status.metadata,
+                version: status.version,
+            };
+            state.store.set_ping(sender_id, ping).await?;
+        }
+        SignalMessage::Request(_, _) => return Ok(Delivery::Rejected("Requests can't be nested".to_string())),
+        _ => {}
+    }
+
+    Ok(Delivery::Delivered)
+}
+
+/// `true` if the user hosts the session
+async fn is_host(session_id: &SessionId, user_id: UserId, state: &ServerState) -> crate::Result<bool> {
+    Ok(state.store.session(session_id).await?.is_some_and(|session| session.host == Some(user_id)))
+}
+
+/// `true` if one of the users hosts the session and the other one is its client, users can only reach the other side of their session
+async fn in_same_session(session_id: &SessionId, sender_id: UserId, recipient_id: UserId, state: &ServerState) -> crate::Result<bool> {
+    let Some(session) = state.store.session(session_id).await? else {
+        return Ok(false);
+    };
+
+    Ok(match session.host {
+        Some(host_id) if host_id == sender_id => session.users.contains(&recipient_id),
+        Some(host_id) if host_id == recipient_id => session.users.contains(&sender_id),
+        _ => false,
+    })
+}
+
+/// Protocol versions and the capabilities enabled on this server
+fn handshake(state: &ServerState) -> Handshake {
+    let mut capabilities = vec![Capability::BinaryEncoding, Capability::Acknowledgements];
+    if state.config.relay.is_some() {
+        capabilities.push(Capability::Relay);
+    }
+
+    Handshake::new(capabilities)
+}
+
+/// Count an abusive message against the user's address and disconnect everyone from it once it's banned
+async fn strike(sender_id: UserId, state: &ServerState) -> crate::Result<()> {
+    let banned_users = state.firewall.strike(sender_id);
+    if banned_users.is_empty() {
+        return Ok(());
     }
-    // remove session if it's empty
-    if let Some(session_id) = session_to_delete {
-        sessions.write().await.remove(&session_id);
+
+    increment(&state.metrics.firewall.bans);
+    for user_id in banned_users {
+        state.bus.send(user_id, Outbound::Close(axum::extract::ws::close_code::POLICY, "Banned".to_string())).await?;
     }
+
+    Ok(())
+}
+
+/// Add the user to the session and connect it with the host or the waiting users, passing on the metadata they joined with
+async fn join_session(sender_id: UserId, session_id: SessionId, is_host: bool, metadata: Option<serde_json::Value>, tenant: &Tenant, state: &ServerState) -> crate::Result<Delivery> {
+    if metadata.as_ref().is_some_and(|metadata| metadata.to_string().len() > MAX_METADATA_SIZE) {
+        warn!("metadata of user {:?} is too large", sender_id);
+        let error = format!("Metadata is larger than {MAX_METADATA_SIZE} bytes");
+        state.bus.send(sender_id, Outbound::Signal(SignalMessage::Error(session_id, sender_id, error.clone()))).await?;
+        return Ok(Delivery::Rejected(error));
+    }
+
+    if !state.tenant_usage.add_session(tenant, &session_id, state.store.as_ref()).await? {
+        warn!("Tenant {:?} reached its session limit", tenant.name());
+        let error = "Session limit reached".to_string();
+        state.bus.send(sender_id, Outbound::Signal(SignalMessage::Error(session_id, sender_id, error.clone()))).await?;
+        return Ok(Delivery::Rejected(error));
+    }
+
+    // advertise the ICE configuration before any negotiation starts
+    if let Some(ice_config) = &state.config.ice {
+        let response = SignalMessage::IceConfig(session_id.clone(), ice_config.clone());
+        state.bus.send(sender_id, Outbound::Signal(response)).await?;
+    }
+
+    if is_host {
+        match state.store.claim_host(&session_id, sender_id).await? {
+            Some(users) => {
+                if let Some(metadata) = &metadata {
+                    state.store.set_metadata(&session_id, sender_id, metadata).await?;
+                }
+
+                // start connections with all already present users
+                for client_id in users {
+                    // clients only hear about the host if it has metadata
+                    if metadata.is_some() {
+                        let client_response = SignalMessage::SessionReady(session_id.clone(), sender_id, metadata.clone());
+                        state.bus.send(client_id, Outbound::Signal(client_response)).await?;
+                    }
+
+                    let client_metadata = state.store.metadata(&session_id, client_id).await?;
+                    let host_response = SignalMessage::SessionReady(session_id.clone(), client_id, client_metadata);
+                    state.bus.send(sender_id, Outbound::Signal(host_response)).await?;
+                }
+            }
+            None => {
+                warn!("connecting user wants to be a host, but host is already present, closing connection soon");
+
+                let bus = state.bus.clone();
+
+                tokio::task::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                    if let Err(e) = bus.send(sender_id, Outbound::Close(3001, "Multiple hosts".to_string())).await {
+                        error!("failed to send close message to host: {}", e);
+                    }
+                });
+
+                return Ok(Delivery::Rejected("Session already has a host".to_string()));
+            }
+        }
+    } else {
+        // store the metadata first, a host claiming the session at the same time reads it
+        if let Some(metadata) = &metadata {
+            state.store.set_metadata(&session_id, sender_id, metadata).await?;
+        }
+
+        // connect new user with host
+        let host_id = state.store.add_user(&session_id, sender_id).await?;
+        state.webhooks.send(WebhookEvent::ClientJoined { session_id: session_id.clone(), user_id: sender_id });
+
+        if let Some(host_id) = host_id {
+            if let Some(host_metadata) = state.store.metadata(&session_id, host_id).await? {
+                let client_response = SignalMessage::SessionReady(session_id.clone(), host_id, Some(host_metadata));
+                state.bus.send(sender_id, Outbound::Signal(client_response)).await?;
+            }
+
+            let host_response = SignalMessage::SessionReady(session_id.clone(), sender_id, metadata);
+            state.bus.send(host_id, Outbound::Signal(host_response)).await?;
+        }
+    }
+
+    Ok(Delivery::Delivered)
+}
+
+pub(crate) async fn user_disconnected(user_id: UserId, tenant: &Tenant, state: &ServerState) -> crate::Result<()> {
+    state.tenant_usage.disconnect(tenant, user_id);
+    state.bus.unregister(user_id).await?;
+    state.relay_limiter.remove(&user_id);
+    state.firewall.forget(user_id);
+    state.protocol_versions.remove(user_id);
+    state.recent_requests.remove(user_id);
+    state.store.remove_ping(user_id).await?;
+
+    for removal in state.store.remove_user(user_id).await? {
+        user_removed(user_id, removal, false, tenant, state).await?;
+    }
+
+    Ok(())
+}
+
+/// Report that the user left a session, with `SessionLeave` if `left`, or disconnected, cleaning up the session if it's empty now
+async fn user_removed(user_id: UserId, removal: Removal, left: bool, tenant: &Tenant, state: &ServerState) -> crate::Result<()> {
+    let session_id = removal.session_id.clone();
+
+    // the host closes the connection with a client that left, clients close theirs once the host is gone
+    if let Some(session) = state.store.session(&session_id).await? {
+        let recipients: Vec<_> = match removal.was_host {
+            true => session.users.into_iter().collect(),
+            false => session.host.into_iter().collect(),
+        };
+        for recipient_id in recipients {
+            let message = SignalMessage::SessionLeft(session_id.clone(), user_id);
+            state.bus.send(recipient_id, Outbound::Signal(message)).await?;
+        }
+    }
+
+    if removal.was_host && left {
+        state.webhooks.send(WebhookEvent::HostLeft { session_id, user_id });
+    } else if removal.was_host {
+        state.webhooks.send(WebhookEvent::HostOffline { session_id, user_id });
+    } else {
+        state.webhooks.send(WebhookEvent::ClientLeft { session_id, user_id });
+    }
+
+    if removal.deleted {
+        // reservations of empty sessions expire like unclaimed ones
+        state.store.set_reservation_expiry(&removal.session_id, Some(state.config.reservation_duration())).await?;
+        state.tenant_usage.remove_session(tenant, &removal.session_id);
+        state.webhooks.send(WebhookEvent::SessionDeleted { session_id: removal.session_id });
+    }
+
+    Ok(())
 }
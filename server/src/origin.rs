@@ -0,0 +1,75 @@
+use crate::config::Config;
+use crate::metrics::increment;
+use crate::router::ServerState;
+use crate::tenant::{self, Tenant};
+use axum::extract::{Request, State};
+use axum::http::header::{ORIGIN, UPGRADE};
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+
+/// `true` if the origin matches the allowlist of the request's tenant, or the server's allowlist otherwise.
+/// Everything is allowed if neither is configured.
+pub fn is_allowed(config: &Config, headers: &HeaderMap, uri: &Uri, origin: &str) -> bool {
+    // unknown app keys are rejected later, check them against the server's allowlist
+    let tenant = Tenant::resolve(config, tenant::app_key(headers, uri).as_deref());
+    let allowed_origins = tenant.as_ref().and_then(Tenant::allowed_origins).or(config.allowed_origins.as_deref());
+
+    match allowed_origins {
+        Some(allowed_origins) => allowed_origins.iter().any(|pattern| matches(pattern, origin)),
+        None => true,
+    }
+}
+
+/// Preflights can't send the `X-App-Key` header, so without an `app_key` query parameter they're allowed for the origins of every tenant.
+/// The request that follows carries the key and is checked against its tenant's allowlist
+pub fn is_allowed_preflight(config: &Config, headers: &HeaderMap, uri: &Uri, origin: &str) -> bool {
+    if is_allowed(config, headers, uri, origin) {
+        return true;
+    }
+
+    tenant::app_key(headers, uri).is_none()
+        && config
+            .tenants
+            .iter()
+            .filter_map(|tenant| tenant.allowed_origins.as_ref())
+            .any(|allowed_origins| allowed_origins.iter().any(|pattern| matches(pattern, origin)))
+}
+
+/// Patterns are exact origins, `*`, or contain a subdomain wildcard like `https://*.example.com`
+fn matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+
+    match pattern.split_once("*.") {
+        Some((scheme, domain)) => origin
+            .strip_prefix(scheme)
+            .and_then(|host| host.strip_suffix(domain))
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => false,
+    }
+}
+
+/// Reject requests and websocket upgrades from browsers on origins that aren't allowed,
+/// requests without an `Origin` header come from other apps and are always allowed
+pub async fn guard(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    let origin = request.headers().get(ORIGIN).map(|origin| origin.to_str().unwrap_or_default().to_string());
+
+    if let Some(origin) = origin {
+        if !is_allowed(&state.config, request.headers(), request.uri(), &origin) {
+            if request.headers().contains_key(UPGRADE) {
+                warn!("Rejected websocket upgrade from origin {:?}", origin);
+                increment(&state.metrics.origins.rejected_upgrades);
+            } else {
+                warn!("Rejected request from origin {:?}", origin);
+                increment(&state.metrics.origins.rejected_requests);
+            }
+
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,127 @@
+use crate::one_to_many;
+use crate::router::ServerState;
+use crate::store::Outbound;
+use crate::tenant::Tenant;
+use ezrtc::protocol::{PollSession, UserId};
+use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+
+/// Users are disconnected if they don't poll for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Longest time a poll request waits for messages
+pub const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// User connected over HTTP long polling instead of a websocket
+pub struct PollConnection {
+    pub user_id: UserId,
+    pub tenant: Tenant,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Outbound>>,
+    last_seen: Mutex<Instant>,
+    closed: Notify,
+}
+
+pub type PollConnections = Arc<Mutex<HashMap<String, Arc<PollConnection>>>>;
+
+/// Create a long polling connection, it stays registered until it is closed or idle for too long,
+/// returns `None` if the tenant has too many users
+pub async fn connect(state: &ServerState, tenant: Tenant, address: Option<IpAddr>) -> crate::Result<Option<PollSession>> {
+    let user_id = state.store.next_user_id().await?;
+    if !state.tenant_usage.connect(&tenant) {
+        warn!("Too many users connected to tenant {:?}", tenant.name());
+        return Ok(None);
+    }
+
+    let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    info!("new user connected over long polling: {:?}", user_id);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let connection = Arc::new(PollConnection {
+        user_id,
+        tenant,
+        rx: tokio::sync::Mutex::new(rx),
+        last_seen: Mutex::new(Instant::now()),
+        closed: Notify::new(),
+    });
+
+    state.firewall.track(user_id, address);
+    state.bus.register(user_id, tx.clone()).await?;
+    state.poll_connections.lock().unwrap().insert(token.clone(), connection.clone());
+
+    let state2 = state.clone();
+    let token2 = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = one_to_many::keep_alive(user_id, tx, state2.clone()) => info!("Ping task stopped"),
+            _ = idle(&connection) => info!("Long polling user stopped polling: {:?}", user_id),
+            _ = connection.closed.notified() => info!("Long polling connection closed: {:?}", user_id),
+        }
+
+        state2.poll_connections.lock().unwrap().remove(&token2);
+        error!("User disconnected: {:?}", user_id);
+        if let Err(e) = one_to_many::user_disconnected(user_id, &connection.tenant, &state2).await {
+            error!("error while removing user: {}", e);
+        }
+    });
+
+    Ok(Some(PollSession { token, user_id }))
+}
+
+async fn idle(connection: &PollConnection) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        if connection.last_seen.lock().unwrap().elapsed() > IDLE_TIMEOUT {
+            return;
+        }
+    }
+}
+
+pub fn find(state: &ServerState, token: &str) -> Option<Arc<PollConnection>> {
+    let connection = state.poll_connections.lock().unwrap().get(token).cloned()?;
+    *connection.last_seen.lock().unwrap() = Instant::now();
+
+    Some(connection)
+}
+
+/// Wait up to `timeout` for messages encoded for the user's protocol version, returns `None` if the server closed the connection
+pub async fn receive(state: &ServerState, connection: &PollConnection, timeout: Duration) -> Option<Vec<serde_json::Value>> {
+    let mut rx = connection.rx.lock().await;
+    let mut messages = Vec::new();
+
+    let first = match tokio::time::timeout(timeout.min(MAX_POLL_TIMEOUT), rx.recv()).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return None,
+        Err(_) => return Some(messages),
+    };
+
+    // return everything that is already queued in one response
+    let mut next = Some(first);
+    while let Some(message) = next {
+        match connection.tenant.unscope_outbound(message) {
+            Outbound::Signal(message) => match message.to_value(state.protocol_versions.get(connection.user_id)) {
+                Ok(message) => messages.push(message),
+                Err(e) => error!("Failed to encode message: {}", e),
+            },
+            Outbound::Close(code, reason) => {
+                info!("Closing long polling connection {:?}: {} {}", connection.user_id, code, reason);
+                connection.closed.notify_one();
+                return None;
+            }
+        }
+        next = rx.try_recv().ok();
+    }
+
+    *connection.last_seen.lock().unwrap() = Instant::now();
+    Some(messages)
+}
+
+pub fn close(connection: &PollConnection) {
+    connection.closed.notify_one();
+}
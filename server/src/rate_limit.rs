@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counts what each key used in the current window, windows last a second by default
+pub struct RateLimiter<K> {
+    period: Duration,
+    windows: Mutex<HashMap<K, (Instant, u64)>>,
+}
+
+impl<K> Default for RateLimiter<K> {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1))
+    }
+}
+
+impl<K> RateLimiter<K> {
+    pub fn new(period: Duration) -> Self {
+        Self { period, windows: Mutex::default() }
+    }
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    /// Count `amount` for the key, returns `false` without counting it if that would exceed `limit`
+    pub fn allow(&self, key: K, amount: u64, limit: u64) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(key).or_insert_with(|| (Instant::now(), 0));
+
+        if window.0.elapsed() >= self.period {
+            *window = (Instant::now(), 0);
+        }
+
+        if window.1 + amount > limit {
+            return false;
+        }
+
+        window.1 += amount;
+        true
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.windows.lock().unwrap().remove(key);
+    }
+
+    /// Drop the windows that ended, for keys that aren't removed when they go away
+    pub fn purge(&self) {
+        self.windows.lock().unwrap().retain(|_, (start, _)| start.elapsed() < self.period);
+    }
+}
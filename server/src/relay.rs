@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Bytes per second each user can relay through the signaling server
+    pub bandwidth_limit: u64,
+}
@@ -0,0 +1,67 @@
+use ezrtc::protocol::{SignalMessage, UserId};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Requests remembered per user, clients retry a few times right after sending so the last ones are enough
+const REMEMBERED_REQUESTS: usize = 32;
+
+/// What happened to a request the user sent before
+pub enum SeenRequest {
+    /// The request is still being handled, its answer is sent once it's done
+    Pending,
+    /// The request was handled, the answer is sent again
+    Answered(SignalMessage),
+}
+
+/// Ids of the last requests of a user with their answer, `None` while they're handled
+type RequestLog = VecDeque<(String, Option<SignalMessage>)>;
+
+/// Last requests of each user connected to this server instance, so retries aren't delivered twice
+#[derive(Default)]
+pub struct RecentRequests {
+    requests: Mutex<HashMap<UserId, RequestLog>>,
+}
+
+impl RecentRequests {
+    /// Remember the request, returns what happened to it if the user sent it before
+    pub fn start(&self, user_id: UserId, request_id: &str) -> Option<SeenRequest> {
+        let mut requests = self.requests.lock().unwrap();
+        let requests = requests.entry(user_id).or_default();
+
+        if let Some((_, response)) = requests.iter().find(|(id, _)| id == request_id) {
+            return Some(match response {
+                Some(response) => SeenRequest::Answered(response.clone()),
+                None => SeenRequest::Pending,
+            });
+        }
+
+        if requests.len() == REMEMBERED_REQUESTS {
+            requests.pop_front();
+        }
+        requests.push_back((request_id.to_string(), None));
+        None
+    }
+
+    /// Remember the answer to a request, to send it again when the request is retried
+    pub fn finish(&self, user_id: UserId, request_id: &str, response: &SignalMessage) {
+        let mut requests = self.requests.lock().unwrap();
+        let Some(requests) = requests.get_mut(&user_id) else {
+            return;
+        };
+
+        if let Some((_, answer)) = requests.iter_mut().find(|(id, _)| id == request_id) {
+            *answer = Some(response.clone());
+        }
+    }
+
+    /// Forget a request that failed without an answer, so a retry is handled again
+    pub fn forget(&self, user_id: UserId, request_id: &str) {
+        if let Some(requests) = self.requests.lock().unwrap().get_mut(&user_id) {
+            requests.retain(|(id, _)| id != request_id);
+        }
+    }
+
+    pub fn remove(&self, user_id: UserId) {
+        self.requests.lock().unwrap().remove(&user_id);
+    }
+}
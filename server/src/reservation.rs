@@ -0,0 +1,67 @@
+use crate::router::ServerState;
+use crate::store::Reservation;
+use crate::tenant::Tenant;
+use anyhow::anyhow;
+use ezrtc::protocol::{SessionId, SessionReservation};
+use rand::distributions::{Alphanumeric, Slice};
+use rand::Rng;
+
+/// Join code characters, without the easily confused `0`, `O`, `1` and `I`
+const CODE_ALPHABET: &[char] = &[
+    'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'J', 'K', 'L', 'M', 'N', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+const CODE_LENGTH: usize = 6;
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+fn random_code() -> String {
+    let alphabet = Slice::new(CODE_ALPHABET).expect("code alphabet is not empty");
+    rand::thread_rng().sample_iter(alphabet).take(CODE_LENGTH).collect()
+}
+
+/// Codes are case insensitive and can be written with separators, e.g. `abc-def`
+pub fn normalize_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+/// Reserve a random session id and join code for a host, codes are only valid within the tenant
+pub async fn reserve(state: &ServerState, tenant: &Tenant) -> crate::Result<SessionReservation> {
+    let ttl = state.config.reservation_duration();
+
+    // codes are short, try again if one is already in use
+    for _ in 0..5 {
+        let session_id = SessionId::new(random_string(32));
+        let code = random_code();
+        let reservation = Reservation {
+            session_id: tenant.scope(&session_id),
+            code: scope_code(tenant, &code),
+            claim_token: random_string(32),
+        };
+
+        if state.store.reserve(&reservation, ttl).await? {
+            return Ok(SessionReservation {
+                session_id,
+                code,
+                claim_token: reservation.claim_token,
+                expires_in: ttl.as_secs(),
+            });
+        }
+    }
+
+    Err(anyhow!("failed to find an unused join code"))
+}
+
+pub async fn resolve(state: &ServerState, tenant: &Tenant, code: &str) -> crate::Result<Option<SessionId>> {
+    let session_id = state.store.resolve_code(&scope_code(tenant, &normalize_code(code))).await?;
+
+    Ok(session_id.map(|session_id| tenant.unscope(&session_id)))
+}
+
+fn scope_code(tenant: &Tenant, code: &str) -> String {
+    match tenant.name() {
+        "" => code.to_string(),
+        name => format!("{name}:{code}"),
+    }
+}
@@ -1,19 +1,84 @@
-use axum::extract::{Path, State, WebSocketUpgrade};
-use axum::http::Method;
-use axum::response::Response;
-use axum::routing::get;
-use axum::{Json, Router};
-use ezrtc::protocol::SessionId;
+use axum::extract::{Path, Query, State, WebSocketUpgrade};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, HeaderName, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, patch, post};
+use axum::{middleware, Json, Router};
+use ezrtc::protocol::{Encoding, PollSession, SessionId, SessionReservation, SignalMessage, TurnCredentials, UserId};
+use log::error;
 use serde::{Deserialize, Serialize};
-use tower_http::cors::{Any, CorsLayer};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
-use crate::one_to_many;
+use crate::config::Config;
+use crate::firewall::{ClientIp, Firewall, FirewallLists, FirewallStatus};
+use crate::lobby::LobbyPage;
+use crate::metrics::Metrics;
+use crate::poll::PollConnections;
+use crate::rate_limit::RateLimiter;
+use crate::request::RecentRequests;
+use crate::store::{MemoryBus, MemoryStore, MessageBus, Outbound, SessionStore};
+use crate::tenant::{Tenant, TenantUsage};
+use crate::version::ProtocolVersions;
+use crate::webhook::Webhooks;
+use crate::whep::WhepResources;
+use crate::{auth, firewall, lobby, one_to_many, origin, poll, reservation, turn, whep};
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct ServerState {
-    one_to_many_connections: one_to_many::Connections,
-    one_to_many_sessions: one_to_many::Sessions,
-    one_to_many_pings: one_to_many::Pings,
+    pub(crate) config: Arc<Config>,
+    pub(crate) store: Arc<dyn SessionStore>,
+    pub(crate) bus: Arc<dyn MessageBus>,
+    pub(crate) metrics: Arc<Metrics>,
+    /// Bytes each user relayed in the current second
+    pub(crate) relay_limiter: Arc<RateLimiter<UserId>>,
+    pub(crate) poll_connections: PollConnections,
+    pub(crate) whep_resources: WhepResources,
+    pub(crate) webhooks: Arc<Webhooks>,
+    pub(crate) tenant_usage: Arc<TenantUsage>,
+    pub(crate) firewall: Arc<Firewall>,
+    pub(crate) protocol_versions: Arc<ProtocolVersions>,
+    pub(crate) recent_requests: Arc<RecentRequests>,
+}
+
+impl ServerState {
+    pub fn new(config: Config, store: Arc<dyn SessionStore>, bus: Arc<dyn MessageBus>) -> Self {
+        let metrics: Arc<Metrics> = Arc::default();
+        let webhooks = Arc::new(Webhooks::new(config.webhooks.clone(), metrics.clone()));
+        let firewall = Arc::new(Firewall::new(config.firewall.clone()));
+
+        Self {
+            config: Arc::new(config),
+            store,
+            bus,
+            metrics,
+            relay_limiter: Arc::default(),
+            poll_connections: PollConnections::default(),
+            whep_resources: WhepResources::default(),
+            webhooks,
+            tenant_usage: Arc::default(),
+            firewall,
+            protocol_versions: Arc::default(),
+            recent_requests: Arc::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// In memory state for a single server instance
+    pub fn with_config(config: Config) -> Self {
+        Self::new(config, Arc::new(MemoryStore::default()), Arc::new(MemoryBus::default()))
+    }
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::with_config(Config::default())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,6 +93,44 @@ struct StatusMessage {
     metadata: Option<serde_json::Value>,
 }
 
+#[derive(Deserialize)]
+struct TurnQuery {
+    /// Session the credentials are for, its users share the TURN bandwidth limit
+    session: String,
+}
+
+#[derive(Deserialize)]
+struct PollQuery {
+    /// Seconds to wait for messages
+    timeout: Option<u64>,
+}
+
+/// Who receives a notification
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum NotifyTarget {
+    #[default]
+    Host,
+    All,
+}
+
+#[derive(Deserialize)]
+struct NotifyQuery {
+    #[serde(default)]
+    target: NotifyTarget,
+}
+
+#[derive(Serialize)]
+struct NotifyResponse {
+    /// Number of users the notification was delivered to
+    delivered: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JoinCodeMessage {
+    session_id: SessionId,
+}
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 async fn root() -> Json<RootMessage> {
@@ -43,17 +146,180 @@ async fn health_handler() -> &'static str {
 }
 
 #[allow(clippy::unused_async)]
-async fn one_to_many_handler(State(state): State<ServerState>, ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(move |socket| one_to_many::user_connected(socket, state.one_to_many_connections, state.one_to_many_sessions, state.one_to_many_pings))
+async fn one_to_many_handler(State(state): State<ServerState>, tenant: Tenant, ClientIp(address): ClientIp, ws: WebSocketUpgrade) -> Response {
+    ws.protocols(Encoding::SUBPROTOCOLS)
+        .on_upgrade(move |socket| one_to_many::user_connected(socket, tenant, address, state))
 }
 
-async fn status_handler(Path(session_id): Path<String>, State(state): State<ServerState>) -> Json<StatusMessage> {
-    let pings = state.one_to_many_pings.lock().unwrap().clone();
+/// Validate the session id from the path and move it into the tenant's namespace
+fn scoped_session_id(tenant: &Tenant, session_id: String) -> Result<SessionId, StatusCode> {
+    let session_id = SessionId::new(session_id);
 
-    // iterate over all pings and return that matches the session_id from path
-    let ping = pings
-        .iter()
-        .find_map(|(_k, v)| if v.session_id == Some(SessionId::new(session_id.clone())) { Some(v.clone()) } else { None });
+    match session_id.validate() {
+        Ok(_) => Ok(tenant.scope(&session_id)),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+async fn poll_connect_handler(State(state): State<ServerState>, tenant: Tenant, ClientIp(address): ClientIp) -> Result<Json<PollSession>, StatusCode> {
+    match poll::connect(&state, tenant, address).await {
+        Ok(Some(session)) => Ok(Json(session)),
+        Ok(None) => Err(StatusCode::TOO_MANY_REQUESTS),
+        Err(e) => {
+            error!("Failed to create long polling connection: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn poll_receive_handler(Path(token): Path<String>, Query(query): Query<PollQuery>, State(state): State<ServerState>) -> Result<Json<Vec<serde_json::Value>>, StatusCode> {
+    let connection = poll::find(&state, &token).ok_or(StatusCode::NOT_FOUND)?;
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(30));
+
+    match poll::receive(&state, &connection, timeout).await {
+        Some(messages) => Ok(Json(messages)),
+        None => Err(StatusCode::GONE),
+    }
+}
+
+async fn poll_send_handler(Path(token): Path<String>, State(state): State<ServerState>, body: String) -> StatusCode {
+    let Some(connection) = poll::find(&state, &token) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    match one_to_many::handle_text(connection.user_id, &connection.tenant, &body, &state).await {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(err) => {
+            error!("error while handling user message: {}", err);
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+async fn poll_close_handler(Path(token): Path<String>, State(state): State<ServerState>) -> StatusCode {
+    match poll::find(&state, &token) {
+        Some(connection) => {
+            poll::close(&connection);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+fn has_content_type(headers: &HeaderMap, expected: &str) -> bool {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    content_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(expected)
+}
+
+async fn whep_offer_handler(Path(session_id): Path<String>, State(state): State<ServerState>, tenant: Tenant, headers: HeaderMap, body: String) -> Response {
+    if !has_content_type(&headers, "application/sdp") {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+
+    let scoped_id = match scoped_session_id(&tenant, session_id.clone()) {
+        Ok(scoped_id) => scoped_id,
+        Err(status) => return status.into_response(),
+    };
+
+    match whep::offer(&state, tenant, scoped_id, body).await {
+        Ok(answer) => {
+            let location = format!("/whep/{}/{}", session_id, answer.resource_id);
+            (StatusCode::CREATED, [(CONTENT_TYPE, "application/sdp".to_string()), (LOCATION, location)], answer.sdp).into_response()
+        }
+        Err(status) => status.into_response(),
+    }
+}
+
+async fn whep_trickle_handler(Path((session_id, resource_id)): Path<(String, String)>, State(state): State<ServerState>, tenant: Tenant, headers: HeaderMap, body: String) -> StatusCode {
+    if !has_content_type(&headers, "application/trickle-ice-sdpfrag") {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    }
+
+    let Some(resource) = whep::find(&state, &tenant.scope(&SessionId::new(session_id)), &resource_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    match whep::trickle(&state, &resource, &body).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(e) => {
+            error!("Failed to forward ICE candidates: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn whep_delete_handler(Path((session_id, resource_id)): Path<(String, String)>, State(state): State<ServerState>, tenant: Tenant) -> StatusCode {
+    match whep::find(&state, &tenant.scope(&SessionId::new(session_id)), &resource_id) {
+        Some(resource) => {
+            whep::close(&resource);
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn notify_handler(
+    Path(session_id): Path<String>,
+    Query(query): Query<NotifyQuery>,
+    State(state): State<ServerState>,
+    tenant: Tenant,
+    headers: HeaderMap,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<Json<NotifyResponse>, StatusCode> {
+    auth::authorize(&headers, &state.config)?;
+
+    let session_id = scoped_session_id(&tenant, session_id)?;
+    let session = match state.store.session(&session_id).await {
+        Ok(session) => session.ok_or(StatusCode::NOT_FOUND)?,
+        Err(e) => {
+            error!("Failed to read session: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let recipients: Vec<UserId> = match query.target {
+        NotifyTarget::Host => session.host.into_iter().collect(),
+        NotifyTarget::All => session.host.into_iter().chain(session.users).collect(),
+    };
+
+    if recipients.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut delivered = 0;
+    for user_id in recipients {
+        let message = SignalMessage::Notify(session_id.clone(), payload.clone());
+        match state.bus.send(user_id, Outbound::Signal(message)).await {
+            Ok(true) => delivered += 1,
+            Ok(false) => {}
+            Err(e) => error!("Failed to notify user {:?}: {}", user_id, e),
+        }
+    }
+
+    Ok(Json(NotifyResponse { delivered }))
+}
+
+async fn metrics_handler(State(state): State<ServerState>, headers: HeaderMap) -> Result<Json<serde_json::Value>, StatusCode> {
+    if !state.config.public_metrics {
+        auth::authorize(&headers, &state.config)?;
+    }
+
+    Ok(Json(serde_json::to_value(&*state.metrics).unwrap_or_default()))
+}
+
+async fn status_handler(Path(session_id): Path<String>, State(state): State<ServerState>, tenant: Tenant) -> Json<StatusMessage> {
+    let Ok(session_id) = scoped_session_id(&tenant, session_id) else {
+        return Json(StatusMessage { online: false, metadata: None });
+    };
+
+    // find the ping that matches the session_id from path
+    let ping = match state.store.find_ping(&session_id).await {
+        Ok(ping) => ping,
+        Err(e) => {
+            error!("Failed to read status: {}", e);
+            None
+        }
+    };
 
     match ping {
         Some(ping) => Json(StatusMessage { online: ping.online, metadata: ping.metadata.clone() }),
@@ -61,12 +327,166 @@ async fn status_handler(Path(session_id): Path<String>, State(state): State<Serv
     }
 }
 
+async fn sessions_handler(Query(query): Query<HashMap<String, String>>, State(state): State<ServerState>, tenant: Tenant) -> Result<Json<LobbyPage>, StatusCode> {
+    match lobby::list(&state, &tenant, &query).await {
+        Ok(page) => Ok(Json(page)),
+        Err(e) => {
+            error!("Failed to list sessions: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Reservations hold store entries until they expire, so each address can only make a few unless the backend reserves with the API token
+async fn reserve_session_handler(State(state): State<ServerState>, headers: HeaderMap, tenant: Tenant, ClientIp(address): ClientIp) -> Result<Json<SessionReservation>, StatusCode> {
+    // servers with tenants only reserve for their apps
+    if !state.config.tenants.is_empty() && tenant.name().is_empty() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if auth::authorize(&headers, &state.config).is_err() && !state.tenant_usage.allow_reservation(&tenant, address) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    match reservation::reserve(&state, &tenant).await {
+        Ok(reservation) => Ok(Json(reservation)),
+        Err(e) => {
+            error!("Failed to reserve session: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn join_code_handler(Path(code): Path<String>, State(state): State<ServerState>, tenant: Tenant) -> Result<Json<JoinCodeMessage>, StatusCode> {
+    match reservation::resolve(&state, &tenant, &code).await {
+        Ok(Some(session_id)) => Ok(Json(JoinCodeMessage { session_id })),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to resolve join code: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn turn_credentials_handler(Query(query): Query<TurnQuery>, State(state): State<ServerState>, headers: HeaderMap, tenant: Tenant) -> Result<Json<TurnCredentials>, StatusCode> {
+    let Some(turn_config) = &state.config.turn else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    // servers with tenants only relay for their apps
+    if !state.config.tenants.is_empty() && tenant.name().is_empty() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let session_id = scoped_session_id(&tenant, query.session)?;
+
+    // the relay is only for sessions with a connected host, backends can request credentials for any session
+    if auth::authorize(&headers, &state.config).is_err() {
+        match state.store.session(&session_id).await {
+            Ok(Some(session)) if session.host.is_some() => {}
+            Ok(_) => return Err(StatusCode::FORBIDDEN),
+            Err(e) => {
+                error!("Failed to read session: {}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    match turn::credentials(turn_config, &session_id) {
+        Ok(credentials) => Ok(Json(credentials)),
+        Err(e) => {
+            error!("Failed to create TURN credentials: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn firewall_handler(State(state): State<ServerState>, headers: HeaderMap) -> Result<Json<FirewallStatus>, StatusCode> {
+    auth::authorize(&headers, &state.config)?;
+
+    Ok(Json(state.firewall.status()))
+}
+
+async fn firewall_update_handler(State(state): State<ServerState>, headers: HeaderMap, Json(lists): Json<FirewallLists>) -> Result<Json<FirewallStatus>, StatusCode> {
+    auth::authorize(&headers, &state.config)?;
+
+    if let Err(e) = state.firewall.reload(lists) {
+        error!("Rejected firewall lists: {}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(Json(state.firewall.status()))
+}
+
+/// Read the lists from the config file again
+async fn firewall_reload_handler(State(state): State<ServerState>, headers: HeaderMap) -> Result<Json<FirewallStatus>, StatusCode> {
+    auth::authorize(&headers, &state.config)?;
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to read config: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let lists = FirewallLists {
+        allow: config.firewall.allow,
+        deny: config.firewall.deny,
+    };
+    if let Err(e) = state.firewall.reload(lists) {
+        error!("Rejected firewall lists: {}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(Json(state.firewall.status()))
+}
+
+async fn unban_handler(Path(address): Path<String>, State(state): State<ServerState>, headers: HeaderMap) -> StatusCode {
+    if let Err(status) = auth::authorize(&headers, &state.config) {
+        return status;
+    }
+
+    match address.parse() {
+        Ok(address) if state.firewall.unban(address) => StatusCode::NO_CONTENT,
+        Ok(_) => StatusCode::NOT_FOUND,
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
+
 pub fn create(server_state: ServerState) -> Router {
+    let config = server_state.config.clone();
+    let allow_origin = AllowOrigin::predicate(move |value, parts| {
+        value.to_str().is_ok_and(|value| match parts.method {
+            Method::OPTIONS => origin::is_allowed_preflight(&config, &parts.headers, &parts.uri, value),
+            _ => origin::is_allowed(&config, &parts.headers, &parts.uri, value),
+        })
+    });
+
     Router::new()
         .route("/health", get(health_handler))
         .route("/", get(root))
         .route("/one-to-many", get(one_to_many_handler))
         .route("/status/:id", get(status_handler))
-        .layer(CorsLayer::new().allow_methods([Method::GET]).allow_origin(Any))
+        .route("/turn-credentials", get(turn_credentials_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/poll", post(poll_connect_handler))
+        .route("/poll/:token", get(poll_receive_handler).post(poll_send_handler).delete(poll_close_handler))
+        .route("/sessions", get(sessions_handler).post(reserve_session_handler))
+        .route("/join/:code", get(join_code_handler))
+        .route("/sessions/:id/notify", post(notify_handler))
+        .route("/whep/:id", post(whep_offer_handler))
+        .route("/whep/:id/:resource", patch(whep_trickle_handler).delete(whep_delete_handler))
+        .route("/admin/firewall", get(firewall_handler).put(firewall_update_handler))
+        .route("/admin/firewall/reload", post(firewall_reload_handler))
+        .route("/admin/firewall/bans/:address", delete(unban_handler))
+        .layer(middleware::from_fn_with_state(server_state.clone(), origin::guard))
+        .layer(middleware::from_fn_with_state(server_state.clone(), firewall::guard))
+        .layer(
+            CorsLayer::new()
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+                .allow_headers([AUTHORIZATION, CONTENT_TYPE, HeaderName::from_static("x-app-key")])
+                .expose_headers([LOCATION])
+                .allow_origin(allow_origin),
+        )
         .with_state(server_state)
 }
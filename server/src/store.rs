@@ -0,0 +1,143 @@
+use async_trait::async_trait;
+use axum::extract::ws::{CloseFrame, Message};
+use ezrtc::protocol::{Encoding, SessionId, SignalMessage, UserId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+mod memory;
+#[cfg(feature = "redis")]
+mod redis;
+
+#[cfg(feature = "redis")]
+pub use self::redis::{RedisBus, RedisStore};
+pub use memory::{MemoryBus, MemoryStore};
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub host: Option<UserId>,
+    pub users: HashSet<UserId>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Ping {
+    pub online: bool,
+    pub session_id: Option<SessionId>,
+    pub metadata: Option<serde_json::Value>,
+    /// Listed in the lobby at `GET /sessions`
+    #[serde(default)]
+    pub public: bool,
+    /// Version reported by the host
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// Session id reserved for a host with `POST /sessions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reservation {
+    pub session_id: SessionId,
+    pub code: String,
+    pub claim_token: String,
+}
+
+/// Name of the tenant that scoped the session id, `""` if it wasn't scoped
+pub fn namespace(session_id: &SessionId) -> &str {
+    session_id.as_str().split_once(':').map(|(namespace, _)| namespace).unwrap_or_default()
+}
+
+/// Session a user was removed from
+#[derive(Debug, Clone)]
+pub struct Removal {
+    pub session_id: SessionId,
+    pub was_host: bool,
+    /// The session became empty and was deleted
+    pub deleted: bool,
+}
+
+/// Message queued for a connected user, encoded when it reaches the user's websocket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Outbound {
+    Signal(SignalMessage),
+    Close(u16, String),
+}
+
+impl Outbound {
+    /// Websocket message in the wire format of protocol `version` and the connection's encoding
+    pub fn into_message(self, version: u32, encoding: Encoding) -> crate::Result<Message> {
+        match self {
+            Outbound::Signal(message) if encoding.is_binary() => Ok(Message::Binary(message.encode_binary(encoding, version)?)),
+            Outbound::Signal(message) => Ok(Message::Text(message.encode(version)?)),
+            Outbound::Close(code, reason) => Ok(Message::Close(Some(CloseFrame { code, reason: reason.into() }))),
+        }
+    }
+}
+
+/// Session membership and host status shared by every server instance.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Allocate an id that is unique across all instances using this store
+    async fn next_user_id(&self) -> crate::Result<UserId>;
+
+    /// Get a snapshot of the session
+    async fn session(&self, session_id: &SessionId) -> crate::Result<Option<Session>>;
+
+    /// Make the user host of the session, returns the users already waiting or `None` if the session already has a host
+    async fn claim_host(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<Vec<UserId>>>;
+
+    /// Add a client to the session, returns the current host
+    async fn add_user(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<UserId>>;
+
+    /// Remove the user from every session, deleting sessions that become empty
+    async fn remove_user(&self, user_id: UserId) -> crate::Result<Vec<Removal>>;
+
+    /// Remove the user from one session, deleting it if it becomes empty. `None` if the user isn't in the session
+    async fn leave_session(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<Removal>>;
+
+    /// Store the metadata the user joined the session with, it's removed with the user
+    async fn set_metadata(&self, session_id: &SessionId, user_id: UserId, metadata: &serde_json::Value) -> crate::Result<()>;
+
+    async fn metadata(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<serde_json::Value>>;
+
+    async fn ping(&self, user_id: UserId) -> crate::Result<Option<Ping>>;
+
+    async fn set_ping(&self, user_id: UserId, ping: Ping) -> crate::Result<()>;
+
+    async fn remove_ping(&self, user_id: UserId) -> crate::Result<()>;
+
+    /// Find the last ping sent by the host of the session
+    async fn find_ping(&self, session_id: &SessionId) -> crate::Result<Option<Ping>>;
+
+    /// Last pings of the hosts that made their session public in the tenant's namespace, `""` for sessions without a tenant
+    async fn public_pings(&self, namespace: &str) -> crate::Result<Vec<Ping>>;
+
+    /// Number of clients in each of the sessions, in the same order
+    async fn client_counts(&self, session_ids: &[SessionId]) -> crate::Result<Vec<usize>>;
+
+    /// Store the reservation until `ttl` runs out, returns `false` if the session id or code is already taken
+    async fn reserve(&self, reservation: &Reservation, ttl: Duration) -> crate::Result<bool>;
+
+    async fn reservation(&self, session_id: &SessionId) -> crate::Result<Option<Reservation>>;
+
+    /// Find the session reserved with the join code
+    async fn resolve_code(&self, code: &str) -> crate::Result<Option<SessionId>>;
+
+    /// Let the reservation expire after `ttl`, or keep it while the host is connected with `None`
+    async fn set_reservation_expiry(&self, session_id: &SessionId, ttl: Option<Duration>) -> crate::Result<()>;
+
+    /// Keep the entries of a connected user and its sessions, called by the keep alive of the instance the user is connected to.
+    /// Shared stores let the entries of users that nobody refreshes expire, e.g. after their instance crashed
+    async fn touch(&self, user_id: UserId) -> crate::Result<()>;
+}
+
+/// Routes messages to users, even if they are connected to another server instance.
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    /// Register a user connected to this instance
+    async fn register(&self, user_id: UserId, tx: mpsc::UnboundedSender<Outbound>) -> crate::Result<()>;
+
+    async fn unregister(&self, user_id: UserId) -> crate::Result<()>;
+
+    /// Deliver a message to the user, returns `false` if the user is not connected to any instance
+    async fn send(&self, user_id: UserId, message: Outbound) -> crate::Result<bool>;
+}
@@ -0,0 +1,227 @@
+use super::{MessageBus, Outbound, Ping, Removal, Reservation, Session, SessionStore};
+use async_trait::async_trait;
+use ezrtc::protocol::{SessionId, UserId};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock};
+
+/// Store that keeps everything in memory, only usable by a single server instance.
+#[derive(Default)]
+pub struct MemoryStore {
+    next_user_id: AtomicUsize,
+    sessions: RwLock<HashMap<SessionId, Session>>,
+    pings: Mutex<HashMap<UserId, Ping>>,
+    reservations: Mutex<HashMap<SessionId, (Reservation, Option<Instant>)>>,
+    metadata: Mutex<HashMap<SessionId, HashMap<UserId, serde_json::Value>>>,
+}
+
+impl MemoryStore {
+    /// Reservations that haven't expired yet
+    fn reservations(&self) -> std::sync::MutexGuard<'_, HashMap<SessionId, (Reservation, Option<Instant>)>> {
+        let mut reservations = self.reservations.lock().unwrap();
+        reservations.retain(|_, (_, expires_at)| expires_at.is_none_or(|expires_at| expires_at > Instant::now()));
+
+        reservations
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn next_user_id(&self) -> crate::Result<UserId> {
+        Ok(UserId::new(self.next_user_id.fetch_add(1, Ordering::Relaxed) + 1))
+    }
+
+    async fn session(&self, session_id: &SessionId) -> crate::Result<Option<Session>> {
+        Ok(self.sessions.read().await.get(session_id).cloned())
+    }
+
+    async fn claim_host(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<Vec<UserId>>> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.entry(session_id.clone()).or_default();
+
+        if session.host.is_some() {
+            return Ok(None);
+        }
+
+        session.host = Some(user_id);
+        Ok(Some(session.users.iter().copied().collect()))
+    }
+
+    async fn add_user(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<UserId>> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.entry(session_id.clone()).or_default();
+        session.users.insert(user_id);
+
+        Ok(session.host)
+    }
+
+    async fn remove_user(&self, user_id: UserId) -> crate::Result<Vec<Removal>> {
+        let mut sessions = self.sessions.write().await;
+        let mut metadata = self.metadata.lock().unwrap();
+        let mut removals = Vec::new();
+
+        for (session_id, session) in sessions.iter_mut() {
+            let was_host = session.host == Some(user_id);
+            if was_host {
+                session.host = None;
+            }
+
+            if session.users.remove(&user_id) || was_host {
+                if let Some(users) = metadata.get_mut(session_id) {
+                    users.remove(&user_id);
+                }
+
+                removals.push(Removal {
+                    session_id: session_id.clone(),
+                    was_host,
+                    deleted: session.host.is_none() && session.users.is_empty(),
+                });
+            }
+        }
+
+        // remove sessions that are empty
+        sessions.retain(|_, session| session.host.is_some() || !session.users.is_empty());
+        metadata.retain(|session_id, _| sessions.contains_key(session_id));
+
+        Ok(removals)
+    }
+
+    async fn leave_session(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<Removal>> {
+        let mut sessions = self.sessions.write().await;
+        let Some(session) = sessions.get_mut(session_id) else {
+            return Ok(None);
+        };
+
+        let was_host = session.host == Some(user_id);
+        if was_host {
+            session.host = None;
+        }
+
+        if !session.users.remove(&user_id) && !was_host {
+            return Ok(None);
+        }
+
+        let deleted = session.host.is_none() && session.users.is_empty();
+        let mut metadata = self.metadata.lock().unwrap();
+        if deleted {
+            sessions.remove(session_id);
+            metadata.remove(session_id);
+        } else if let Some(users) = metadata.get_mut(session_id) {
+            users.remove(&user_id);
+        }
+
+        Ok(Some(Removal {
+            session_id: session_id.clone(),
+            was_host,
+            deleted,
+        }))
+    }
+
+    async fn set_metadata(&self, session_id: &SessionId, user_id: UserId, metadata: &serde_json::Value) -> crate::Result<()> {
+        self.metadata.lock().unwrap().entry(session_id.clone()).or_default().insert(user_id, metadata.clone());
+        Ok(())
+    }
+
+    async fn metadata(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<serde_json::Value>> {
+        Ok(self.metadata.lock().unwrap().get(session_id).and_then(|metadata| metadata.get(&user_id)).cloned())
+    }
+
+    async fn ping(&self, user_id: UserId) -> crate::Result<Option<Ping>> {
+        Ok(self.pings.lock().unwrap().get(&user_id).cloned())
+    }
+
+    async fn set_ping(&self, user_id: UserId, ping: Ping) -> crate::Result<()> {
+        self.pings.lock().unwrap().insert(user_id, ping);
+        Ok(())
+    }
+
+    async fn remove_ping(&self, user_id: UserId) -> crate::Result<()> {
+        self.pings.lock().unwrap().remove(&user_id);
+        Ok(())
+    }
+
+    async fn find_ping(&self, session_id: &SessionId) -> crate::Result<Option<Ping>> {
+        let pings = self.pings.lock().unwrap();
+
+        Ok(pings.values().find(|ping| ping.session_id.as_ref() == Some(session_id)).cloned())
+    }
+
+    async fn public_pings(&self, namespace: &str) -> crate::Result<Vec<Ping>> {
+        let pings = self.pings.lock().unwrap();
+
+        Ok(pings
+            .values()
+            .filter(|ping| ping.public && ping.session_id.as_ref().is_some_and(|session_id| super::namespace(session_id) == namespace))
+            .cloned()
+            .collect())
+    }
+
+    async fn client_counts(&self, session_ids: &[SessionId]) -> crate::Result<Vec<usize>> {
+        let sessions = self.sessions.read().await;
+
+        Ok(session_ids.iter().map(|session_id| sessions.get(session_id).map(|session| session.users.len()).unwrap_or_default()).collect())
+    }
+
+    async fn reserve(&self, reservation: &Reservation, ttl: Duration) -> crate::Result<bool> {
+        let mut reservations = self.reservations();
+
+        if reservations.contains_key(&reservation.session_id) || reservations.values().any(|(other, _)| other.code == reservation.code) {
+            return Ok(false);
+        }
+
+        reservations.insert(reservation.session_id.clone(), (reservation.clone(), Some(Instant::now() + ttl)));
+        Ok(true)
+    }
+
+    async fn reservation(&self, session_id: &SessionId) -> crate::Result<Option<Reservation>> {
+        Ok(self.reservations().get(session_id).map(|(reservation, _)| reservation.clone()))
+    }
+
+    async fn resolve_code(&self, code: &str) -> crate::Result<Option<SessionId>> {
+        Ok(self.reservations().values().find(|(reservation, _)| reservation.code == code).map(|(reservation, _)| reservation.session_id.clone()))
+    }
+
+    async fn set_reservation_expiry(&self, session_id: &SessionId, ttl: Option<Duration>) -> crate::Result<()> {
+        if let Some((_, expires_at)) = self.reservations().get_mut(session_id) {
+            *expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        }
+
+        Ok(())
+    }
+
+    async fn touch(&self, _user_id: UserId) -> crate::Result<()> {
+        // entries are removed when the user disconnects and don't outlive the process
+        Ok(())
+    }
+}
+
+/// Bus that can only reach users connected to this server instance.
+#[derive(Default)]
+pub struct MemoryBus {
+    connections: RwLock<HashMap<UserId, mpsc::UnboundedSender<Outbound>>>,
+}
+
+#[async_trait]
+impl MessageBus for MemoryBus {
+    async fn register(&self, user_id: UserId, tx: mpsc::UnboundedSender<Outbound>) -> crate::Result<()> {
+        self.connections.write().await.insert(user_id, tx);
+        Ok(())
+    }
+
+    async fn unregister(&self, user_id: UserId) -> crate::Result<()> {
+        self.connections.write().await.remove(&user_id);
+        Ok(())
+    }
+
+    async fn send(&self, user_id: UserId, message: Outbound) -> crate::Result<bool> {
+        match self.connections.read().await.get(&user_id) {
+            Some(tx) => {
+                tx.send(message)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
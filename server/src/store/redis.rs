@@ -0,0 +1,513 @@
+use super::{namespace, MessageBus, Outbound, Ping, Removal, Reservation, Session, SessionStore};
+use async_trait::async_trait;
+use ezrtc::protocol::{SessionId, UserId};
+use futures_util::StreamExt;
+use log::{error, info};
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, RwLock};
+
+fn host_key(session_id: &SessionId) -> String {
+    format!("ezrtc:session:{session_id}:host")
+}
+
+fn users_key(session_id: &SessionId) -> String {
+    format!("ezrtc:session:{session_id}:users")
+}
+
+fn metadata_key(session_id: &SessionId) -> String {
+    format!("ezrtc:session:{session_id}:metadata")
+}
+
+fn user_sessions_key(user_id: UserId) -> String {
+    format!("ezrtc:user:{user_id}:sessions")
+}
+
+fn user_node_key(user_id: UserId) -> String {
+    format!("ezrtc:user:{user_id}:node")
+}
+
+fn ping_key(user_id: UserId) -> String {
+    format!("ezrtc:ping:{user_id}")
+}
+
+fn session_ping_key(session_id: &SessionId) -> String {
+    format!("ezrtc:session:{session_id}:ping")
+}
+
+fn reservation_key(session_id: &SessionId) -> String {
+    format!("ezrtc:reservation:{session_id}")
+}
+
+fn code_key(code: &str) -> String {
+    format!("ezrtc:code:{code}")
+}
+
+/// Public sessions are kept per tenant, so the lobby doesn't load other tenants' sessions
+fn public_sessions_key(namespace: &str) -> String {
+    format!("ezrtc:public_sessions:{namespace}")
+}
+
+/// Entries of users and sessions expire unless the keep alive of the user's instance refreshes them, see [`SessionStore::touch`]
+const KEY_TTL: Duration = Duration::from_secs(180);
+
+/// Remove the user from the session in one step, so concurrent leaves can't both miss deleting the empty session.
+/// Returns whether the user was the host and whether the session was deleted
+const REMOVE_FROM_SESSION: &str = r#"
+local was_host = redis.call('GET', KEYS[1]) == ARGV[1]
+if was_host then
+    redis.call('DEL', KEYS[1])
+end
+redis.call('SREM', KEYS[2], ARGV[1])
+redis.call('HDEL', KEYS[3], ARGV[1])
+
+local deleted = redis.call('EXISTS', KEYS[1]) == 0 and redis.call('SCARD', KEYS[2]) == 0
+if deleted then
+    redis.call('DEL', KEYS[2], KEYS[3], KEYS[4])
+end
+
+return { was_host and 1 or 0, deleted and 1 or 0 }
+"#;
+
+fn ttl() -> i64 {
+    KEY_TTL.as_secs() as i64
+}
+
+fn node_channel(node_id: &str) -> String {
+    format!("ezrtc:node:{node_id}")
+}
+
+/// Store backed by Redis, so multiple server instances can share sessions.
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: MultiplexedConnection,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> crate::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_multiplexed_async_connection().await?;
+
+        Ok(Self { connection })
+    }
+
+    /// Remove the user from the session, deleting the session if it becomes empty
+    async fn remove_from_session(&self, session_id: SessionId, user_id: UserId) -> crate::Result<Removal> {
+        let mut connection = self.connection.clone();
+        let (was_host, deleted): (bool, bool) = redis::Script::new(REMOVE_FROM_SESSION)
+            .key(host_key(&session_id))
+            .key(users_key(&session_id))
+            .key(metadata_key(&session_id))
+            .key(session_ping_key(&session_id))
+            .arg(user_id.into_inner())
+            .invoke_async(&mut connection)
+            .await?;
+
+        Ok(Removal { session_id, was_host, deleted })
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn next_user_id(&self) -> crate::Result<UserId> {
+        let mut connection = self.connection.clone();
+        let id: usize = connection.incr("ezrtc:next_user_id", 1).await?;
+
+        Ok(UserId::new(id))
+    }
+
+    async fn session(&self, session_id: &SessionId) -> crate::Result<Option<Session>> {
+        let mut connection = self.connection.clone();
+        let host: Option<usize> = connection.get(host_key(session_id)).await?;
+        let users: Vec<usize> = connection.smembers(users_key(session_id)).await?;
+
+        if host.is_none() && users.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Session {
+            host: host.map(UserId::new),
+            users: users.into_iter().map(UserId::new).collect(),
+        }))
+    }
+
+    async fn claim_host(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<Vec<UserId>>> {
+        let mut connection = self.connection.clone();
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(host_key(session_id))
+            .arg(user_id.into_inner())
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl())
+            .query_async(&mut connection)
+            .await?;
+
+        if claimed.is_none() {
+            return Ok(None);
+        }
+
+        let (users,): (Vec<usize>,) = redis::pipe()
+            .atomic()
+            .sadd(user_sessions_key(user_id), session_id.as_str())
+            .ignore()
+            .expire(user_sessions_key(user_id), ttl())
+            .ignore()
+            .smembers(users_key(session_id))
+            .query_async(&mut connection)
+            .await?;
+
+        Ok(Some(users.into_iter().map(UserId::new).collect()))
+    }
+
+    async fn add_user(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<UserId>> {
+        let mut connection = self.connection.clone();
+        let (host,): (Option<usize>,) = redis::pipe()
+            .atomic()
+            .sadd(users_key(session_id), user_id.into_inner())
+            .ignore()
+            .expire(users_key(session_id), ttl())
+            .ignore()
+            .sadd(user_sessions_key(user_id), session_id.as_str())
+            .ignore()
+            .expire(user_sessions_key(user_id), ttl())
+            .ignore()
+            .get(host_key(session_id))
+            .query_async(&mut connection)
+            .await?;
+
+        Ok(host.map(UserId::new))
+    }
+
+    async fn remove_user(&self, user_id: UserId) -> crate::Result<Vec<Removal>> {
+        let mut connection = self.connection.clone();
+        let session_ids: Vec<String> = connection.smembers(user_sessions_key(user_id)).await?;
+        let mut removals = Vec::new();
+
+        for session_id in session_ids {
+            removals.push(self.remove_from_session(SessionId::new(session_id), user_id).await?);
+        }
+
+        let _: () = connection.del(user_sessions_key(user_id)).await?;
+
+        Ok(removals)
+    }
+
+    async fn leave_session(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<Removal>> {
+        let mut connection = self.connection.clone();
+        let removed: usize = connection.srem(user_sessions_key(user_id), session_id.as_str()).await?;
+        if removed == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(self.remove_from_session(session_id.clone(), user_id).await?))
+    }
+
+    async fn set_metadata(&self, session_id: &SessionId, user_id: UserId, metadata: &serde_json::Value) -> crate::Result<()> {
+        let mut connection = self.connection.clone();
+        let _: () = redis::pipe()
+            .atomic()
+            .hset(metadata_key(session_id), user_id.into_inner(), serde_json::to_string(metadata)?)
+            .ignore()
+            .expire(metadata_key(session_id), ttl())
+            .ignore()
+            .query_async(&mut connection)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn metadata(&self, session_id: &SessionId, user_id: UserId) -> crate::Result<Option<serde_json::Value>> {
+        let mut connection = self.connection.clone();
+        let metadata: Option<String> = connection.hget(metadata_key(session_id), user_id.into_inner()).await?;
+
+        Ok(metadata.map(|metadata| serde_json::from_str(&metadata)).transpose()?)
+    }
+
+    async fn ping(&self, user_id: UserId) -> crate::Result<Option<Ping>> {
+        let mut connection = self.connection.clone();
+        let ping: Option<String> = connection.get(ping_key(user_id)).await?;
+
+        Ok(ping.map(|ping| serde_json::from_str(&ping)).transpose()?)
+    }
+
+    async fn set_ping(&self, user_id: UserId, ping: Ping) -> crate::Result<()> {
+        let mut connection = self.connection.clone();
+        let _: () = connection.set_ex(ping_key(user_id), serde_json::to_string(&ping)?, KEY_TTL.as_secs()).await?;
+
+        if let Some(session_id) = &ping.session_id {
+            let _: () = connection.set_ex(session_ping_key(session_id), user_id.into_inner(), KEY_TTL.as_secs()).await?;
+
+            if ping.public {
+                let _: () = connection.sadd(public_sessions_key(namespace(session_id)), session_id.as_str()).await?;
+            } else {
+                let _: () = connection.srem(public_sessions_key(namespace(session_id)), session_id.as_str()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_ping(&self, user_id: UserId) -> crate::Result<()> {
+        if let Some(Ping { session_id: Some(session_id), .. }) = self.ping(user_id).await? {
+            let mut connection = self.connection.clone();
+            let owner: Option<usize> = connection.get(session_ping_key(&session_id)).await?;
+            if owner == Some(user_id.into_inner()) {
+                let _: () = connection.del(session_ping_key(&session_id)).await?;
+                let _: () = connection.srem(public_sessions_key(namespace(&session_id)), session_id.as_str()).await?;
+            }
+        }
+
+        let mut connection = self.connection.clone();
+        let _: () = connection.del(ping_key(user_id)).await?;
+
+        Ok(())
+    }
+
+    async fn find_ping(&self, session_id: &SessionId) -> crate::Result<Option<Ping>> {
+        let mut connection = self.connection.clone();
+        let owner: Option<usize> = connection.get(session_ping_key(session_id)).await?;
+
+        match owner {
+            Some(user_id) => self.ping(UserId::new(user_id)).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn public_pings(&self, namespace: &str) -> crate::Result<Vec<Ping>> {
+        let mut connection = self.connection.clone();
+        let session_ids: Vec<String> = connection.smembers(public_sessions_key(namespace)).await?;
+        if session_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let owners: Vec<Option<usize>> = connection.mget(session_ids.iter().map(|session_id| session_ping_key(&SessionId::new(session_id.clone()))).collect::<Vec<_>>()).await?;
+        let (live, mut stale): (Vec<_>, Vec<_>) = session_ids.into_iter().zip(owners).partition(|(_, owner)| owner.is_some());
+
+        let mut pings = Vec::new();
+        if !live.is_empty() {
+            let found: Vec<Option<String>> = connection.mget(live.iter().flat_map(|(_, owner)| owner.map(|user_id| ping_key(UserId::new(user_id)))).collect::<Vec<_>>()).await?;
+
+            for ((session_id, owner), ping) in live.into_iter().zip(found) {
+                match ping {
+                    Some(ping) => pings.push(serde_json::from_str(&ping)?),
+                    None => stale.push((session_id, owner)),
+                }
+            }
+        }
+
+        // sessions whose host expired without leaving
+        if !stale.is_empty() {
+            let _: () = connection.srem(public_sessions_key(namespace), stale.into_iter().map(|(session_id, _)| session_id).collect::<Vec<_>>()).await?;
+        }
+
+        Ok(pings)
+    }
+
+    async fn client_counts(&self, session_ids: &[SessionId]) -> crate::Result<Vec<usize>> {
+        if session_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut connection = self.connection.clone();
+        let mut pipe = redis::pipe();
+        for session_id in session_ids {
+            pipe.scard(users_key(session_id));
+        }
+
+        Ok(pipe.query_async(&mut connection).await?)
+    }
+
+    async fn reserve(&self, reservation: &Reservation, ttl: Duration) -> crate::Result<bool> {
+        let mut connection = self.connection.clone();
+        let code_claimed: Option<String> = redis::cmd("SET")
+            .arg(code_key(&reservation.code))
+            .arg(reservation.session_id.as_str())
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs())
+            .query_async(&mut connection)
+            .await?;
+
+        if code_claimed.is_none() {
+            return Ok(false);
+        }
+
+        let reserved: Option<String> = redis::cmd("SET")
+            .arg(reservation_key(&reservation.session_id))
+            .arg(serde_json::to_string(reservation)?)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs())
+            .query_async(&mut connection)
+            .await?;
+
+        if reserved.is_none() {
+            let _: () = connection.del(code_key(&reservation.code)).await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    async fn reservation(&self, session_id: &SessionId) -> crate::Result<Option<Reservation>> {
+        let mut connection = self.connection.clone();
+        let reservation: Option<String> = connection.get(reservation_key(session_id)).await?;
+
+        Ok(reservation.map(|reservation| serde_json::from_str(&reservation)).transpose()?)
+    }
+
+    async fn resolve_code(&self, code: &str) -> crate::Result<Option<SessionId>> {
+        let mut connection = self.connection.clone();
+        let session_id: Option<String> = connection.get(code_key(code)).await?;
+
+        Ok(session_id.map(SessionId::new))
+    }
+
+    async fn set_reservation_expiry(&self, session_id: &SessionId, ttl: Option<Duration>) -> crate::Result<()> {
+        let Some(reservation) = self.reservation(session_id).await? else {
+            return Ok(());
+        };
+
+        // claimed reservations are refreshed by the host's keep alive
+        let ttl = ttl.unwrap_or(KEY_TTL).as_secs() as i64;
+        let mut connection = self.connection.clone();
+        for key in [reservation_key(session_id), code_key(&reservation.code)] {
+            let _: () = connection.expire(key, ttl).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn touch(&self, user_id: UserId) -> crate::Result<()> {
+        let mut connection = self.connection.clone();
+        let session_ids: Vec<String> = connection.smembers(user_sessions_key(user_id)).await?;
+        let session_ids: Vec<SessionId> = session_ids.into_iter().map(SessionId::new).collect();
+
+        let mut pipe = redis::pipe();
+        for key in [user_sessions_key(user_id), user_node_key(user_id), ping_key(user_id)] {
+            pipe.expire(key, ttl()).ignore();
+        }
+        for session_id in &session_ids {
+            for key in [host_key(session_id), users_key(session_id), metadata_key(session_id), session_ping_key(session_id)] {
+                pipe.expire(key, ttl()).ignore();
+            }
+        }
+
+        // the reservation of a session stays while its host is connected
+        for session_id in &session_ids {
+            let host: Option<usize> = connection.get(host_key(session_id)).await?;
+            if host != Some(user_id.into_inner()) {
+                continue;
+            }
+
+            if let Some(reservation) = self.reservation(session_id).await? {
+                pipe.expire(reservation_key(session_id), ttl()).ignore().expire(code_key(&reservation.code), ttl()).ignore();
+            }
+        }
+
+        let _: () = pipe.query_async(&mut connection).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    user_id: UserId,
+    message: Outbound,
+}
+
+/// Bus that forwards messages over Redis pub/sub to the instance the user is connected to.
+pub struct RedisBus {
+    node_id: String,
+    connection: MultiplexedConnection,
+    local: Arc<RwLock<HashMap<UserId, mpsc::UnboundedSender<Outbound>>>>,
+}
+
+impl RedisBus {
+    pub async fn connect(url: &str) -> crate::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_multiplexed_async_connection().await?;
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let node_id = format!("{}-{nanos:x}", std::process::id());
+        let local: Arc<RwLock<HashMap<UserId, mpsc::UnboundedSender<Outbound>>>> = Arc::default();
+
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(node_channel(&node_id)).await?;
+        info!("Subscribed to redis channel: {}", node_channel(&node_id));
+
+        // Deliver messages published by other instances to local users
+        let local2 = local.clone();
+        tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+
+            while let Some(message) = messages.next().await {
+                let payload: String = match message.get_payload() {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        error!("Invalid redis message: {}", e);
+                        continue;
+                    }
+                };
+
+                match serde_json::from_str::<Envelope>(&payload) {
+                    Ok(envelope) => {
+                        if let Some(tx) = local2.read().await.get(&envelope.user_id) {
+                            let _ = tx.send(envelope.message);
+                        }
+                    }
+                    Err(e) => error!("Failed to parse redis message: {}", e),
+                }
+            }
+
+            error!("Redis subscription closed");
+        });
+
+        Ok(Self { node_id, connection, local })
+    }
+}
+
+#[async_trait]
+impl MessageBus for RedisBus {
+    async fn register(&self, user_id: UserId, tx: mpsc::UnboundedSender<Outbound>) -> crate::Result<()> {
+        self.local.write().await.insert(user_id, tx);
+
+        let mut connection = self.connection.clone();
+        let _: () = connection.set_ex(user_node_key(user_id), &self.node_id, KEY_TTL.as_secs()).await?;
+
+        Ok(())
+    }
+
+    async fn unregister(&self, user_id: UserId) -> crate::Result<()> {
+        self.local.write().await.remove(&user_id);
+
+        let mut connection = self.connection.clone();
+        let _: () = connection.del(user_node_key(user_id)).await?;
+
+        Ok(())
+    }
+
+    async fn send(&self, user_id: UserId, message: Outbound) -> crate::Result<bool> {
+        if let Some(tx) = self.local.read().await.get(&user_id) {
+            tx.send(message)?;
+            return Ok(true);
+        }
+
+        let mut connection = self.connection.clone();
+        let node_id: Option<String> = connection.get(user_node_key(user_id)).await?;
+
+        match node_id {
+            Some(node_id) => {
+                let envelope = serde_json::to_string(&Envelope { user_id, message })?;
+                let _: () = connection.publish(node_channel(&node_id), envelope).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
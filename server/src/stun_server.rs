@@ -0,0 +1,77 @@
+use crate::metrics::{increment, Metrics};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use stun::message::{Message, Setter, BINDING_REQUEST, BINDING_SUCCESS};
+use stun::xoraddr::XorMappedAddress;
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StunConfig {
+    /// UDP address of the STUN responder, e.g. `0.0.0.0:3478`
+    pub address: String,
+}
+
+/// Bind the configured address and answer binding requests until the socket fails
+pub async fn run(config: StunConfig, metrics: Arc<Metrics>) -> crate::Result<()> {
+    let socket = UdpSocket::bind(&config.address).await?;
+    info!("STUN responder listening on {}", socket.local_addr()?);
+
+    serve(socket, metrics).await
+}
+
+/// Answer binding requests received on `socket` with the sender's address
+pub async fn serve(socket: UdpSocket, metrics: Arc<Metrics>) -> crate::Result<()> {
+    let mut buf = vec![0u8; 1500];
+
+    loop {
+        let (len, peer_addr) = socket.recv_from(&mut buf).await?;
+
+        let mut request = Message::new();
+        request.raw = buf[..len].to_vec();
+
+        if let Err(e) = request.decode() {
+            warn!("Invalid STUN message from {}: {}", peer_addr, e);
+            increment(&metrics.stun.invalid);
+            continue;
+        }
+
+        if request.typ != BINDING_REQUEST {
+            warn!("Unsupported STUN message from {}: {}", peer_addr, request.typ);
+            increment(&metrics.stun.invalid);
+            continue;
+        }
+
+        increment(&metrics.stun.requests);
+
+        // the setters aren't Send, so they can't live across the await below
+        let response = {
+            let mut response = Message::new();
+            let setters: Vec<Box<dyn Setter>> = vec![
+                Box::new(request.transaction_id),
+                Box::new(BINDING_SUCCESS),
+                Box::new(XorMappedAddress {
+                    ip: peer_addr.ip(),
+                    port: peer_addr.port(),
+                }),
+            ];
+
+            match response.build(&setters) {
+                Ok(()) => response,
+                Err(e) => {
+                    error!("Failed to build STUN response: {}", e);
+                    increment(&metrics.stun.errors);
+                    continue;
+                }
+            }
+        };
+
+        match socket.send_to(&response.raw, peer_addr).await {
+            Ok(_) => increment(&metrics.stun.responses),
+            Err(e) => {
+                error!("Failed to send STUN response to {}: {}", peer_addr, e);
+                increment(&metrics.stun.errors);
+            }
+        }
+    }
+}
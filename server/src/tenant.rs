@@ -0,0 +1,258 @@
+use crate::auth;
+use crate::config::Config;
+use crate::rate_limit::RateLimiter;
+use crate::router::ServerState;
+use crate::store::{Outbound, SessionStore};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use ezrtc::protocol::{SessionId, SignalMessage, UserId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Limits of a tenant, counted separately on every server instance.
+/// Behind a load balancer a tenant can use its quotas once on each instance, divide them by the number of instances to limit the total
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Quotas {
+    /// Sessions that can exist at the same time
+    pub max_sessions: Option<usize>,
+    /// Users that can be connected at the same time
+    pub max_users: Option<usize>,
+    /// Messages each user can send per second
+    pub message_rate: Option<u64>,
+    /// Sessions each address can reserve with `POST /sessions` per minute, 10 if not set
+    pub reservation_rate: Option<u64>,
+}
+
+impl Quotas {
+    pub fn reservation_limit(&self) -> u64 {
+        self.reservation_rate.unwrap_or(10)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantConfig {
+    /// Prefix of the tenant's sessions, can't contain `:`
+    pub name: String,
+    /// Key the tenant's apps send in the `app_key` query parameter or the `X-App-Key` header
+    pub app_key: String,
+    #[serde(flatten)]
+    pub quotas: Quotas,
+    /// Origins allowed to use the tenant's sessions, overrides the server's `allowed_origins`
+    #[serde(default)]
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct AppKeyQuery {
+    app_key: Option<String>,
+}
+
+/// Namespace of the sessions a connection can use, users without an app key share the default namespace
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    config: Option<Arc<TenantConfig>>,
+    quotas: Quotas,
+}
+
+impl Tenant {
+    /// Find the tenant of the app key, returns `None` if the key is unknown
+    pub fn resolve(config: &Config, app_key: Option<&str>) -> Option<Self> {
+        let Some(app_key) = app_key else {
+            return Some(Self {
+                config: None,
+                quotas: config.quotas.clone(),
+            });
+        };
+
+        let tenant = config.tenants.iter().find(|tenant| auth::constant_time_eq(tenant.app_key.as_bytes(), app_key.as_bytes()))?;
+
+        Some(Self {
+            quotas: tenant.quotas.clone(),
+            config: Some(Arc::new(tenant.clone())),
+        })
+    }
+
+    /// Name of the tenant, empty for the default namespace
+    pub fn name(&self) -> &str {
+        self.config.as_ref().map(|config| config.name.as_str()).unwrap_or_default()
+    }
+
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    /// Origins configured for the tenant, `None` if it uses the server's allowlist
+    pub fn allowed_origins(&self) -> Option<&[String]> {
+        self.config.as_ref()?.allowed_origins.as_deref()
+    }
+
+    /// Session id used by the store, ids have to be validated first so they can't escape the namespace
+    pub fn scope(&self, session_id: &SessionId) -> SessionId {
+        match &self.config {
+            Some(config) => SessionId::new(format!("{}:{}", config.name, session_id)),
+            None => session_id.clone(),
+        }
+    }
+
+    /// Session id shown to the tenant's users
+    pub fn unscope(&self, session_id: &SessionId) -> SessionId {
+        match &self.config {
+            Some(config) => SessionId::new(session_id.as_str().strip_prefix(&format!("{}:", config.name)).unwrap_or(session_id.as_str()).to_string()),
+            None => session_id.clone(),
+        }
+    }
+
+    /// `true` if the scoped session id belongs to this tenant
+    pub fn owns(&self, session_id: &SessionId) -> bool {
+        match &self.config {
+            Some(config) => session_id.as_str().strip_prefix(&config.name).is_some_and(|rest| rest.starts_with(':')),
+            None => !session_id.as_str().contains(':'),
+        }
+    }
+
+    /// Validate and scope the session id of a message from a user
+    pub fn scope_message(&self, message: &mut SignalMessage) -> Result<(), String> {
+        if let Some(session_id) = message.session_id_mut() {
+            session_id.validate().map_err(|e| format!("Invalid session id: {e}"))?;
+            *session_id = self.scope(session_id);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the namespace from a message before it's sent to the user
+    pub fn unscope_outbound(&self, message: Outbound) -> Outbound {
+        match message {
+            Outbound::Signal(mut message) => {
+                if let Some(session_id) = message.session_id_mut() {
+                    *session_id = self.unscope(session_id);
+                }
+                Outbound::Signal(message)
+            }
+            message => message,
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<ServerState> for Tenant {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> Result<Self, Self::Rejection> {
+        Tenant::resolve(&state.config, app_key(&parts.headers, &parts.uri).as_deref()).ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// App key from the `X-App-Key` header or the `app_key` query parameter
+pub fn app_key(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    let header = headers.get("x-app-key").and_then(|value| value.to_str().ok()).map(str::to_string);
+
+    header.or_else(|| Query::<AppKeyQuery>::try_from_uri(uri).ok().and_then(|query| query.0.app_key))
+}
+
+/// Tracks what each tenant uses on this server instance
+pub struct TenantUsage {
+    users: Mutex<HashMap<String, usize>>,
+    sessions: Mutex<HashMap<String, HashSet<SessionId>>>,
+    messages: RateLimiter<UserId>,
+    reservations: RateLimiter<(String, Option<IpAddr>)>,
+}
+
+impl Default for TenantUsage {
+    fn default() -> Self {
+        Self {
+            users: Mutex::default(),
+            sessions: Mutex::default(),
+            messages: RateLimiter::default(),
+            reservations: RateLimiter::new(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl TenantUsage {
+    /// Count a connecting user, returns `false` if the tenant has too many users
+    pub fn connect(&self, tenant: &Tenant) -> bool {
+        let mut users = self.users.lock().unwrap();
+        let count = users.entry(tenant.name().to_string()).or_default();
+
+        if tenant.quotas().max_users.is_some_and(|max_users| *count >= max_users) {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+
+    pub fn disconnect(&self, tenant: &Tenant, user_id: UserId) {
+        if let Some(count) = self.users.lock().unwrap().get_mut(tenant.name()) {
+            *count = count.saturating_sub(1);
+        }
+
+        self.messages.remove(&user_id);
+    }
+
+    /// Count the scoped session, returns `false` if it's new and the tenant has too many sessions.
+    /// The last user of a session can leave through another instance, counted sessions the store doesn't have anymore are dropped once the limit is reached
+    pub async fn add_session(&self, tenant: &Tenant, session_id: &SessionId, store: &dyn SessionStore) -> crate::Result<bool> {
+        let Some(max_sessions) = tenant.quotas().max_sessions else {
+            return Ok(true);
+        };
+
+        if self.try_add_session(tenant, session_id, max_sessions) {
+            return Ok(true);
+        }
+
+        let counted: Vec<_> = self.sessions.lock().unwrap().get(tenant.name()).map(|sessions| sessions.iter().cloned().collect()).unwrap_or_default();
+        for counted_id in counted {
+            if store.session(&counted_id).await?.is_none() {
+                self.remove_session(tenant, &counted_id);
+            }
+        }
+
+        Ok(self.try_add_session(tenant, session_id, max_sessions))
+    }
+
+    fn try_add_session(&self, tenant: &Tenant, session_id: &SessionId, max_sessions: usize) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let sessions = sessions.entry(tenant.name().to_string()).or_default();
+
+        if sessions.contains(session_id) {
+            return true;
+        }
+
+        if sessions.len() >= max_sessions {
+            return false;
+        }
+
+        sessions.insert(session_id.clone());
+        true
+    }
+
+    pub fn remove_session(&self, tenant: &Tenant, session_id: &SessionId) {
+        if let Some(sessions) = self.sessions.lock().unwrap().get_mut(tenant.name()) {
+            sessions.remove(session_id);
+        }
+    }
+
+    /// Count a message of the user, returns `false` if the user exceeded the message rate
+    pub fn allow_message(&self, tenant: &Tenant, user_id: UserId) -> bool {
+        let Some(message_rate) = tenant.quotas().message_rate else {
+            return true;
+        };
+
+        self.messages.allow(user_id, 1, message_rate)
+    }
+
+    /// Count a session reservation from the address, returns `false` if it reserved too many sessions in the last minute
+    pub fn allow_reservation(&self, tenant: &Tenant, address: Option<IpAddr>) -> bool {
+        // addresses are never removed, drop the ones that stopped reserving
+        self.reservations.purge();
+        self.reservations.allow((tenant.name().to_string(), address), 1, tenant.quotas().reservation_limit())
+    }
+}
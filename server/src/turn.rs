@@ -0,0 +1,49 @@
+use crate::turn_server::TurnServerConfig;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ezrtc::protocol::{SessionId, TurnCredentials};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnConfig {
+    /// Secret shared with the TURN server (`static-auth-secret` in coturn)
+    pub secret: String,
+    /// TURN server urls handed to the clients, e.g. `turn:turn.example.com:3478`
+    pub uris: Vec<String>,
+    /// Lifetime of the issued credentials in seconds
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    /// Run a TURN server in this process that accepts the issued credentials
+    pub server: Option<TurnServerConfig>,
+}
+
+fn default_ttl() -> u64 {
+    86400
+}
+
+/// Create credentials for the users of the session that the TURN server accepts until the ttl runs out,
+/// `session_id` has to be validated and scoped to the tenant
+pub fn credentials(config: &TurnConfig, session_id: &SessionId) -> crate::Result<TurnCredentials> {
+    let expiry = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + config.ttl;
+    let username = format!("{expiry}:{session_id}");
+
+    let password = password(&config.secret, &username);
+
+    Ok(TurnCredentials {
+        username,
+        password,
+        ttl: config.ttl,
+        uris: config.uris.clone(),
+    })
+}
+
+/// Password of a TURN username, the base64 encoded HMAC-SHA1 of the username with the shared secret
+pub fn password(secret: &str, username: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+
+    STANDARD.encode(mac.finalize().into_bytes())
+}
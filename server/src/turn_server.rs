@@ -0,0 +1,261 @@
+use crate::metrics::{increment, Metrics};
+use crate::rate_limit::RateLimiter;
+use crate::turn;
+use ::turn::allocation::AllocationInfo;
+use ::turn::auth::{generate_auth_key, AuthHandler};
+use ::turn::relay::relay_range::RelayAddressGeneratorRanges;
+use ::turn::relay::RelayAddressGenerator;
+use ::turn::server::config::{ConnConfig, ServerConfig};
+use ::turn::server::Server;
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use webrtc_util::vnet::net::Net;
+use webrtc_util::Conn;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnServerConfig {
+    /// UDP address of the TURN server, e.g. `0.0.0.0:3478`
+    pub address: String,
+    /// Public IP address advertised for relayed candidates
+    pub relay_address: IpAddr,
+    #[serde(default = "default_min_port")]
+    pub min_port: u16,
+    #[serde(default = "default_max_port")]
+    pub max_port: u16,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Bytes per second the allocations of a session can relay together, unlimited if not set
+    pub bandwidth_limit: Option<u64>,
+}
+
+fn default_min_port() -> u16 {
+    49152
+}
+
+fn default_max_port() -> u16 {
+    65535
+}
+
+fn default_realm() -> String {
+    "ezrtc".to_string()
+}
+
+/// Start a TURN server that accepts the credentials issued at `/turn-credentials`
+pub async fn run(config: TurnServerConfig, secret: String, metrics: Arc<Metrics>) -> crate::Result<Server> {
+    let socket = UdpSocket::bind(&config.address).await?;
+    info!("TURN server listening on {}, relaying ports {}-{}", socket.local_addr()?, config.min_port, config.max_port);
+
+    let quotas = Arc::new(SessionQuotas::new(config.bandwidth_limit, metrics.clone()));
+    let conn = Arc::new(QuotaConn {
+        inner: Arc::new(socket),
+        quotas: quotas.clone(),
+    });
+
+    let relay_addr_generator = MeteredRelayAddressGenerator {
+        ranges: RelayAddressGeneratorRanges {
+            relay_address: config.relay_address,
+            min_port: config.min_port,
+            max_port: config.max_port,
+            max_retries: 10,
+            address: "0.0.0.0".to_string(),
+            net: Arc::new(Net::new(None)),
+        },
+        metrics,
+    };
+
+    // forget the session of a client once its allocation is gone
+    let (alloc_close_tx, mut alloc_close_rx) = mpsc::channel::<AllocationInfo>(64);
+    let released = quotas.clone();
+    tokio::spawn(async move {
+        while let Some(allocation) = alloc_close_rx.recv().await {
+            released.release(allocation.five_tuple.src_addr);
+        }
+    });
+
+    let server = Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(relay_addr_generator),
+        }],
+        realm: config.realm,
+        auth_handler: Arc::new(CredentialsAuthHandler { secret, quotas }),
+        channel_bind_timeout: Duration::from_secs(0),
+        alloc_close_notify: Some(alloc_close_tx),
+    })
+    .await?;
+
+    Ok(server)
+}
+
+/// Accepts the `<expiry>:<session>` usernames issued by [`turn::credentials`] and remembers the session of each client address
+struct CredentialsAuthHandler {
+    secret: String,
+    quotas: Arc<SessionQuotas>,
+}
+
+impl AuthHandler for CredentialsAuthHandler {
+    fn auth_handle(&self, username: &str, realm: &str, src_addr: SocketAddr) -> Result<Vec<u8>, ::turn::Error> {
+        let Some((expiry, session)) = username.split_once(':') else {
+            return Err(::turn::Error::Other(format!("Invalid username {username}")));
+        };
+
+        let expiry = expiry.parse::<u64>().map_err(|_| ::turn::Error::Other(format!("Invalid username {username}")))?;
+        if expiry < SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() {
+            return Err(::turn::Error::Other(format!("Expired username {username}")));
+        }
+
+        self.quotas.bind(src_addr, session);
+
+        Ok(generate_auth_key(username, realm, &turn::password(&self.secret, username)))
+    }
+}
+
+/// Allocates relay ports from the configured range and counts the allocations
+struct MeteredRelayAddressGenerator {
+    ranges: RelayAddressGeneratorRanges,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl RelayAddressGenerator for MeteredRelayAddressGenerator {
+    fn validate(&self) -> Result<(), ::turn::Error> {
+        self.ranges.validate()
+    }
+
+    async fn allocate_conn(&self, use_ipv4: bool, requested_port: u16) -> Result<(Arc<dyn Conn + Send + Sync>, SocketAddr), ::turn::Error> {
+        let result = self.ranges.allocate_conn(use_ipv4, requested_port).await?;
+        increment(&self.metrics.turn.allocations);
+
+        Ok(result)
+    }
+}
+
+/// Bandwidth used by the TURN clients of each session in the current second
+pub struct SessionQuotas {
+    limit: Option<u64>,
+    sessions: Mutex<HashMap<SocketAddr, String>>,
+    windows: RateLimiter<String>,
+    metrics: Arc<Metrics>,
+}
+
+impl SessionQuotas {
+    pub fn new(limit: Option<u64>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            limit,
+            sessions: Mutex::new(HashMap::new()),
+            windows: RateLimiter::default(),
+            metrics,
+        }
+    }
+
+    /// Count the packets of the client at `address` towards `session`
+    pub fn bind(&self, address: SocketAddr, session: &str) {
+        self.sessions.lock().unwrap().insert(address, session.to_string());
+    }
+
+    /// Forget the client at `address`, the window of its session is dropped with its last client
+    pub fn release(&self, address: SocketAddr) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.remove(&address) else {
+            return;
+        };
+
+        if !sessions.values().any(|other| *other == session) {
+            self.windows.remove(&session);
+        }
+    }
+
+    /// Count `len` relayed bytes of the client at `address`, returns `false` if its session used up its bandwidth
+    pub fn allow(&self, address: SocketAddr, len: usize) -> bool {
+        let session = self.sessions.lock().unwrap().get(&address).cloned();
+
+        if let (Some(limit), Some(session)) = (self.limit, session) {
+            if !self.windows.allow(session, len as u64, limit) {
+                increment(&self.metrics.turn.dropped_packets);
+                return false;
+            }
+        }
+
+        self.metrics.turn.relayed_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        true
+    }
+}
+
+/// ChannelData messages and Send or Data indications carry relayed data, everything else is TURN signaling
+fn is_relayed(packet: &[u8]) -> bool {
+    match packet {
+        [0x40..=0x7f, ..] => true,
+        [first, second, ..] => matches!(u16::from_be_bytes([*first, *second]), 0x0016 | 0x0017),
+        _ => false,
+    }
+}
+
+/// Listening socket of the TURN server that enforces the session quotas on the data it relays
+struct QuotaConn {
+    inner: Arc<dyn Conn + Send + Sync>,
+    quotas: Arc<SessionQuotas>,
+}
+
+impl QuotaConn {
+    fn allow(&self, packet: &[u8], address: SocketAddr) -> bool {
+        !is_relayed(packet) || self.quotas.allow(address, packet.len())
+    }
+}
+
+#[async_trait]
+impl Conn for QuotaConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        self.inner.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, addr) = self.inner.recv_from(buf).await?;
+            if self.allow(&buf[..len], addr) {
+                return Ok((len, addr));
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.inner.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        if !self.allow(buf, target) {
+            warn!("Dropped {} bytes relayed to {}, the session exceeded its TURN bandwidth", buf.len(), target);
+            return Err(webrtc_util::Error::Other("TURN bandwidth quota exceeded".to_string()));
+        }
+
+        self.inner.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
@@ -0,0 +1,26 @@
+use ezrtc::protocol::UserId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Version of users that connect without sending a hello
+pub const LEGACY_VERSION: u32 = 1;
+
+/// Protocol version negotiated by each user connected to this server instance
+#[derive(Default)]
+pub struct ProtocolVersions {
+    versions: Mutex<HashMap<UserId, u32>>,
+}
+
+impl ProtocolVersions {
+    pub fn set(&self, user_id: UserId, version: u32) {
+        self.versions.lock().unwrap().insert(user_id, version);
+    }
+
+    pub fn get(&self, user_id: UserId) -> u32 {
+        self.versions.lock().unwrap().get(&user_id).copied().unwrap_or(LEGACY_VERSION)
+    }
+
+    pub fn remove(&self, user_id: UserId) {
+        self.versions.lock().unwrap().remove(&user_id);
+    }
+}
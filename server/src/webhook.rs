@@ -0,0 +1,157 @@
+use crate::metrics::{increment, Metrics};
+use ezrtc::protocol::{SessionId, UserId};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// Urls that receive every event
+    pub urls: Vec<String>,
+    /// Secret used to sign the events, see [`signature`]
+    pub secret: String,
+    /// Attempts after the first failed delivery, waiting twice as long before each one
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+/// Session lifecycle event posted to the webhook urls
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The host sent its first keep alive for the session
+    HostOnline {
+        session_id: SessionId,
+        user_id: UserId,
+        metadata: Option<serde_json::Value>,
+    },
+    /// The host stopped responding to pings or disconnected
+    HostOffline {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    /// The host left the session with `SessionLeave` and is still connected
+    HostLeft {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    ClientJoined {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    ClientLeft {
+        session_id: SessionId,
+        user_id: UserId,
+    },
+    /// The last user left the session
+    SessionDeleted {
+        session_id: SessionId,
+    },
+}
+
+/// Body of a webhook request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Unix timestamp of the event, also sent in the `X-Ezrtc-Timestamp` header
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: WebhookEvent,
+}
+
+/// Posts events to the configured urls in the background
+pub struct Webhooks {
+    config: Option<WebhookConfig>,
+    client: reqwest::Client,
+    metrics: Arc<Metrics>,
+}
+
+impl Webhooks {
+    pub fn new(config: Option<WebhookConfig>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            metrics,
+        }
+    }
+
+    /// Deliver the event to every url without waiting for the responses
+    pub fn send(&self, event: WebhookEvent) {
+        let Some(config) = &self.config else {
+            return;
+        };
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        let body = match serde_json::to_string(&WebhookPayload { timestamp, event }) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to encode webhook event: {}", e);
+                return;
+            }
+        };
+
+        for url in &config.urls {
+            let client = self.client.clone();
+            let metrics = self.metrics.clone();
+            let url = url.clone();
+            let body = body.clone();
+            let secret = config.secret.clone();
+            let retries = config.retries;
+
+            tokio::spawn(async move {
+                if deliver(&client, &url, &secret, timestamp, body, retries).await {
+                    increment(&metrics.webhooks.delivered);
+                } else {
+                    error!("Giving up on webhook delivery to {}", url);
+                    increment(&metrics.webhooks.failed);
+                }
+            });
+        }
+    }
+}
+
+/// Post the body until the url responds with a success status, returns `false` if every attempt failed
+pub async fn deliver(client: &reqwest::Client, url: &str, secret: &str, timestamp: u64, body: String, retries: u32) -> bool {
+    let signature = signature(secret, timestamp, &body);
+    let mut delay = Duration::from_secs(1);
+
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+
+        let response = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Ezrtc-Timestamp", timestamp.to_string())
+            .header("X-Ezrtc-Signature", &signature)
+            .timeout(Duration::from_secs(10))
+            .body(body.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => return true,
+            Ok(response) => warn!("Webhook {} responded with {} (attempt {})", url, response.status(), attempt + 1),
+            Err(e) => warn!("Webhook {} failed: {} (attempt {})", url, e, attempt + 1),
+        }
+    }
+
+    false
+}
+
+/// `sha256=<hex encoded HMAC-SHA256 of "<timestamp>.<body>">`, receivers should compare it with the `X-Ezrtc-Signature` header
+pub fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+
+    let hex: String = mac.finalize().into_bytes().iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("sha256={hex}")
+}
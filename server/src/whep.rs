@@ -0,0 +1,205 @@
+use crate::one_to_many;
+use crate::router::ServerState;
+use crate::store::Outbound;
+use crate::tenant::Tenant;
+use crate::webhook::WebhookEvent;
+use axum::http::StatusCode;
+use ezrtc::protocol::{IceCandidateJSON, IcePayload, SdpPayload, SdpType, SessionId, SignalMessage, UserId, PROTOCOL_VERSION};
+use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+/// How long the host has to answer an offer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Peer connection between a host and a user that sent its offer over HTTP
+pub struct WhepResource {
+    pub session_id: SessionId,
+    pub user_id: UserId,
+    pub host_id: UserId,
+    closed: Notify,
+}
+
+pub type WhepResources = Arc<Mutex<HashMap<String, Arc<WhepResource>>>>;
+
+/// Answer of the host and the id of the resource that can be patched or deleted
+pub struct WhepAnswer {
+    pub resource_id: String,
+    pub sdp: String,
+}
+
+/// Pass the offer to the host of the session and wait for its answer, `session_id` has to be scoped to the tenant
+pub async fn offer(state: &ServerState, tenant: Tenant, session_id: SessionId, sdp: String) -> Result<WhepAnswer, StatusCode> {
+    let user_id = state.store.next_user_id().await.map_err(internal)?;
+    if !state.tenant_usage.connect(&tenant) {
+        warn!("Too many users connected to tenant {:?}", tenant.name());
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    state.bus.register(user_id, tx).await.map_err(internal)?;
+    info!("new user connected over WHEP: {:?}", user_id);
+
+    let result = negotiate(state, &session_id, user_id, sdp, &mut rx).await;
+    let (host_id, sdp) = match result {
+        Ok(answer) => answer,
+        Err(status) => {
+            if let Err(e) = one_to_many::user_disconnected(user_id, &tenant, state).await {
+                error!("error while removing user: {}", e);
+            }
+            return Err(status);
+        }
+    };
+
+    let resource_id: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    let resource = Arc::new(WhepResource {
+        session_id,
+        user_id,
+        host_id,
+        closed: Notify::new(),
+    });
+    state.whep_resources.lock().unwrap().insert(resource_id.clone(), resource.clone());
+
+    let state2 = state.clone();
+    let resource_id2 = resource_id.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = drain(&mut rx) => info!("WHEP user closed by server: {:?}", user_id),
+            _ = host_left(&state2, &resource) => info!("Host of WHEP user left: {:?}", user_id),
+            _ = resource.closed.notified() => info!("WHEP resource deleted: {:?}", user_id),
+        }
+
+        state2.whep_resources.lock().unwrap().remove(&resource_id2);
+        if let Err(e) = one_to_many::user_disconnected(user_id, &tenant, &state2).await {
+            error!("error while removing user: {}", e);
+        }
+    });
+
+    Ok(WhepAnswer { resource_id, sdp })
+}
+
+async fn negotiate(state: &ServerState, session_id: &SessionId, user_id: UserId, sdp: String, rx: &mut mpsc::UnboundedReceiver<Outbound>) -> Result<(UserId, String), StatusCode> {
+    let Some(host_id) = state.store.add_user(session_id, user_id).await.map_err(internal)? else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    state.webhooks.send(WebhookEvent::ClientJoined {
+        session_id: session_id.clone(),
+        user_id,
+    });
+
+    // converted to the host's version when it's sent
+    let offer = SignalMessage::SdpOffer(session_id.clone(), user_id, SdpPayload::new(SdpType::Offer, sdp, PROTOCOL_VERSION));
+    if !state.bus.send(host_id, Outbound::Signal(offer)).await.map_err(internal)? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let answer = tokio::time::timeout(ANSWER_TIMEOUT, async {
+        while let Some(message) = rx.recv().await {
+            match message {
+                Outbound::Signal(SignalMessage::SdpAnswer(_, sender_id, answer)) if sender_id == host_id => return Ok(answer.into_sdp()),
+                Outbound::Signal(SignalMessage::Error(_, _, error)) => {
+                    warn!("Host rejected WHEP offer: {}", error);
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Outbound::Close(..) => break,
+                _ => {}
+            }
+        }
+
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    })
+    .await;
+
+    match answer {
+        Ok(answer) => Ok((host_id, answer?)),
+        Err(_) => {
+            warn!("Host didn't answer WHEP offer in time: {:?}", host_id);
+            Err(StatusCode::GATEWAY_TIMEOUT)
+        }
+    }
+}
+
+/// Discard messages until the server closes the connection, the host sends its candidates in the answer
+async fn drain(rx: &mut mpsc::UnboundedReceiver<Outbound>) {
+    while let Some(message) = rx.recv().await {
+        if let Outbound::Close(..) = message {
+            return;
+        }
+    }
+}
+
+async fn host_left(state: &ServerState, resource: &WhepResource) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        // WHEP users don't answer keep alives, refresh them while the resource exists
+        if let Err(e) = state.store.touch(resource.user_id).await {
+            error!("Failed to refresh WHEP user: {}", e);
+        }
+
+        match state.store.session(&resource.session_id).await {
+            Ok(Some(session)) if session.host == Some(resource.host_id) => {}
+            Ok(_) => return,
+            Err(e) => error!("Failed to read session: {}", e),
+        }
+    }
+}
+
+pub fn find(state: &ServerState, session_id: &SessionId, resource_id: &str) -> Option<Arc<WhepResource>> {
+    state.whep_resources.lock().unwrap().get(resource_id).filter(|resource| &resource.session_id == session_id).cloned()
+}
+
+/// Forward the candidates of a trickle ICE SDP fragment to the host
+pub async fn trickle(state: &ServerState, resource: &WhepResource, fragment: &str) -> crate::Result<()> {
+    for candidate in candidates(fragment) {
+        let message = SignalMessage::IceCandidate(resource.session_id.clone(), resource.user_id, IcePayload::Candidate(candidate));
+        state.bus.send(resource.host_id, Outbound::Signal(message)).await?;
+    }
+
+    Ok(())
+}
+
+pub fn close(resource: &WhepResource) {
+    resource.closed.notify_one();
+}
+
+/// Parse the `a=candidate` lines of an `application/trickle-ice-sdpfrag` body
+fn candidates(fragment: &str) -> Vec<IceCandidateJSON> {
+    let mut candidates = Vec::new();
+    let mut username_fragment = None;
+    let mut sdp_mid = None;
+    let mut sdp_mline_index: Option<u16> = None;
+
+    for line in fragment.lines().map(str::trim) {
+        if line.starts_with("m=") {
+            sdp_mline_index = Some(sdp_mline_index.map_or(0, |index| index + 1));
+            sdp_mid = None;
+        } else if let Some(mid) = line.strip_prefix("a=mid:") {
+            sdp_mid = Some(mid.to_string());
+        } else if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+            username_fragment = Some(ufrag.to_string());
+        } else if let Some(candidate) = line.strip_prefix("a=") {
+            if candidate.starts_with("candidate:") {
+                candidates.push(IceCandidateJSON {
+                    candidate: candidate.to_string(),
+                    sdp_mid: sdp_mid.clone(),
+                    sdp_mline_index: Some(sdp_mline_index.unwrap_or(0)),
+                    username_fragment: username_fragment.clone(),
+                });
+            }
+        }
+    }
+
+    candidates
+}
+
+fn internal(e: crate::Error) -> StatusCode {
+    error!("WHEP request failed: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
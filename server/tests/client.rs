@@ -0,0 +1,133 @@
+//! Rust client connected to a server in the test process
+
+mod common;
+
+use ezrtc::client::EzRTCClient;
+use ezrtc::protocol::{IceConfig, IceServer, IceTransportPolicy, UserId};
+use ezrtc::socket::{DataChannelHandler, WSHost};
+use ezrtc::DataChannel;
+use ezrtc_server::config::Config;
+use ezrtc_server::turn::TurnConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+
+struct Handler;
+
+impl DataChannelHandler for Handler {
+    fn handle_data_channel_open(&self, _dc: DataChannel) {}
+
+    fn handle_data_channel_message(&self, _message: String) {}
+
+    fn handle_keep_alive(&self, _handle: &mut WSHost, _user_id: UserId) {}
+}
+
+fn ice_config() -> IceConfig {
+    IceConfig {
+        ice_servers: vec![IceServer {
+            urls: vec!["stun:stun.example.com:3478".to_string()],
+            username: None,
+            credential: None,
+        }],
+        ice_transport_policy: Some(IceTransportPolicy::Relay),
+    }
+}
+
+/// Configuration of the client's current peer connection once it matches, the client applies the server's ICE configuration in the background
+async fn configuration_matching(client: &EzRTCClient, condition: impl Fn(&RTCConfiguration) -> bool) -> RTCConfiguration {
+    for _ in 0..100 {
+        let peer_connection = client.peer_connection.lock().unwrap().clone();
+        let configuration = peer_connection.get_configuration().await;
+        if condition(&configuration) {
+            return configuration;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("configuration didn't match in 10 seconds");
+}
+
+fn urls(configuration: &RTCConfiguration) -> Vec<String> {
+    configuration.ice_servers.iter().flat_map(|ice_server| ice_server.urls.clone()).collect()
+}
+
+#[tokio::test]
+async fn clients_use_the_servers_ice_configuration() {
+    let server = common::start(Config {
+        ice: Some(ice_config()),
+        ..Config::default()
+    })
+    .await;
+    let url = format!("{}/one-to-many", server.url.replacen("http", "ws", 1));
+    let supplied = RTCIceServer {
+        urls: vec!["stun:supplied.example.com:3478".to_string()],
+        ..RTCIceServer::default()
+    };
+    let client = EzRTCClient::new(url, "room".to_string(), vec![supplied], Arc::new(Box::new(Handler))).await;
+
+    // the advertised servers replace the supplied ones
+    let configuration = configuration_matching(&client, |configuration| configuration.ice_transport_policy == RTCIceTransportPolicy::Relay).await;
+    assert_eq!(urls(&configuration), ["stun:stun.example.com:3478"]);
+}
+
+#[tokio::test]
+async fn joining_another_session_keeps_the_servers_ice_configuration() {
+    let server = common::start(Config {
+        ice: Some(ice_config()),
+        ..Config::default()
+    })
+    .await;
+    let url = format!("{}/one-to-many", server.url.replacen("http", "ws", 1));
+    let supplied = RTCIceServer {
+        urls: vec!["stun:supplied.example.com:3478".to_string()],
+        ..RTCIceServer::default()
+    };
+    let client = EzRTCClient::new(url, "first".to_string(), vec![supplied], Arc::new(Box::new(Handler))).await;
+
+    let relayed = |configuration: &RTCConfiguration| configuration.ice_transport_policy == RTCIceTransportPolicy::Relay;
+    configuration_matching(&client, relayed).await;
+
+    client.join_session("second".to_string()).await.unwrap();
+
+    // the new peer connection is configured before the server advertises its configuration again
+    let peer_connection = client.peer_connection.lock().unwrap().clone();
+    let configuration = peer_connection.get_configuration().await;
+    assert!(relayed(&configuration));
+    assert_eq!(urls(&configuration), ["stun:stun.example.com:3478"]);
+}
+
+
+#[tokio::test]
+async fn joining_another_session_gets_its_turn_credentials() {
+    let server = common::start(Config {
+        turn: Some(TurnConfig {
+            secret: "turn-secret".to_string(),
+            uris: vec!["turn:turn.example.com:3478".to_string()],
+            ttl: 600,
+            server: None,
+        }),
+        ..Config::default()
+    })
+    .await;
+    let first_host = server.connect().await;
+    let second_host = server.connect().await;
+    first_host.join("first", true).await;
+    second_host.join("second", true).await;
+
+    let url = format!("{}/one-to-many", server.url.replacen("http", "ws", 1));
+    let supplied = RTCIceServer {
+        urls: vec!["stun:supplied.example.com:3478".to_string()],
+        ..RTCIceServer::default()
+    };
+    let client = EzRTCClient::new(url, "first".to_string(), vec![supplied], Arc::new(Box::new(Handler))).await;
+    assert!(client.ice_servers[1].username.ends_with(":first"));
+
+    client.join_session("second".to_string()).await.unwrap();
+
+    let peer_connection = client.peer_connection.lock().unwrap().clone();
+    let configuration = peer_connection.get_configuration().await;
+    assert_eq!(urls(&configuration), ["stun:supplied.example.com:3478", "turn:turn.example.com:3478"]);
+    assert!(configuration.ice_servers[1].username.ends_with(":second"));
+}
@@ -0,0 +1,150 @@
+//! Signaling server running in the test process, users connect over HTTP long polling so every message is handled before the request returns
+
+#![allow(dead_code)]
+
+use ezrtc::protocol::{Handshake, PollSession, SessionId, SignalMessage, UserId};
+use ezrtc_server::config::Config;
+use ezrtc_server::router::{self, ServerState};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+pub struct TestServer {
+    pub url: String,
+    pub state: ServerState,
+    client: reqwest::Client,
+}
+
+pub async fn start(config: Config) -> TestServer {
+    start_with_state(ServerState::with_config(config)).await
+}
+
+pub async fn start_with_state(state: ServerState) -> TestServer {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = router::create(state.clone());
+
+    tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap() });
+
+    TestServer {
+        url,
+        state,
+        client: reqwest::Client::new(),
+    }
+}
+
+impl TestServer {
+    pub async fn connect(&self) -> User {
+        self.connect_with_key(None).await
+    }
+
+    pub async fn connect_with_key(&self, app_key: Option<&str>) -> User {
+        let mut request = self.client.post(format!("{}/poll", self.url));
+        if let Some(app_key) = app_key {
+            request = request.header("x-app-key", app_key);
+        }
+
+        let session: PollSession = request.send().await.unwrap().error_for_status().unwrap().json().await.unwrap();
+
+        User {
+            user_id: session.user_id,
+            url: format!("{}/poll/{}", self.url, session.token),
+            client: self.client.clone(),
+            inbox: Mutex::new(Vec::new()),
+            next_request_id: AtomicU32::new(1),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.get(format!("{}{path}", self.url))
+    }
+
+    pub fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.post(format!("{}{path}", self.url))
+    }
+
+    pub fn put(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.put(format!("{}{path}", self.url))
+    }
+
+    pub fn delete(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.delete(format!("{}{path}", self.url))
+    }
+}
+
+/// User connected over long polling, it speaks protocol version 1
+pub struct User {
+    pub user_id: UserId,
+    url: String,
+    client: reqwest::Client,
+    /// Messages received while waiting for an answer to a request
+    inbox: Mutex<Vec<SignalMessage>>,
+    next_request_id: AtomicU32,
+}
+
+impl User {
+    pub async fn send(&self, message: SignalMessage) {
+        let body = message.encode(1).unwrap();
+        self.client.post(&self.url).body(body).send().await.unwrap().error_for_status().unwrap();
+    }
+
+    /// Send the message as a request and return the server's answer, `Err` contains the reason it was rejected
+    pub async fn request(&self, message: SignalMessage) -> Result<(), String> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed).to_string();
+        self.request_with_id(&request_id, message).await
+    }
+
+    /// [`User::request`] with the request id of an earlier request, like a client that retries
+    pub async fn request_with_id(&self, request_id: &str, message: SignalMessage) -> Result<(), String> {
+        self.send(SignalMessage::Request(request_id.to_string(), Box::new(message))).await;
+
+        let mut answer = None;
+        for message in self.poll().await {
+            match message {
+                SignalMessage::Ack(_) => answer = Some(Ok(())),
+                SignalMessage::Nack(_, reason) => answer = Some(Err(reason)),
+                message => self.inbox.lock().unwrap().push(message),
+            }
+        }
+
+        answer.unwrap_or_else(|| panic!("no answer to the request of user {:?}", self.user_id))
+    }
+
+    /// Messages received by the user without the server's keep alives, waits up to a second if there are none
+    pub async fn receive(&self) -> Vec<SignalMessage> {
+        let mut messages: Vec<_> = self.inbox.lock().unwrap().drain(..).collect();
+        if messages.is_empty() {
+            messages = self.poll().await;
+        }
+
+        messages
+    }
+
+    async fn poll(&self) -> Vec<SignalMessage> {
+        let messages: Vec<serde_json::Value> = self.client.get(format!("{}?timeout=1", self.url)).send().await.unwrap().json().await.unwrap();
+
+        messages
+            .into_iter()
+            .map(|message| SignalMessage::decode(&message.to_string()).unwrap())
+            .filter(|message| !matches!(message, SignalMessage::KeepAlive(_, _)))
+            .collect()
+    }
+
+    pub async fn close(&self) {
+        self.client.delete(&self.url).send().await.unwrap();
+    }
+
+    /// Negotiate the newest protocol version, messages sent by the user stay in the version 1 format
+    pub async fn hello(&self) {
+        self.send(SignalMessage::Hello(Handshake::new(Vec::new()))).await;
+        assert!(matches!(&self.receive().await[..], [SignalMessage::Welcome(_, _)]));
+    }
+
+    pub async fn join(&self, session: &str, is_host: bool) {
+        self.request(SignalMessage::SessionJoin(session_id(session), is_host, None)).await.unwrap();
+    }
+}
+
+pub fn session_id(session: &str) -> SessionId {
+    SessionId::new(session.to_string())
+}
@@ -0,0 +1,224 @@
+mod common;
+
+use common::TestServer;
+use ezrtc::protocol::{PollSession, UserId};
+use ezrtc_server::config::Config;
+use ezrtc_server::firewall::{Cidr, Firewall, FirewallConfig, FirewallLists, FirewallStatus};
+use reqwest::StatusCode;
+use std::net::IpAddr;
+use std::time::Duration;
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+fn cidr(cidr: &str) -> Cidr {
+    cidr.parse().unwrap()
+}
+
+#[test]
+fn parses_ranges_and_plain_addresses() {
+    assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+    assert_eq!(cidr(" 192.168.1.1 ").to_string(), "192.168.1.1/32");
+    assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+    assert_eq!(cidr("::ffff:10.0.0.1").to_string(), "10.0.0.1/32");
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("2001:db8::/129".parse::<Cidr>().is_err());
+    assert!("10.0.0.0/".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+    assert!("example.com".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn contains_addresses_in_the_range() {
+    assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+    assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+    assert!(cidr("192.168.1.1").contains(ip("192.168.1.1")));
+    assert!(!cidr("192.168.1.1").contains(ip("192.168.1.2")));
+    assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+    assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+
+    assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+    assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+
+    // clients connecting over a dual stack socket
+    assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+}
+
+#[test]
+fn allow_and_deny_lists() {
+    let firewall = Firewall::new(FirewallConfig {
+        allow: vec!["10.0.0.0/8".to_string()],
+        deny: vec!["10.0.0.1".to_string()],
+        ..FirewallConfig::default()
+    });
+
+    assert!(firewall.is_allowed(ip("10.0.0.2")));
+    assert!(!firewall.is_allowed(ip("10.0.0.1")));
+    assert!(!firewall.is_allowed(ip("192.168.1.1")));
+
+    // invalid lists keep the current ones
+    let invalid = FirewallLists {
+        allow: Vec::new(),
+        deny: vec!["10.0.0.0/99".to_string()],
+    };
+    assert!(firewall.reload(invalid).is_err());
+    assert!(!firewall.is_allowed(ip("192.168.1.1")));
+
+    firewall.reload(FirewallLists::default()).unwrap();
+    assert!(firewall.is_allowed(ip("192.168.1.1")));
+}
+
+#[test]
+fn bans_addresses_after_enough_strikes() {
+    let firewall = Firewall::new(FirewallConfig {
+        strikes: 3,
+        ..FirewallConfig::default()
+    });
+    let (first, second, other) = (UserId::new(1), UserId::new(2), UserId::new(3));
+    firewall.track(first, Some(ip("10.0.0.1")));
+    firewall.track(second, Some(ip("::ffff:10.0.0.1")));
+    firewall.track(other, Some(ip("10.0.0.2")));
+
+    assert!(firewall.strike(first).is_empty());
+    assert!(firewall.strike(second).is_empty());
+    assert!(firewall.strike(other).is_empty());
+
+    let banned = firewall.strike(first);
+    assert_eq!(banned.len(), 2);
+    assert!(banned.contains(&first) && banned.contains(&second));
+    assert!(!firewall.is_allowed(ip("10.0.0.1")));
+    assert!(firewall.is_allowed(ip("10.0.0.2")));
+
+    let bans = firewall.status().bans;
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].address, ip("10.0.0.1"));
+
+    assert!(firewall.unban(ip("10.0.0.1")));
+    assert!(!firewall.unban(ip("10.0.0.1")));
+    assert!(firewall.is_allowed(ip("10.0.0.1")));
+}
+
+#[test]
+fn untracked_users_are_not_counted() {
+    let firewall = Firewall::new(FirewallConfig {
+        strikes: 1,
+        ..FirewallConfig::default()
+    });
+    let user_id = UserId::new(1);
+    firewall.track(user_id, None);
+
+    assert!(firewall.strike(user_id).is_empty());
+    assert!(firewall.status().bans.is_empty());
+}
+
+#[tokio::test]
+async fn strikes_and_bans_expire() {
+    let firewall = Firewall::new(FirewallConfig {
+        strikes: 2,
+        strike_window: 1,
+        ban_duration: 1,
+        ..FirewallConfig::default()
+    });
+    let user_id = UserId::new(1);
+    firewall.track(user_id, Some(ip("10.0.0.1")));
+
+    // the second strike comes after the window of the first one
+    assert!(firewall.strike(user_id).is_empty());
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(firewall.strike(user_id).is_empty());
+
+    assert_eq!(firewall.strike(user_id), [user_id]);
+    assert!(!firewall.is_allowed(ip("10.0.0.1")));
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(firewall.is_allowed(ip("10.0.0.1")));
+    assert!(firewall.status().bans.is_empty());
+}
+
+fn admin_config() -> Config {
+    Config {
+        api_token: Some("secret".to_string()),
+        firewall: FirewallConfig {
+            strikes: 1,
+            trust_forwarded_for: true,
+            ..FirewallConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+/// Connect from the address and send an invalid message, the test server bans it after one strike
+async fn ban(server: &TestServer, address: &str) {
+    let session: PollSession = server.post("/poll").header("x-forwarded-for", address).send().await.unwrap().json().await.unwrap();
+    server.post(&format!("/poll/{}", session.token)).header("x-forwarded-for", address).body("not a message").send().await.unwrap();
+}
+
+async fn status(server: &TestServer) -> FirewallStatus {
+    server.get("/admin/firewall").bearer_auth("secret").send().await.unwrap().error_for_status().unwrap().json().await.unwrap()
+}
+
+#[tokio::test]
+async fn admin_routes_require_the_api_token() {
+    let server = common::start(admin_config()).await;
+
+    assert_eq!(server.get("/admin/firewall").send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(server.get("/admin/firewall").bearer_auth("wrong").send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(server.delete("/admin/firewall/bans/10.0.0.1").send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+    // the routes don't exist without a token
+    let server = common::start(Config::default()).await;
+    assert_eq!(server.get("/admin/firewall").bearer_auth("secret").send().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn lists_and_lifts_bans() {
+    let server = common::start(admin_config()).await;
+    ban(&server, "10.0.0.1").await;
+
+    let response = server.post("/poll").header("x-forwarded-for", "10.0.0.1").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let bans = status(&server).await.bans;
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].address, ip("10.0.0.1"));
+
+    assert_eq!(server.delete("/admin/firewall/bans/10.0.0.1").bearer_auth("secret").send().await.unwrap().status(), StatusCode::NO_CONTENT);
+    assert_eq!(server.delete("/admin/firewall/bans/10.0.0.1").bearer_auth("secret").send().await.unwrap().status(), StatusCode::NOT_FOUND);
+    assert_eq!(server.delete("/admin/firewall/bans/not-an-address").bearer_auth("secret").send().await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+    assert!(status(&server).await.bans.is_empty());
+    let response = server.post("/poll").header("x-forwarded-for", "10.0.0.1").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn replaces_and_reloads_the_lists() {
+    let server = common::start(admin_config()).await;
+
+    let lists = FirewallLists {
+        allow: Vec::new(),
+        deny: vec!["10.0.0.0/8".to_string()],
+    };
+    let updated: FirewallStatus = server.put("/admin/firewall").bearer_auth("secret").json(&lists).send().await.unwrap().error_for_status().unwrap().json().await.unwrap();
+    assert_eq!(updated.lists.deny, ["10.0.0.0/8"]);
+    assert_eq!(server.post("/poll").header("x-forwarded-for", "10.1.2.3").send().await.unwrap().status(), StatusCode::FORBIDDEN);
+
+    let invalid = FirewallLists {
+        allow: vec!["10.0.0.0/99".to_string()],
+        deny: Vec::new(),
+    };
+    assert_eq!(server.put("/admin/firewall").bearer_auth("secret").json(&invalid).send().await.unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(status(&server).await.lists.deny, ["10.0.0.0/8"]);
+
+    // the config file has different lists now
+    let path = std::env::temp_dir().join(format!("ezrtc-firewall-{}.json", std::process::id()));
+    std::fs::write(&path, r#"{ "firewall": { "deny": ["192.168.0.0/16"] } }"#).unwrap();
+    std::env::set_var("EZRTC_CONFIG", &path);
+    let reloaded: FirewallStatus = server.post("/admin/firewall/reload").bearer_auth("secret").send().await.unwrap().error_for_status().unwrap().json().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(reloaded.lists.deny, ["192.168.0.0/16"]);
+    assert_eq!(server.post("/poll").header("x-forwarded-for", "10.1.2.3").send().await.unwrap().status(), StatusCode::OK);
+}
@@ -0,0 +1,166 @@
+//! Rust host connected to a server in the test process
+
+mod common;
+
+use common::{session_id, TestServer};
+use ezrtc::host::EzRTCHost;
+use ezrtc::options::ConnectionOptions;
+use ezrtc::protocol::{Encoding, SdpPayload, SdpType, SignalMessage, UserId, PROTOCOL_VERSION};
+use ezrtc::socket::{DataChannelHandler, WSHost};
+use ezrtc::DataChannel;
+use ezrtc_server::config::Config;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+struct Handler;
+
+impl DataChannelHandler for Handler {
+    fn handle_data_channel_open(&self, _dc: DataChannel) {}
+
+    fn handle_data_channel_message(&self, _message: String) {}
+
+    fn handle_keep_alive(&self, _handle: &mut WSHost, _user_id: UserId) {}
+}
+
+async fn host(server: &TestServer, session: &str) -> EzRTCHost {
+    let url = format!("{}/one-to-many", server.url.replacen("http", "ws", 1));
+
+    EzRTCHost::new(url, session.to_string(), Vec::new(), Arc::new(Box::new(Handler))).await
+}
+
+/// Wait until the condition holds, the host connects and negotiates in the background
+async fn eventually(condition: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("condition wasn't met in 10 seconds");
+}
+
+#[tokio::test]
+async fn whep_users_get_a_data_channel() {
+    let server = common::start(Config::default()).await;
+    let host = host(&server, "room").await;
+
+    let peer_connection = ezrtc::client::create_peer_connection(RTCConfiguration::default(), Arc::new(Box::new(Handler))).await;
+    let data_channel = peer_connection.create_data_channel("data", None).await.unwrap();
+    let (messages, mut received) = mpsc::unbounded_channel();
+    data_channel.on_message(Box::new(move |message| {
+        let _ = messages.send(String::from_utf8(message.data.to_vec()).unwrap());
+        Box::pin(async {})
+    }));
+
+    let offer = peer_connection.create_offer(None).await.unwrap();
+    let mut gathered = peer_connection.gathering_complete_promise().await;
+    peer_connection.set_local_description(offer).await.unwrap();
+    let _ = gathered.recv().await;
+    let offer = peer_connection.local_description().await.unwrap().sdp;
+
+    // the host joins in the background
+    let mut response = None;
+    for _ in 0..100 {
+        let attempt = server.post("/whep/room").header("content-type", "application/sdp").body(offer.clone()).send().await.unwrap();
+        if attempt.status() != reqwest::StatusCode::NOT_FOUND {
+            response = Some(attempt);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let response = response.unwrap().error_for_status().unwrap();
+    let answer = RTCSessionDescription::answer(response.text().await.unwrap()).unwrap();
+    peer_connection.set_remote_description(answer).await.unwrap();
+
+    let data_channels = Arc::clone(&host.data_channels);
+    eventually(|| data_channels.lock().unwrap().len() == 1).await;
+
+    let host_channel = host.data_channels.lock().unwrap().values().next().unwrap().clone();
+    eventually(|| host_channel.ready_state() == ezrtc::RTCDataChannelState::Open).await;
+    host_channel.send_text("hello whep".to_string()).await.unwrap();
+    let message = tokio::time::timeout(Duration::from_secs(10), received.recv()).await.unwrap().unwrap();
+    assert_eq!(message, "hello whep");
+}
+
+#[tokio::test]
+async fn host_rejects_offers_from_connected_users() {
+    let server = common::start(Config::default()).await;
+    let _host = host(&server, "room").await;
+    let client = server.connect().await;
+
+    // the host offers to the client once both joined
+    client.join("room", false).await;
+    let mut host_id = None;
+    for _ in 0..10 {
+        host_id = client.receive().await.iter().find_map(|message| match message {
+            SignalMessage::SdpOffer(_, from, _) => Some(*from),
+            _ => None,
+        });
+        if host_id.is_some() {
+            break;
+        }
+    }
+    let host_id = host_id.expect("the host didn't offer");
+
+    let offer = SdpPayload::new(SdpType::Offer, "v=0\r\n".to_string(), PROTOCOL_VERSION);
+    client.request(SignalMessage::SdpOffer(session_id("room"), host_id, offer)).await.unwrap();
+
+    let mut rejected = false;
+    for _ in 0..5 {
+        rejected = client.receive().await.iter().any(|message| matches!(message, SignalMessage::Error(_, from, error) if *from == host_id && error == "Already connected"));
+        if rejected {
+            break;
+        }
+    }
+    assert!(rejected);
+}
+
+#[tokio::test]
+async fn host_forgets_clients_that_leave() {
+    let server = common::start(Config::default()).await;
+    let host = host(&server, "room").await;
+    let client = server.connect().await;
+
+    client.join("room", false).await;
+    let peer_connections = Arc::clone(&host.peer_connections);
+    eventually(|| peer_connections.lock().unwrap().contains_key(&client.user_id)).await;
+    assert!(host.roster().contains_key(&client.user_id));
+
+    client.request(SignalMessage::SessionLeave(session_id("room"))).await.unwrap();
+    eventually(|| peer_connections.lock().unwrap().is_empty()).await;
+    assert!(host.roster().is_empty());
+    assert!(host.data_channels.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn binary_hosts_talk_to_json_clients() {
+    for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+        let server = common::start(Config::default()).await;
+        let url = format!("{}/one-to-many", server.url.replacen("http", "ws", 1));
+        let options = ConnectionOptions {
+            encoding,
+            ..ConnectionOptions::default()
+        };
+        let host = EzRTCHost::with_options(url, "room".to_string(), Vec::new(), Arc::new(Box::new(Handler)), options).await;
+        let client = server.connect().await;
+
+        // the server encodes the JSON join for the host
+        client.request(SignalMessage::SessionJoin(session_id("room"), false, Some(json!({ "name": "Alice" })))).await.unwrap();
+        eventually(|| host.roster().get(&client.user_id) == Some(&json!({ "name": "Alice" }))).await;
+
+        // and decodes the host's binary offer for the client
+        let mut offered = false;
+        for _ in 0..10 {
+            offered = client.receive().await.iter().any(|message| matches!(message, SignalMessage::SdpOffer(_, _, offer) if offer.clone().into_sdp().starts_with("v=0")));
+            if offered {
+                break;
+            }
+        }
+        assert!(offered, "no offer from the {encoding:?} host");
+    }
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::{session_id, User};
+use ezrtc::protocol::{SignalMessage, UserId};
+use ezrtc_server::config::Config;
+
+fn leave(session: &str) -> SignalMessage {
+    SignalMessage::SessionLeave(session_id(session))
+}
+
+/// `true` if the user was told that `user_id` left the session
+async fn told_left(user: &User, session: &str, user_id: UserId) -> bool {
+    matches!(&user.receive().await[..], [SignalMessage::SessionLeft(left_session, left_user)] if *left_session == session_id(session) && *left_user == user_id)
+}
+
+#[tokio::test]
+async fn host_is_told_when_a_client_leaves_and_rejoins() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    let other = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    other.join("room", false).await;
+    host.receive().await;
+
+    client.request(leave("room")).await.unwrap();
+    assert!(told_left(&host, "room", client.user_id).await);
+    // the other clients only hear about the host
+    assert!(other.receive().await.is_empty());
+
+    client.join("room", false).await;
+    assert!(matches!(&host.receive().await[..], [SignalMessage::SessionReady(_, user_id, None)] if *user_id == client.user_id));
+}
+
+#[tokio::test]
+async fn clients_are_told_when_the_host_leaves() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let clients = [server.connect().await, server.connect().await];
+    host.join("room", true).await;
+    for client in &clients {
+        client.join("room", false).await;
+    }
+    host.receive().await;
+
+    host.request(leave("room")).await.unwrap();
+    for client in &clients {
+        assert!(told_left(client, "room", host.user_id).await);
+    }
+
+    // the clients stay in the session and meet the next host
+    let next_host = server.connect().await;
+    next_host.join("room", true).await;
+    let messages = next_host.receive().await;
+    assert_eq!(messages.len(), 2);
+    for client in &clients {
+        assert!(messages.iter().any(|message| matches!(message, SignalMessage::SessionReady(_, user_id, None) if *user_id == client.user_id)));
+    }
+}
+
+#[tokio::test]
+async fn disconnecting_counts_as_leaving() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    host.receive().await;
+
+    client.close().await;
+    assert!(told_left(&host, "room", client.user_id).await);
+
+    host.close().await;
+}
+
+#[tokio::test]
+async fn users_switch_sessions_over_the_same_connection() {
+    let server = common::start(Config::default()).await;
+    let first_host = server.connect().await;
+    let second_host = server.connect().await;
+    let client = server.connect().await;
+    first_host.join("first", true).await;
+    second_host.join("second", true).await;
+    client.join("first", false).await;
+    first_host.receive().await;
+
+    client.request(leave("first")).await.unwrap();
+    client.join("second", false).await;
+
+    assert!(told_left(&first_host, "first", client.user_id).await);
+    assert!(matches!(&second_host.receive().await[..], [SignalMessage::SessionReady(session, user_id, None)] if *session == session_id("second") && *user_id == client.user_id));
+
+    // leaving again is rejected, the server doesn't tell anyone
+    assert_eq!(client.request(leave("first")).await, Err("User is not in the session".to_string()));
+    assert!(first_host.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn last_user_leaving_deletes_the_session() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    host.join("room", true).await;
+
+    host.request(leave("room")).await.unwrap();
+
+    // another user can host the session now
+    let next_host = server.connect().await;
+    next_host.join("room", true).await;
+    assert!(host.receive().await.is_empty());
+}
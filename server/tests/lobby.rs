@@ -0,0 +1,138 @@
+mod common;
+
+use common::{session_id, TestServer, User};
+use ezrtc::protocol::{SignalMessage, Status};
+use ezrtc_server::config::Config;
+use ezrtc_server::lobby::LobbyPage;
+use ezrtc_server::tenant::{Quotas, TenantConfig};
+use serde_json::json;
+
+fn tenant_config() -> Config {
+    Config {
+        tenants: vec![TenantConfig {
+            name: "acme".to_string(),
+            app_key: "acme-key".to_string(),
+            quotas: Quotas::default(),
+            allowed_origins: None,
+        }],
+        ..Config::default()
+    }
+}
+
+/// Host a session and announce it with a keep alive
+async fn host(server: &TestServer, app_key: Option<&str>, session: &str, metadata: serde_json::Value, public: bool) -> User {
+    let host = server.connect_with_key(app_key).await;
+    host.join(session, true).await;
+
+    let status = Status {
+        session_id: Some(session_id(session)),
+        is_host: Some(true),
+        version: Some("1.0.0".to_string()),
+        metadata: Some(metadata),
+        public: Some(public),
+    };
+    host.send(SignalMessage::KeepAlive(host.user_id, status)).await;
+
+    host
+}
+
+async fn list(server: &TestServer, app_key: Option<&str>, query: &str) -> LobbyPage {
+    let mut request = server.get(&format!("/sessions{query}"));
+    if let Some(app_key) = app_key {
+        request = request.header("x-app-key", app_key);
+    }
+
+    request.send().await.unwrap().error_for_status().unwrap().json().await.unwrap()
+}
+
+fn session_ids(page: &LobbyPage) -> Vec<&str> {
+    page.sessions.iter().map(|session| session.session_id.as_str()).collect()
+}
+
+#[tokio::test]
+async fn lists_public_sessions_with_their_clients() {
+    let server = common::start(Config::default()).await;
+    let _host = host(&server, None, "room", json!({ "name": "Room" }), true).await;
+    let _private = host(&server, None, "private", json!({}), false).await;
+    let client = server.connect().await;
+    client.join("room", false).await;
+
+    let page = list(&server, None, "").await;
+
+    assert_eq!(page.total, 1);
+    assert_eq!(session_ids(&page), ["room"]);
+    assert_eq!(page.sessions[0].clients, 1);
+    assert_eq!(page.sessions[0].metadata, Some(json!({ "name": "Room" })));
+    assert_eq!(page.sessions[0].version.as_deref(), Some("1.0.0"));
+}
+
+#[tokio::test]
+async fn filters_by_metadata() {
+    let server = common::start(Config::default()).await;
+    let _a = host(&server, None, "a", json!({ "game": "chess", "players": 2, "ranked": true }), true).await;
+    let _b = host(&server, None, "b", json!({ "game": "go", "players": 2, "ranked": false }), true).await;
+
+    assert_eq!(session_ids(&list(&server, None, "?metadata.game=chess").await), ["a"]);
+    assert_eq!(session_ids(&list(&server, None, "?metadata.players=2").await), ["a", "b"]);
+    assert_eq!(session_ids(&list(&server, None, "?metadata.ranked=false").await), ["b"]);
+    assert_eq!(session_ids(&list(&server, None, "?metadata.game=go&metadata.ranked=true").await), Vec::<&str>::new());
+    assert_eq!(session_ids(&list(&server, None, "?metadata.missing=1").await), Vec::<&str>::new());
+}
+
+#[tokio::test]
+async fn paginates_sorted_by_session_id() {
+    let server = common::start(Config::default()).await;
+    let mut hosts = Vec::new();
+    for session in ["c", "a", "b"] {
+        hosts.push(host(&server, None, session, json!({}), true).await);
+    }
+
+    let first = list(&server, None, "?per_page=2").await;
+    let second = list(&server, None, "?per_page=2&page=2").await;
+
+    assert_eq!((first.total, first.page, first.per_page), (3, 1, 2));
+    assert_eq!(session_ids(&first), ["a", "b"]);
+    assert_eq!(session_ids(&second), ["c"]);
+}
+
+#[tokio::test]
+async fn lists_only_the_tenants_sessions() {
+    let server = common::start(tenant_config()).await;
+    let _acme = host(&server, Some("acme-key"), "room", json!({ "tenant": "acme" }), true).await;
+    let _default = host(&server, None, "room", json!({ "tenant": "default" }), true).await;
+
+    let acme = list(&server, Some("acme-key"), "").await;
+    let default = list(&server, None, "").await;
+
+    assert_eq!(session_ids(&acme), ["room"]);
+    assert_eq!(acme.sessions[0].metadata, Some(json!({ "tenant": "acme" })));
+    assert_eq!(session_ids(&default), ["room"]);
+    assert_eq!(default.sessions[0].metadata, Some(json!({ "tenant": "default" })));
+}
+
+#[tokio::test]
+async fn only_hosts_announce_their_sessions() {
+    let server = common::start(Config::default()).await;
+    let host = host(&server, None, "room", json!({ "name": "Room" }), true).await;
+    let client = server.connect().await;
+    client.join("room", false).await;
+
+    let forged = |user_id, session: &str| {
+        let status = Status {
+            session_id: Some(session_id(session)),
+            is_host: Some(true),
+            version: None,
+            metadata: Some(json!({ "name": "Forged" })),
+            public: Some(true),
+        };
+        SignalMessage::KeepAlive(user_id, status)
+    };
+    // a client can't overwrite the host's announcement, add its own or list a session nobody hosts
+    client.send(forged(host.user_id, "room")).await;
+    client.send(forged(client.user_id, "room")).await;
+    client.send(forged(client.user_id, "empty")).await;
+
+    let page = list(&server, None, "").await;
+    assert_eq!(session_ids(&page), ["room"]);
+    assert_eq!(page.sessions[0].metadata, Some(json!({ "name": "Room" })));
+}
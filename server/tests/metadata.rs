@@ -0,0 +1,78 @@
+mod common;
+
+use common::session_id;
+use ezrtc::protocol::SignalMessage;
+use ezrtc_server::config::Config;
+use ezrtc_server::one_to_many::MAX_METADATA_SIZE;
+use serde_json::json;
+
+#[tokio::test]
+async fn forwards_metadata_between_host_and_client() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.hello().await;
+    client.hello().await;
+
+    host.request(SignalMessage::SessionJoin(session_id("room"), true, Some(json!({ "name": "Host" })))).await.unwrap();
+    client.request(SignalMessage::SessionJoin(session_id("room"), false, Some(json!({ "name": "Alice" })))).await.unwrap();
+
+    let received = host.receive().await;
+    assert!(matches!(&received[..], [SignalMessage::SessionReady(_, user_id, Some(metadata))] if *user_id == client.user_id && *metadata == json!({ "name": "Alice" })));
+    let received = client.receive().await;
+    assert!(matches!(&received[..], [SignalMessage::SessionReady(_, user_id, Some(metadata))] if *user_id == host.user_id && *metadata == json!({ "name": "Host" })));
+}
+
+#[tokio::test]
+async fn host_joining_late_gets_the_roster_with_metadata() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let alice = server.connect().await;
+    let bob = server.connect().await;
+    host.hello().await;
+    alice.request(SignalMessage::SessionJoin(session_id("room"), false, Some(json!({ "name": "Alice" })))).await.unwrap();
+    bob.join("room", false).await;
+
+    host.join("room", true).await;
+
+    let mut roster: Vec<_> = host
+        .receive()
+        .await
+        .into_iter()
+        .map(|message| match message {
+            SignalMessage::SessionReady(_, user_id, metadata) => (user_id.into_inner(), metadata),
+            message => panic!("unexpected message {message:?}"),
+        })
+        .collect();
+    roster.sort_by_key(|(user_id, _)| *user_id);
+
+    assert_eq!(roster, [(alice.user_id.into_inner(), Some(json!({ "name": "Alice" }))), (bob.user_id.into_inner(), None)]);
+    // the host joined without metadata, so the clients don't hear about it
+    assert!(alice.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn leaves_out_metadata_for_older_versions() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+
+    client.request(SignalMessage::SessionJoin(session_id("room"), false, Some(json!({ "name": "Alice" })))).await.unwrap();
+
+    assert!(matches!(&host.receive().await[..], [SignalMessage::SessionReady(_, user_id, None)] if *user_id == client.user_id));
+}
+
+#[tokio::test]
+async fn rejects_large_metadata() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+
+    let metadata = json!({ "name": "a".repeat(MAX_METADATA_SIZE) });
+    let answer = client.request(SignalMessage::SessionJoin(session_id("room"), false, Some(metadata))).await;
+
+    assert!(answer.unwrap_err().contains("Metadata is larger"));
+    assert!(host.receive().await.is_empty());
+}
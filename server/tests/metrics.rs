@@ -0,0 +1,34 @@
+mod common;
+
+use ezrtc_server::config::Config;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+#[tokio::test]
+async fn metrics_need_the_api_token() {
+    let server = common::start(Config {
+        api_token: Some("secret".to_string()),
+        ..Config::default()
+    })
+    .await;
+
+    assert_eq!(server.get("/metrics").send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(server.get("/metrics").bearer_auth("wrong").send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    let metrics: Value = server.get("/metrics").bearer_auth("secret").send().await.unwrap().error_for_status().unwrap().json().await.unwrap();
+    assert!(metrics.is_object());
+
+    // the route doesn't exist without a token
+    let server = common::start(Config::default()).await;
+    assert_eq!(server.get("/metrics").send().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn public_metrics_are_served_without_a_token() {
+    let server = common::start(Config {
+        public_metrics: true,
+        ..Config::default()
+    })
+    .await;
+
+    assert_eq!(server.get("/metrics").send().await.unwrap().status(), StatusCode::OK);
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use common::{session_id, TestServer, User};
+use ezrtc::protocol::SignalMessage;
+use ezrtc_server::config::Config;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+fn config() -> Config {
+    Config {
+        api_token: Some("secret".to_string()),
+        ..Config::default()
+    }
+}
+
+async fn notify(server: &TestServer, path: &str, token: &str) -> reqwest::Response {
+    server.post(path).bearer_auth(token).json(&json!({ "kind": "reload" })).send().await.unwrap()
+}
+
+/// `true` if the user received only the notification
+async fn notified(user: &User) -> bool {
+    matches!(&user.receive().await[..], [SignalMessage::Notify(session, payload)] if *session == session_id("room") && *payload == json!({ "kind": "reload" }))
+}
+
+#[tokio::test]
+async fn notifies_the_host_or_everyone() {
+    let server = common::start(config()).await;
+    let host = server.connect().await;
+    let clients = [server.connect().await, server.connect().await];
+    host.join("room", true).await;
+    for client in &clients {
+        client.join("room", false).await;
+    }
+    host.receive().await;
+
+    let response: Value = notify(&server, "/sessions/room/notify", "secret").await.error_for_status().unwrap().json().await.unwrap();
+    assert_eq!(response["delivered"], 1);
+    assert!(notified(&host).await);
+    for client in &clients {
+        assert!(client.receive().await.is_empty());
+    }
+
+    let response: Value = notify(&server, "/sessions/room/notify?target=all", "secret").await.error_for_status().unwrap().json().await.unwrap();
+    assert_eq!(response["delivered"], 3);
+    assert!(notified(&host).await);
+    for client in &clients {
+        assert!(notified(client).await);
+    }
+}
+
+#[tokio::test]
+async fn needs_the_api_token() {
+    let server = common::start(config()).await;
+    let host = server.connect().await;
+    host.join("room", true).await;
+
+    assert_eq!(notify(&server, "/sessions/room/notify", "wrong").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(server.post("/sessions/room/notify").json(&json!({})).send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+    // the endpoint doesn't exist without a token
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    host.join("room", true).await;
+    assert_eq!(notify(&server, "/sessions/room/notify", "secret").await.status(), StatusCode::NOT_FOUND);
+    assert!(host.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn unknown_sessions_are_not_found() {
+    let server = common::start(config()).await;
+    assert_eq!(notify(&server, "/sessions/room/notify", "secret").await.status(), StatusCode::NOT_FOUND);
+
+    // a session without a host has nobody to notify by default
+    let client = server.connect().await;
+    client.join("room", false).await;
+    assert_eq!(notify(&server, "/sessions/room/notify", "secret").await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(notify(&server, "/sessions/room/notify?target=all", "secret").await.status(), StatusCode::OK);
+}
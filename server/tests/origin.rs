@@ -0,0 +1,105 @@
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode, Uri};
+use ezrtc_server::config::Config;
+use ezrtc_server::origin;
+use ezrtc_server::router::{self, ServerState};
+use ezrtc_server::tenant::{Quotas, TenantConfig};
+use tower::ServiceExt;
+
+fn config() -> Config {
+    Config {
+        allowed_origins: Some(vec!["https://example.com".to_string(), "https://*.example.com".to_string()]),
+        tenants: vec![TenantConfig {
+            name: "chess".to_string(),
+            app_key: "chess-key".to_string(),
+            quotas: Quotas::default(),
+            allowed_origins: Some(vec!["https://chess.games.test".to_string()]),
+        }],
+        ..Config::default()
+    }
+}
+
+fn allowed(origin: &str) -> bool {
+    origin::is_allowed(&config(), &HeaderMap::new(), &Uri::from_static("/sessions"), origin)
+}
+
+#[test]
+fn matches_exact_origins() {
+    assert!(allowed("https://example.com"));
+    assert!(allowed("HTTPS://EXAMPLE.COM"));
+    assert!(!allowed("http://example.com"));
+    assert!(!allowed("https://example.org"));
+}
+
+#[test]
+fn wildcard_matches_subdomains_only() {
+    assert!(allowed("https://app.example.com"));
+    assert!(allowed("https://a.b.example.com"));
+
+    assert!(!allowed("https://.example.com"));
+    assert!(!allowed("https://evilexample.com"));
+    assert!(!allowed("http://app.example.com"));
+    assert!(!allowed("https://app.example.com.evil.test"));
+}
+
+#[test]
+fn everything_is_allowed_without_an_allowlist() {
+    assert!(origin::is_allowed(&Config::default(), &HeaderMap::new(), &Uri::from_static("/"), "https://anything.test"));
+}
+
+#[test]
+fn tenants_use_their_own_allowlist() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-app-key", HeaderValue::from_static("chess-key"));
+    let uri = Uri::from_static("/sessions");
+
+    assert!(origin::is_allowed(&config(), &headers, &uri, "https://chess.games.test"));
+    assert!(!origin::is_allowed(&config(), &headers, &uri, "https://example.com"));
+    assert!(origin::is_allowed(&config(), &HeaderMap::new(), &Uri::from_static("/sessions?app_key=chess-key"), "https://chess.games.test"));
+}
+
+async fn send(request: Request<Body>) -> (StatusCode, Option<String>) {
+    let app = router::create(ServerState::with_config(config()));
+    let response = app.oneshot(request).await.unwrap();
+    let allow_origin = response.headers().get("access-control-allow-origin").map(|value| value.to_str().unwrap().to_string());
+
+    (response.status(), allow_origin)
+}
+
+fn preflight(uri: &str, origin: &str) -> Request<Body> {
+    Request::options(uri)
+        .header("origin", origin)
+        .header("access-control-request-method", "GET")
+        .header("access-control-request-headers", "x-app-key")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn preflights_without_an_app_key_allow_tenant_origins() {
+    let (_, allow_origin) = send(preflight("/sessions", "https://chess.games.test")).await;
+    assert_eq!(allow_origin.as_deref(), Some("https://chess.games.test"));
+
+    let (_, allow_origin) = send(preflight("/sessions", "https://evil.test")).await;
+    assert_eq!(allow_origin, None);
+
+    // the key in the query selects the tenant's allowlist only
+    let (_, allow_origin) = send(preflight("/sessions?app_key=chess-key", "https://example.com")).await;
+    assert_eq!(allow_origin, None);
+}
+
+#[tokio::test]
+async fn requests_are_checked_against_their_tenant() {
+    let request = |origin: &str| Request::get("/sessions").header("origin", origin).header("x-app-key", "chess-key").body(Body::empty()).unwrap();
+
+    let (status, allow_origin) = send(request("https://chess.games.test")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(allow_origin.as_deref(), Some("https://chess.games.test"));
+
+    let (status, _) = send(request("https://example.com")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // the tenant's origin isn't allowed for requests without its key
+    let (status, _) = send(Request::get("/sessions").header("origin", "https://chess.games.test").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,174 @@
+//! HTTP long polling, with raw requests and with the Rust host falling back to it
+
+mod common;
+
+use common::{session_id, TestServer, User};
+use ezrtc::host::EzRTCHost;
+use ezrtc::options::ConnectionOptions;
+use ezrtc::protocol::{PollSession, SignalMessage, UserId};
+use ezrtc::socket::{DataChannelHandler, WSHost};
+use ezrtc::DataChannel;
+use ezrtc_server::config::Config;
+use ezrtc_server::firewall::FirewallConfig;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+struct Handler;
+
+impl DataChannelHandler for Handler {
+    fn handle_data_channel_open(&self, _dc: DataChannel) {}
+
+    fn handle_data_channel_message(&self, _message: String) {}
+
+    fn handle_keep_alive(&self, _handle: &mut WSHost, _user_id: UserId) {}
+}
+
+async fn connect(server: &TestServer) -> PollSession {
+    server.post("/poll").send().await.unwrap().error_for_status().unwrap().json().await.unwrap()
+}
+
+async fn send(server: &TestServer, session: &PollSession, message: SignalMessage) -> StatusCode {
+    server.post(&format!("/poll/{}", session.token)).body(message.encode(1).unwrap()).send().await.unwrap().status()
+}
+
+async fn receive(server: &TestServer, session: &PollSession) -> reqwest::Response {
+    server.get(&format!("/poll/{}?timeout=1", session.token)).send().await.unwrap()
+}
+
+/// Received messages without the server's keep alives
+async fn messages(server: &TestServer, session: &PollSession) -> Vec<SignalMessage> {
+    let messages: Vec<Value> = receive(server, session).await.error_for_status().unwrap().json().await.unwrap();
+
+    messages
+        .iter()
+        .map(|message| SignalMessage::decode(&message.to_string()).unwrap())
+        .filter(|message| !matches!(message, SignalMessage::KeepAlive(_, _)))
+        .collect()
+}
+
+#[tokio::test]
+async fn exchanges_messages_over_http() {
+    let server = common::start(Config::default()).await;
+    let host = connect(&server).await;
+    let client = connect(&server).await;
+    assert_ne!(host.token, client.token);
+    assert_ne!(host.user_id, client.user_id);
+
+    // nothing to receive yet, the request waits for the timeout
+    assert!(messages(&server, &host).await.is_empty());
+
+    assert_eq!(send(&server, &host, SignalMessage::SessionJoin(session_id("room"), true, None)).await, StatusCode::ACCEPTED);
+    assert_eq!(send(&server, &client, SignalMessage::SessionJoin(session_id("room"), false, None)).await, StatusCode::ACCEPTED);
+
+    assert!(matches!(&messages(&server, &host).await[..], [SignalMessage::SessionReady(_, user_id, None)] if *user_id == client.user_id));
+}
+
+#[tokio::test]
+async fn closed_connections_forget_their_token() {
+    let server = common::start(Config::default()).await;
+    let session = connect(&server).await;
+
+    assert_eq!(server.delete(&format!("/poll/{}", session.token)).send().await.unwrap().status(), StatusCode::NO_CONTENT);
+    // the connection is removed in the background
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(receive(&server, &session).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(send(&server, &session, SignalMessage::SessionJoin(session_id("room"), true, None)).await, StatusCode::NOT_FOUND);
+    assert_eq!(server.delete(&format!("/poll/{}", session.token)).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+    assert_eq!(server.get("/poll/unknown").send().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn connections_closed_by_the_server_are_gone() {
+    let server = common::start(Config {
+        firewall: FirewallConfig {
+            strikes: 1,
+            trust_forwarded_for: true,
+            ..FirewallConfig::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let session: PollSession = server.post("/poll").header("x-forwarded-for", "10.0.0.1").send().await.unwrap().json().await.unwrap();
+
+    // the invalid message gets the address banned and the connection closed, the test polls from another address
+    server.post(&format!("/poll/{}", session.token)).header("x-forwarded-for", "10.0.0.1").body("not a message").send().await.unwrap();
+
+    assert_eq!(receive(&server, &session).await.status(), StatusCode::GONE);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(receive(&server, &session).await.status(), StatusCode::NOT_FOUND);
+}
+
+/// Host that can't open the websocket, the url has no websocket route but long polling works next to it
+async fn polling_host(server: &TestServer, session: &str) -> EzRTCHost {
+    let url = format!("{}/no-websocket", server.url.replacen("http", "ws", 1));
+    let options = ConnectionOptions {
+        long_poll_fallback: true,
+        ..ConnectionOptions::default()
+    };
+
+    EzRTCHost::with_options(url, session.to_string(), Vec::new(), Arc::new(Box::new(Handler)), options).await
+}
+
+/// Join the session as a client until the host offers a connection, the host connects in the background
+async fn offered(user: &User, session: &str, host_id: Option<UserId>) {
+    user.join(session, false).await;
+
+    for _ in 0..30 {
+        let messages = user.receive().await;
+        if messages.iter().any(|message| matches!(message, SignalMessage::SdpOffer(_, from, _) if host_id.is_none_or(|host_id| *from == host_id))) {
+            return;
+        }
+    }
+
+    panic!("the host didn't send an offer");
+}
+
+#[tokio::test]
+async fn host_falls_back_to_long_polling() {
+    let server = common::start(Config::default()).await;
+    let host = polling_host(&server, "room").await;
+
+    let client = server.connect().await;
+    offered(&client, "room", None).await;
+    assert!(host.handle.user_id().is_some());
+}
+
+#[tokio::test]
+async fn host_reconnects_when_the_server_forgets_it() {
+    let server = common::start(Config {
+        api_token: Some("secret".to_string()),
+        firewall: FirewallConfig {
+            strikes: 1,
+            trust_forwarded_for: true,
+            ..FirewallConfig::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let host = polling_host(&server, "room").await;
+    let client = server.connect().await;
+    offered(&client, "room", None).await;
+    let first_id = host.handle.user_id().unwrap();
+
+    // banning the address closes the host's connection, it reconnects once the ban is lifted
+    let session: PollSession = server.post("/poll").header("x-forwarded-for", "127.0.0.1").send().await.unwrap().json().await.unwrap();
+    server.post(&format!("/poll/{}", session.token)).header("x-forwarded-for", "127.0.0.1").body("not a message").send().await.unwrap();
+    let unban = server.delete("/admin/firewall/bans/127.0.0.1").header("x-forwarded-for", "10.0.0.1").bearer_auth("secret").send().await.unwrap();
+    assert_eq!(unban.status(), StatusCode::NO_CONTENT);
+
+    let client = server.connect().await;
+    for _ in 0..100 {
+        if host.handle.user_id().is_some_and(|user_id| user_id != first_id) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let second_id = host.handle.user_id().unwrap();
+    assert_ne!(second_id, first_id);
+
+    // the host joined its session again with the new connection
+    offered(&client, "room", Some(second_id)).await;
+}
@@ -0,0 +1,76 @@
+//! Two server instances sharing sessions through Redis, runs if `EZRTC_TEST_REDIS_URL` points to a Redis server
+
+#![cfg(feature = "redis")]
+
+mod common;
+
+use common::{session_id, TestServer};
+use ezrtc::protocol::SignalMessage;
+use ezrtc_server::config::Config;
+use ezrtc_server::router::ServerState;
+use ezrtc_server::store::{RedisBus, RedisStore};
+use redis::AsyncCommands;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn redis_url() -> Option<String> {
+    std::env::var("EZRTC_TEST_REDIS_URL").ok()
+}
+
+async fn instance(url: &str) -> TestServer {
+    let state = ServerState::new(Config::default(), Arc::new(RedisStore::connect(url).await.unwrap()), Arc::new(RedisBus::connect(url).await.unwrap()));
+
+    common::start_with_state(state).await
+}
+
+/// Session id that isn't used by earlier runs against the same Redis server
+fn unique_session() -> String {
+    format!("redis-{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos())
+}
+
+#[tokio::test]
+async fn host_and_client_on_different_instances() {
+    let Some(url) = redis_url() else { return };
+    let first = instance(&url).await;
+    let second = instance(&url).await;
+    let session = unique_session();
+
+    let host = first.connect().await;
+    let client = second.connect().await;
+    host.join(&session, true).await;
+    client.join(&session, false).await;
+
+    assert!(matches!(&host.receive().await[..], [SignalMessage::SessionReady(_, user_id, _)] if *user_id == client.user_id));
+
+    client.request(SignalMessage::Error(session_id(&session), host.user_id, "across instances".to_string())).await.unwrap();
+    assert!(matches!(&host.receive().await[..], [SignalMessage::Error(_, user_id, error)] if *user_id == client.user_id && error == "across instances"));
+}
+
+#[tokio::test]
+async fn session_is_deleted_once_everyone_left() {
+    let Some(url) = redis_url() else { return };
+    let first = instance(&url).await;
+    let second = instance(&url).await;
+    let session = unique_session();
+
+    let host = first.connect().await;
+    let client = second.connect().await;
+    host.join(&session, true).await;
+    client.join(&session, false).await;
+
+    let mut connection = redis::Client::open(url.as_str()).unwrap().get_multiplexed_async_connection().await.unwrap();
+    let users_key = format!("ezrtc:session:{session}:users");
+    let ttl: i64 = connection.ttl(&users_key).await.unwrap();
+    assert!(ttl > 0, "session entries don't expire");
+
+    // leaving from both instances at once
+    let (host_left, client_left) = tokio::join!(
+        host.request(SignalMessage::SessionLeave(session_id(&session))),
+        client.request(SignalMessage::SessionLeave(session_id(&session)))
+    );
+    host_left.unwrap();
+    client_left.unwrap();
+
+    let exists: bool = connection.exists(vec![format!("ezrtc:session:{session}:host"), users_key]).await.unwrap();
+    assert!(!exists);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::session_id;
+use ezrtc::protocol::SignalMessage;
+use ezrtc_server::config::Config;
+use ezrtc_server::relay::RelayConfig;
+
+fn relay_config() -> Config {
+    Config {
+        relay: Some(RelayConfig { bandwidth_limit: 1_000_000 }),
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn relays_between_host_and_client() {
+    let server = common::start(relay_config()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    host.receive().await;
+    client.receive().await;
+
+    host.request(SignalMessage::RelayOpen(session_id("room"), client.user_id)).await.unwrap();
+    host.request(SignalMessage::RelayData(session_id("room"), client.user_id, "hello".to_string())).await.unwrap();
+    client.request(SignalMessage::RelayClose(session_id("room"), host.user_id)).await.unwrap();
+
+    let received = client.receive().await;
+    assert!(matches!(&received[..], [SignalMessage::RelayOpen(_, from), SignalMessage::RelayData(_, _, data)] if *from == host.user_id && data == "hello"));
+    assert!(matches!(&host.receive().await[..], [SignalMessage::RelayClose(_, from)] if *from == client.user_id));
+}
+
+#[tokio::test]
+async fn rejects_relays_outside_the_session() {
+    let server = common::start(relay_config()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    let stranger = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    stranger.join("other", false).await;
+    host.receive().await;
+
+    // claiming the host's session doesn't make the stranger a member
+    assert!(stranger.request(SignalMessage::RelayOpen(session_id("room"), host.user_id)).await.is_err());
+    assert!(stranger.request(SignalMessage::RelayData(session_id("room"), host.user_id, "spoofed".to_string())).await.is_err());
+    assert!(stranger.request(SignalMessage::RelayClose(session_id("room"), client.user_id)).await.is_err());
+    // clients can only reach their host
+    assert!(client.request(SignalMessage::RelayData(session_id("room"), stranger.user_id, "hello".to_string())).await.is_err());
+
+    assert!(host.receive().await.is_empty());
+    assert!(stranger.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn rejects_relays_if_disabled() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+
+    let error = client.request(SignalMessage::RelayOpen(session_id("room"), host.user_id)).await.unwrap_err();
+    assert_eq!(error, "Relay is disabled on this server");
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::TestServer;
+use ezrtc::protocol::{SessionReservation, SignalMessage};
+use ezrtc_server::config::Config;
+use ezrtc_server::tenant::{Quotas, TenantConfig};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+fn limited_config() -> Config {
+    Config {
+        api_token: Some("secret".to_string()),
+        quotas: Quotas {
+            reservation_rate: Some(2),
+            ..Quotas::default()
+        },
+        ..Config::default()
+    }
+}
+
+async fn reserve(server: &TestServer) -> reqwest::Response {
+    server.post("/sessions").send().await.unwrap()
+}
+
+#[tokio::test]
+async fn hosts_claim_reserved_sessions_and_clients_find_them_by_code() {
+    let server = common::start(Config::default()).await;
+    let reservation: SessionReservation = reserve(&server).await.error_for_status().unwrap().json().await.unwrap();
+    assert_eq!(reservation.code.len(), 6);
+
+    // codes are case insensitive and can contain separators
+    let code = format!("{}-{}", &reservation.code[..3], &reservation.code[3..]).to_lowercase();
+    let found: Value = server.get(&format!("/join/{code}")).send().await.unwrap().error_for_status().unwrap().json().await.unwrap();
+    assert_eq!(found["session_id"], reservation.session_id.as_str());
+    assert_eq!(server.get("/join/AAAAAA").send().await.unwrap().status(), StatusCode::NOT_FOUND);
+
+    let host = server.connect().await;
+    let intruder = server.connect().await;
+    let wrong_token = SignalMessage::SessionClaim(reservation.session_id.clone(), "wrong".to_string(), None);
+    assert!(intruder.request(wrong_token).await.is_err());
+    assert!(intruder.request(SignalMessage::SessionJoin(reservation.session_id.clone(), true, None)).await.is_err());
+
+    let claim = SignalMessage::SessionClaim(reservation.session_id.clone(), reservation.claim_token.clone(), None);
+    host.request(claim).await.unwrap();
+}
+
+#[tokio::test]
+async fn limits_reservations_per_address() {
+    let server = common::start(limited_config()).await;
+
+    assert_eq!(reserve(&server).await.status(), StatusCode::OK);
+    assert_eq!(reserve(&server).await.status(), StatusCode::OK);
+    assert_eq!(reserve(&server).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // backends reserve for their users with the API token
+    for _ in 0..3 {
+        let response = server.post("/sessions").bearer_auth("secret").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = server.post("/sessions").bearer_auth("wrong").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn tenants_have_separate_limits() {
+    let server = common::start(Config {
+        tenants: vec![TenantConfig {
+            name: "chess".to_string(),
+            app_key: "chess-key".to_string(),
+            quotas: Quotas {
+                reservation_rate: Some(1),
+                ..Quotas::default()
+            },
+            allowed_origins: None,
+        }],
+        ..Config::default()
+    })
+    .await;
+
+    // servers with tenants don't reserve sessions for requests without an app key
+    assert_eq!(reserve(&server).await.status(), StatusCode::UNAUTHORIZED);
+
+    let reservation: SessionReservation = server.post("/sessions").header("x-app-key", "chess-key").send().await.unwrap().error_for_status().unwrap().json().await.unwrap();
+    assert_eq!(server.post("/sessions").header("x-app-key", "chess-key").send().await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // codes only resolve within the tenant
+    let found = server.get(&format!("/join/{}", reservation.code)).header("x-app-key", "chess-key").send().await.unwrap();
+    assert_eq!(found.status(), StatusCode::OK);
+    assert_eq!(server.get(&format!("/join/{}", reservation.code)).send().await.unwrap().status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use common::session_id;
+use ezrtc::protocol::{IceCandidateJSON, IceConfig, IcePayload, IceServer, IceTransportPolicy, SdpPayload, SdpType, SignalMessage, PROTOCOL_VERSION};
+use ezrtc_server::config::Config;
+
+fn offer() -> SdpPayload {
+    SdpPayload::new(SdpType::Offer, "v=0\r\n".to_string(), PROTOCOL_VERSION)
+}
+
+fn answer() -> SdpPayload {
+    SdpPayload::new(SdpType::Answer, "v=0\r\n".to_string(), PROTOCOL_VERSION)
+}
+
+fn candidate() -> IcePayload {
+    let candidate = IceCandidateJSON {
+        candidate: "candidate:1 1 UDP 2122252543 192.168.1.2 50000 typ host".to_string(),
+        sdp_mid: Some("0".to_string()),
+        sdp_mline_index: Some(0),
+        username_fragment: None,
+    };
+    IcePayload::new(candidate, PROTOCOL_VERSION)
+}
+
+#[tokio::test]
+async fn passes_offers_and_errors_on_within_the_session() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    host.receive().await;
+
+    host.request(SignalMessage::SdpOffer(session_id("room"), client.user_id, offer())).await.unwrap();
+    client.request(SignalMessage::Error(session_id("room"), host.user_id, "Failed to answer".to_string())).await.unwrap();
+
+    assert!(matches!(&client.receive().await[..], [SignalMessage::SdpOffer(_, from, _)] if *from == host.user_id));
+    assert!(matches!(&host.receive().await[..], [SignalMessage::Error(_, from, error)] if *from == client.user_id && error == "Failed to answer"));
+}
+
+#[tokio::test]
+async fn rejects_offers_and_errors_outside_the_session() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    let stranger = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    stranger.join("other", true).await;
+    host.receive().await;
+
+    // naming the host's session doesn't make the stranger a member
+    assert!(stranger.request(SignalMessage::SdpOffer(session_id("room"), host.user_id, offer())).await.is_err());
+    assert!(stranger.request(SignalMessage::Error(session_id("room"), host.user_id, "spoofed".to_string())).await.is_err());
+    assert!(stranger.request(SignalMessage::Error(session_id("other"), client.user_id, "spoofed".to_string())).await.is_err());
+    // clients can only reach their host
+    assert!(client.request(SignalMessage::Error(session_id("room"), stranger.user_id, "hello".to_string())).await.is_err());
+
+    assert!(host.receive().await.is_empty());
+    assert!(client.receive().await.is_empty());
+    assert!(stranger.receive().await.is_empty());
+}
+
+#[tokio::test]
+async fn rejects_answers_and_candidates_outside_the_session() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    let stranger = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    stranger.join("other", false).await;
+    host.receive().await;
+
+    assert_eq!(stranger.request(SignalMessage::SdpAnswer(session_id("room"), host.user_id, answer())).await, Err("User is not in the session".to_string()));
+    assert_eq!(stranger.request(SignalMessage::IceCandidate(session_id("room"), host.user_id, candidate())).await, Err("User is not in the session".to_string()));
+    assert!(stranger.request(SignalMessage::IceCandidate(session_id("other"), client.user_id, candidate())).await.is_err());
+    assert!(host.receive().await.is_empty());
+    assert!(client.receive().await.is_empty());
+
+    // members still reach each other
+    client.request(SignalMessage::SdpAnswer(session_id("room"), host.user_id, answer())).await.unwrap();
+    host.request(SignalMessage::IceCandidate(session_id("room"), client.user_id, candidate())).await.unwrap();
+    assert!(matches!(&host.receive().await[..], [SignalMessage::SdpAnswer(_, from, _)] if *from == client.user_id));
+    assert!(matches!(&client.receive().await[..], [SignalMessage::IceCandidate(_, from, _)] if *from == host.user_id));
+}
+
+#[tokio::test]
+async fn retried_requests_are_delivered_once() {
+    let server = common::start(Config::default()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    host.receive().await;
+
+    let offer = SignalMessage::SdpOffer(session_id("room"), client.user_id, offer());
+    host.request_with_id("offer", offer.clone()).await.unwrap();
+    host.request_with_id("offer", offer.clone()).await.unwrap();
+    assert_eq!(client.receive().await.len(), 1);
+
+    // the retry of a rejected request gets the same answer
+    let leave = SignalMessage::SessionLeave(session_id("other"));
+    assert_eq!(client.request_with_id("leave", leave.clone()).await, Err("User is not in the session".to_string()));
+    assert_eq!(client.request_with_id("leave", leave).await, Err("User is not in the session".to_string()));
+
+    // new requests are delivered again
+    host.request_with_id("next offer", offer).await.unwrap();
+    assert_eq!(client.receive().await.len(), 1);
+}
+
+#[tokio::test]
+async fn advertises_the_ice_configuration_on_join() {
+    let ice_config = IceConfig {
+        ice_servers: vec![IceServer {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            username: Some("user".to_string()),
+            credential: Some("password".to_string()),
+        }],
+        ice_transport_policy: Some(IceTransportPolicy::Relay),
+    };
+    let server = common::start(Config {
+        ice: Some(ice_config),
+        ..Config::default()
+    })
+    .await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+
+    for user in [&host, &client] {
+        let messages = user.receive().await;
+        let Some(SignalMessage::IceConfig(session, advertised)) = messages.first() else {
+            panic!("no ICE configuration in {messages:?}");
+        };
+        assert_eq!(*session, session_id("room"));
+        assert_eq!(advertised.ice_transport_policy, Some(IceTransportPolicy::Relay));
+        assert_eq!(advertised.ice_servers.len(), 1);
+        assert_eq!(advertised.ice_servers[0].urls, ["turn:turn.example.com:3478"]);
+        assert_eq!(advertised.ice_servers[0].username.as_deref(), Some("user"));
+        assert_eq!(advertised.ice_servers[0].credential.as_deref(), Some("password"));
+    }
+
+    // servers without a configuration don't send one
+    let server = common::start(Config::default()).await;
+    let user = server.connect().await;
+    user.join("room", true).await;
+    assert!(user.receive().await.is_empty());
+}
@@ -0,0 +1,57 @@
+use ezrtc_server::metrics::Metrics;
+use ezrtc_server::stun_server;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use stun::agent::TransactionId;
+use stun::message::{Getter, Message, BINDING_REQUEST, BINDING_SUCCESS};
+use stun::xoraddr::XorMappedAddress;
+use tokio::net::UdpSocket;
+
+async fn start() -> (UdpSocket, Arc<Metrics>) {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(server.local_addr().unwrap()).await.unwrap();
+
+    let metrics = Arc::new(Metrics::default());
+    tokio::spawn(stun_server::serve(server, metrics.clone()));
+
+    (client, metrics)
+}
+
+#[tokio::test]
+async fn answers_binding_request_with_the_sender_address() {
+    let (client, metrics) = start().await;
+
+    let mut request = Message::new();
+    request.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)]).unwrap();
+    client.send(&request.raw).await.unwrap();
+
+    let mut buf = vec![0u8; 1500];
+    let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await.unwrap().unwrap();
+
+    let mut response = Message::new();
+    response.raw = buf[..len].to_vec();
+    response.decode().unwrap();
+    assert_eq!(response.typ, BINDING_SUCCESS);
+    assert_eq!(response.transaction_id, request.transaction_id);
+
+    let mut address = XorMappedAddress::default();
+    address.get_from(&response).unwrap();
+    let local_addr = client.local_addr().unwrap();
+    assert_eq!((address.ip, address.port), (local_addr.ip(), local_addr.port()));
+
+    assert_eq!(metrics.stun.requests.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.stun.responses.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn ignores_invalid_messages() {
+    let (client, metrics) = start().await;
+
+    client.send(b"not stun").await.unwrap();
+
+    let mut buf = vec![0u8; 1500];
+    assert!(tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf)).await.is_err());
+    assert_eq!(metrics.stun.invalid.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,147 @@
+use ezrtc::protocol::{SessionId, SignalMessage, UserId};
+use ezrtc_server::config::Config;
+use ezrtc_server::store::{MemoryStore, SessionStore};
+use ezrtc_server::tenant::{Quotas, Tenant, TenantConfig, TenantUsage};
+
+fn config() -> Config {
+    Config {
+        tenants: vec![TenantConfig {
+            name: "chess".to_string(),
+            app_key: "chess-key".to_string(),
+            quotas: Quotas {
+                max_sessions: Some(1),
+                max_users: Some(2),
+                message_rate: Some(3),
+                reservation_rate: None,
+            },
+            allowed_origins: None,
+        }],
+        ..Config::default()
+    }
+}
+
+fn chess() -> Tenant {
+    Tenant::resolve(&config(), Some("chess-key")).unwrap()
+}
+
+fn default_tenant() -> Tenant {
+    Tenant::resolve(&config(), None).unwrap()
+}
+
+fn session_id(session: &str) -> SessionId {
+    SessionId::new(session.to_string())
+}
+
+#[test]
+fn resolves_app_keys() {
+    assert_eq!(chess().name(), "chess");
+    assert_eq!(chess().quotas().max_sessions, Some(1));
+    assert_eq!(default_tenant().name(), "");
+    assert!(Tenant::resolve(&config(), Some("unknown")).is_none());
+}
+
+#[test]
+fn scopes_session_ids_to_the_tenant() {
+    let scoped = chess().scope(&session_id("room"));
+
+    assert_eq!(scoped.as_str(), "chess:room");
+    assert_eq!(chess().unscope(&scoped), session_id("room"));
+    assert!(chess().owns(&scoped));
+    assert!(!default_tenant().owns(&scoped));
+
+    assert_eq!(default_tenant().scope(&session_id("room")), session_id("room"));
+    assert!(default_tenant().owns(&session_id("room")));
+    assert!(!chess().owns(&session_id("room")));
+    assert!(!chess().owns(&session_id("chessboard:room")));
+}
+
+#[test]
+fn scopes_messages_with_valid_session_ids() {
+    let mut message = SignalMessage::SessionJoin(session_id("room"), true, None);
+    chess().scope_message(&mut message).unwrap();
+    assert!(matches!(message, SignalMessage::SessionJoin(session_id, _, _) if session_id.as_str() == "chess:room"));
+
+    let mut request = SignalMessage::Request("1".to_string(), Box::new(SignalMessage::SessionLeave(session_id("room"))));
+    chess().scope_message(&mut request).unwrap();
+    assert!(matches!(request, SignalMessage::Request(_, message) if matches!(*message, SignalMessage::SessionLeave(ref session_id) if session_id.as_str() == "chess:room")));
+
+    // ids with a separator would reach into another tenant's namespace
+    for invalid in ["other:room", "", "room/1"] {
+        let mut message = SignalMessage::SessionJoin(session_id(invalid), true, None);
+        assert!(chess().scope_message(&mut message).is_err(), "{invalid:?} was accepted");
+        assert!(default_tenant().scope_message(&mut message).is_err(), "{invalid:?} was accepted");
+    }
+}
+
+#[test]
+fn limits_users_and_messages() {
+    let usage = TenantUsage::default();
+    let tenant = chess();
+
+    assert!(usage.connect(&tenant));
+    assert!(usage.connect(&tenant));
+    assert!(!usage.connect(&tenant));
+    assert!(usage.connect(&default_tenant()));
+
+    usage.disconnect(&tenant, UserId::new(1));
+    assert!(usage.connect(&tenant));
+
+    let user_id = UserId::new(2);
+    assert!((0..3).all(|_| usage.allow_message(&tenant, user_id)));
+    assert!(!usage.allow_message(&tenant, user_id));
+    assert!(usage.allow_message(&tenant, UserId::new(3)));
+    assert!((0..10).all(|_| usage.allow_message(&default_tenant(), user_id)));
+}
+
+#[tokio::test]
+async fn forgets_sessions_deleted_elsewhere() {
+    let usage = TenantUsage::default();
+    let store = MemoryStore::default();
+    let tenant = chess();
+    let (first, second) = (tenant.scope(&session_id("first")), tenant.scope(&session_id("second")));
+
+    assert!(usage.add_session(&tenant, &first, &store).await.unwrap());
+    store.claim_host(&first, UserId::new(1)).await.unwrap();
+    assert!(usage.add_session(&tenant, &first, &store).await.unwrap());
+    assert!(!usage.add_session(&tenant, &second, &store).await.unwrap());
+
+    // the host leaves through another instance, so this one isn't told the session is gone
+    store.remove_user(UserId::new(1)).await.unwrap();
+    assert!(usage.add_session(&tenant, &second, &store).await.unwrap());
+
+    // sessions without a tenant aren't limited
+    for session in ["a", "b", "c"] {
+        assert!(usage.add_session(&default_tenant(), &session_id(session), &store).await.unwrap());
+    }
+}
+
+fn tenant(name: &str, app_key: &str) -> TenantConfig {
+    TenantConfig {
+        name: name.to_string(),
+        app_key: app_key.to_string(),
+        quotas: Quotas::default(),
+        allowed_origins: None,
+    }
+}
+
+#[test]
+fn rejects_ambiguous_tenants() {
+    let validate = |tenants: Vec<TenantConfig>| Config { tenants, ..Config::default() }.validate();
+
+    assert!(validate(vec![tenant("chess", "chess-key"), tenant("go", "go-key")]).is_ok());
+    assert!(validate(vec![tenant("chess:blitz", "key")]).is_err());
+    assert!(validate(vec![tenant("", "key")]).is_err());
+    assert!(validate(vec![tenant("chess", "chess-key"), tenant("chess", "other-key")]).is_err());
+    assert!(validate(vec![tenant("chess", "shared-key"), tenant("go", "shared-key")]).is_err());
+}
+
+#[test]
+fn refuses_to_load_invalid_tenants() {
+    let path = std::env::temp_dir().join(format!("ezrtc-tenants-{}.json", std::process::id()));
+    std::fs::write(&path, r#"{ "tenants": [{ "name": "chess", "app_key": "key" }, { "name": "go", "app_key": "key" }] }"#).unwrap();
+    std::env::set_var("EZRTC_CONFIG", &path);
+    let result = Config::load();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(result.unwrap_err().to_string().contains("app key of another tenant"));
+}
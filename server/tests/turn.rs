@@ -0,0 +1,259 @@
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use ezrtc::client::EzRTCClient;
+use ezrtc::host::EzRTCHost;
+use ezrtc::protocol::{IceConfig, IceTransportPolicy, SessionId, TurnCredentials, UserId};
+use ezrtc::socket::{DataChannelHandler, WSHost};
+use ezrtc::turn::TurnCache;
+use ezrtc::DataChannel;
+use ezrtc_server::config::Config;
+use ezrtc_server::metrics::Metrics;
+use ezrtc_server::router::{self, ServerState};
+use ezrtc_server::store::{MemoryBus, MemoryStore, SessionStore};
+use ezrtc_server::tenant::{Quotas, TenantConfig};
+use ezrtc_server::turn::{self, TurnConfig};
+use ezrtc_server::turn_server::{self, SessionQuotas, TurnServerConfig};
+use reqwest::Url;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+
+const SECRET: &str = "turn-secret";
+
+/// Forwards received data channel messages and greets the other side once the channel opens
+struct Handler {
+    greeting: &'static str,
+    messages: mpsc::UnboundedSender<String>,
+}
+
+impl DataChannelHandler for Handler {
+    fn handle_data_channel_open(&self, dc: DataChannel) {
+        let greeting = self.greeting;
+        tokio::spawn(async move {
+            dc.send_text(greeting.to_string()).await.unwrap();
+        });
+    }
+
+    fn handle_data_channel_message(&self, message: String) {
+        let _ = self.messages.send(message);
+    }
+
+    fn handle_keep_alive(&self, _handle: &mut WSHost, _user_id: UserId) {}
+}
+
+fn handler(greeting: &'static str) -> (Arc<Box<dyn DataChannelHandler>>, mpsc::UnboundedReceiver<String>) {
+    let (messages, rx) = mpsc::unbounded_channel();
+
+    (Arc::new(Box::new(Handler { greeting, messages })), rx)
+}
+
+async fn free_udp_port() -> u16 {
+    tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn peers_connect_through_the_embedded_relay() {
+    let turn_port = free_udp_port().await;
+    let turn_server_config = TurnServerConfig {
+        address: format!("127.0.0.1:{turn_port}"),
+        relay_address: "127.0.0.1".parse().unwrap(),
+        min_port: 40000,
+        max_port: 40100,
+        realm: "ezrtc".to_string(),
+        bandwidth_limit: Some(1_000_000),
+    };
+    let config = Config {
+        turn: Some(TurnConfig {
+            secret: SECRET.to_string(),
+            uris: vec![format!("turn:127.0.0.1:{turn_port}")],
+            ttl: 600,
+            server: Some(turn_server_config.clone()),
+        }),
+        ice: Some(IceConfig {
+            ice_servers: Vec::new(),
+            ice_transport_policy: Some(IceTransportPolicy::Relay),
+        }),
+        ..Config::default()
+    };
+
+    let state = ServerState::with_config(config);
+    let _turn_server = turn_server::run(turn_server_config, SECRET.to_string(), state.metrics()).await.unwrap();
+    let metrics = state.metrics();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/one-to-many", listener.local_addr().unwrap());
+    let app = router::create(state);
+    tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap() });
+
+    let (host_handler, mut host_messages) = handler("hello client");
+    let (client_handler, mut client_messages) = handler("hello host");
+    let _host = EzRTCHost::new(url.clone(), "relayed".to_string(), Vec::new(), host_handler).await;
+    let _client = EzRTCClient::new(url, "relayed".to_string(), Vec::new(), client_handler).await;
+
+    let timeout = Duration::from_secs(30);
+    assert_eq!(tokio::time::timeout(timeout, host_messages.recv()).await.unwrap().unwrap(), "hello host");
+    assert_eq!(tokio::time::timeout(timeout, client_messages.recv()).await.unwrap().unwrap(), "hello client");
+
+    assert!(metrics.turn.allocations.load(Ordering::Relaxed) >= 2);
+    assert!(metrics.turn.relayed_bytes.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn allocations_of_a_session_share_the_quota() {
+    let quotas = SessionQuotas::new(Some(100), Arc::new(Metrics::default()));
+    let host: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+    let client: SocketAddr = "127.0.0.1:1001".parse().unwrap();
+    let other: SocketAddr = "127.0.0.1:1002".parse().unwrap();
+    quotas.bind(host, "session");
+    quotas.bind(client, "session");
+    quotas.bind(other, "other");
+
+    assert!(quotas.allow(host, 60));
+    assert!(!quotas.allow(client, 60));
+    assert!(quotas.allow(client, 40));
+    assert!(quotas.allow(other, 100));
+
+    // the window of the session is forgotten with its last allocation
+    quotas.release(host);
+    quotas.release(client);
+    quotas.bind(client, "session");
+    assert!(quotas.allow(client, 100));
+}
+
+fn turn_config() -> TurnConfig {
+    TurnConfig {
+        secret: SECRET.to_string(),
+        uris: vec!["turn:turn.example.com:3478".to_string()],
+        ttl: 600,
+        server: None,
+    }
+}
+
+#[test]
+fn password_is_the_hmac_of_the_username() {
+    // well known HMAC-SHA1 test vector
+    assert_eq!(turn::password("key", "The quick brown fox jumps over the lazy dog"), "3nybhbi3iqa8ino29wqQcBydtNk=");
+}
+
+#[test]
+fn credentials_expire_after_the_ttl() {
+    let credentials = turn::credentials(&turn_config(), &SessionId::new("room".to_string())).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+    let expires_at = credentials.expires_at().unwrap();
+    assert!((now + 599..=now + 600).contains(&expires_at));
+    assert_eq!(credentials.username, format!("{expires_at}:room"));
+    assert_eq!(credentials.password, turn::password(SECRET, &credentials.username));
+    assert!(credentials.is_for(&SessionId::new("room".to_string())));
+    assert!(!credentials.is_expired());
+}
+
+async fn request_credentials(config: Config, uri: &str) -> (StatusCode, Option<TurnCredentials>) {
+    request_with_host(config, uri, None, None).await
+}
+
+/// Request credentials from a server where `hosted` has a host, with the `Authorization` header if `token` is set
+async fn request_with_host(config: Config, uri: &str, hosted: Option<&str>, token: Option<&str>) -> (StatusCode, Option<TurnCredentials>) {
+    let store = Arc::new(MemoryStore::default());
+    if let Some(session_id) = hosted {
+        store.claim_host(&SessionId::new(session_id.to_string()), UserId::new(1)).await.unwrap();
+    }
+
+    let app = router::create(ServerState::new(config, store, Arc::new(MemoryBus::default())));
+    let mut request = Request::get(uri);
+    if let Some(token) = token {
+        request = request.header("authorization", format!("Bearer {token}"));
+    }
+    let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, serde_json::from_slice(&body).ok())
+}
+
+fn tenant_config() -> Config {
+    Config {
+        turn: Some(turn_config()),
+        tenants: vec![TenantConfig {
+            name: "acme".to_string(),
+            app_key: "acme-key".to_string(),
+            quotas: Quotas::default(),
+            allowed_origins: None,
+        }],
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn credentials_are_scoped_to_the_tenant_session() {
+    let (status, credentials) = request_with_host(tenant_config(), "/turn-credentials?session=room&app_key=acme-key", Some("acme:room"), None).await;
+
+    assert_eq!(status, StatusCode::OK);
+    let credentials = credentials.unwrap();
+    assert!(credentials.username.ends_with(":acme:room"));
+    assert_eq!(credentials.password, turn::password(SECRET, &credentials.username));
+}
+
+#[tokio::test]
+async fn credentials_need_an_app_key_on_servers_with_tenants() {
+    assert_eq!(request_credentials(tenant_config(), "/turn-credentials?session=room").await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(request_credentials(tenant_config(), "/turn-credentials?session=room&app_key=wrong").await.0, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn credentials_need_a_valid_session() {
+    let config = Config {
+        turn: Some(turn_config()),
+        ..Config::default()
+    };
+
+    assert_eq!(request_credentials(config.clone(), "/turn-credentials").await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(request_credentials(config.clone(), "/turn-credentials?session=a:b").await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(request_credentials(Config::default(), "/turn-credentials?session=room").await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn credentials_need_a_hosted_session_or_the_api_token() {
+    let config = Config {
+        turn: Some(turn_config()),
+        api_token: Some("secret".to_string()),
+        ..Config::default()
+    };
+
+    assert_eq!(request_with_host(config.clone(), "/turn-credentials?session=room", Some("room"), None).await.0, StatusCode::OK);
+    assert_eq!(request_with_host(config.clone(), "/turn-credentials?session=room", None, None).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(request_with_host(config.clone(), "/turn-credentials?session=room", Some("other"), None).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(request_with_host(config.clone(), "/turn-credentials?session=room", None, Some("wrong")).await.0, StatusCode::FORBIDDEN);
+
+    // backends hand out credentials before the host connects
+    assert_eq!(request_with_host(config, "/turn-credentials?session=room", None, Some("secret")).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn clients_remember_sessions_without_credentials() {
+    let requests = Arc::new(AtomicU32::new(0));
+    let counter = requests.clone();
+    let app = axum::Router::new().route(
+        "/turn-credentials",
+        axum::routing::get(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async { StatusCode::NOT_FOUND }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("ws://{}/one-to-many", listener.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let mut cache = TurnCache::default();
+    let room = SessionId::new("room".to_string());
+    assert!(ezrtc::turn::merge_ice_servers(&url, &room, &[], &mut cache).await.is_empty());
+    assert!(ezrtc::turn::merge_ice_servers(&url, &room, &[], &mut cache).await.is_empty());
+    assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+    // another session asks again
+    ezrtc::turn::merge_ice_servers(&url, &SessionId::new("other".to_string()), &[], &mut cache).await;
+    assert_eq!(requests.load(Ordering::Relaxed), 2);
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use common::{session_id, TestServer, User};
+use ezrtc::protocol::{SessionId, SignalMessage, Status, UserId};
+use ezrtc_server::config::Config;
+use ezrtc_server::webhook::{deliver, signature, WebhookConfig, WebhookEvent, WebhookPayload};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SECRET: &str = "webhook-secret";
+const TIMESTAMP: u64 = 1700000000;
+
+/// Local webhook receiver that fails the first `failures` requests
+#[derive(Clone, Default)]
+struct Receiver {
+    failures: u32,
+    attempts: Arc<Mutex<u32>>,
+    received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+}
+
+async fn receive(State(receiver): State<Receiver>, headers: HeaderMap, body: String) -> StatusCode {
+    let mut attempts = receiver.attempts.lock().unwrap();
+    *attempts += 1;
+
+    if *attempts <= receiver.failures {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+
+    receiver.received.lock().unwrap().push((headers, body));
+    StatusCode::OK
+}
+
+async fn start(receiver: Receiver) -> String {
+    let app = Router::new().route("/webhook", post(receive)).with_state(receiver);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/webhook", listener.local_addr().unwrap());
+
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    url
+}
+
+fn payload() -> (WebhookEvent, String) {
+    let event = WebhookEvent::ClientJoined {
+        session_id: SessionId::new("session".to_string()),
+        user_id: UserId::new(1),
+    };
+    let body = serde_json::to_string(&WebhookPayload {
+        timestamp: TIMESTAMP,
+        event: event.clone(),
+    })
+    .unwrap();
+
+    (event, body)
+}
+
+#[tokio::test]
+async fn delivers_signed_event_after_retry() {
+    let receiver = Receiver { failures: 1, ..Receiver::default() };
+    let url = start(receiver.clone()).await;
+    let (event, body) = payload();
+
+    assert!(deliver(&reqwest::Client::new(), &url, SECRET, TIMESTAMP, body.clone(), 2).await);
+    assert_eq!(*receiver.attempts.lock().unwrap(), 2);
+
+    let received = receiver.received.lock().unwrap();
+    let (headers, received_body) = &received[0];
+    assert_eq!(received_body, &body);
+    assert_eq!(headers["x-ezrtc-timestamp"], TIMESTAMP.to_string().as_str());
+    assert_eq!(headers["x-ezrtc-signature"], signature(SECRET, TIMESTAMP, &body).as_str());
+
+    let payload: WebhookPayload = serde_json::from_str(received_body).unwrap();
+    assert_eq!(payload.event, event);
+}
+
+#[tokio::test]
+async fn gives_up_after_retries() {
+    let receiver = Receiver {
+        failures: u32::MAX,
+        ..Receiver::default()
+    };
+    let url = start(receiver.clone()).await;
+    let (_, body) = payload();
+
+    assert!(!deliver(&reqwest::Client::new(), &url, SECRET, TIMESTAMP, body, 1).await);
+    assert_eq!(*receiver.attempts.lock().unwrap(), 2);
+    assert!(receiver.received.lock().unwrap().is_empty());
+}
+
+#[test]
+fn signature_changes_with_body() {
+    let (_, body) = payload();
+
+    assert!(signature(SECRET, TIMESTAMP, &body).starts_with("sha256="));
+    assert_ne!(signature(SECRET, TIMESTAMP, &body), signature(SECRET, TIMESTAMP, "{}"));
+    assert_ne!(signature(SECRET, TIMESTAMP, &body), signature(SECRET, TIMESTAMP + 1, &body));
+}
+
+/// Signaling server that posts its events to the receiver
+async fn start_server(receiver: Receiver) -> TestServer {
+    let url = start(receiver).await;
+    common::start(Config {
+        webhooks: Some(WebhookConfig {
+            urls: vec![url],
+            secret: SECRET.to_string(),
+            retries: 0,
+        }),
+        ..Config::default()
+    })
+    .await
+}
+
+/// Events posted to the receiver so far, after giving the server time to deliver them
+async fn events(receiver: &Receiver) -> Vec<WebhookEvent> {
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    receiver.received.lock().unwrap().iter().map(|(_, body)| serde_json::from_str::<WebhookPayload>(body).unwrap().event).collect()
+}
+
+async fn keep_alive(user: &User, user_id: UserId, session: &str) {
+    let status = Status {
+        session_id: Some(session_id(session)),
+        is_host: Some(true),
+        ..Status::default()
+    };
+    user.send(SignalMessage::KeepAlive(user_id, status)).await;
+}
+
+#[tokio::test]
+async fn host_is_online_after_its_first_keep_alive() {
+    let receiver = Receiver::default();
+    let server = start_server(receiver.clone()).await;
+    let host = server.connect().await;
+    host.join("room", true).await;
+
+    keep_alive(&host, host.user_id, "room").await;
+    keep_alive(&host, host.user_id, "room").await;
+
+    let online = WebhookEvent::HostOnline {
+        session_id: session_id("room"),
+        user_id: host.user_id,
+        metadata: None,
+    };
+    assert_eq!(events(&receiver).await, [online]);
+}
+
+#[tokio::test]
+async fn only_the_host_announces_its_session() {
+    let receiver = Receiver::default();
+    let server = start_server(receiver.clone()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("room", true).await;
+    client.join("room", false).await;
+    let joined = events(&receiver).await;
+
+    // neither in its own name nor in the host's
+    keep_alive(&client, client.user_id, "room").await;
+    keep_alive(&client, host.user_id, "room").await;
+    keep_alive(&client, client.user_id, "other").await;
+
+    assert_eq!(events(&receiver).await, joined);
+}
+
+#[tokio::test]
+async fn hosts_that_leave_are_not_offline() {
+    let receiver = Receiver::default();
+    let server = start_server(receiver.clone()).await;
+    let host = server.connect().await;
+    let client = server.connect().await;
+    host.join("first", true).await;
+    client.join("first", false).await;
+    host.join("second", true).await;
+    let joined = events(&receiver).await.len();
+
+    host.request(SignalMessage::SessionLeave(session_id("first"))).await.unwrap();
+    host.close().await;
+
+    let left = WebhookEvent::HostLeft {
+        session_id: session_id("first"),
+        user_id: host.user_id,
+    };
+    let offline = WebhookEvent::HostOffline {
+        session_id: session_id("second"),
+        user_id: host.user_id,
+    };
+    let deleted = WebhookEvent::SessionDeleted { session_id: session_id("second") };
+    assert_eq!(events(&receiver).await[joined..], [left, offline, deleted]);
+}